@@ -0,0 +1,113 @@
+/**
+ * Removing an account from this client, and optionally from the server.
+ * Server-side steps run first, while the token needed to authorise them still exists.
+ * The local wipe then deletes the credentials before the files: once the database key
+ * is gone, anything of the database that survives on disk (backups, old free blocks) is
+ * ciphertext nobody can decrypt.
+ */
+use serde_json::json;
+use tokio_rusqlite::{rusqlite, Connection};
+
+use crate::accounts;
+use crate::manage_keys;
+use crate::paths;
+use crate::to_server;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+//What a removal did, for reporting back to the user
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RemovalReport {
+    pub deleted_on_server: bool,
+    pub device_revoked: bool,
+    //why this device couldn't be revoked, the wipe goes ahead regardless
+    pub revoke_error: Option<String>,
+    //credential entries removed from the credential store
+    pub credentials: usize,
+    pub data_removed: bool,
+    pub registry_removed: bool,
+}
+
+/*
+Removes username from this client. With delete_on_server the account is deleted on the
+server first and nothing local is touched if that fails, so the user can retry. This
+device is then revoked; a failure there (offline, token already expired) is reported but
+doesn't stop the wipe, the server drops the device when its token runs out anyway.
+*/
+pub async fn remove_account(username: &str, delete_on_server: bool) -> Result<RemovalReport, BoxError> {
+    let mut deleted_on_server = false;
+    let mut device_revoked = false;
+    let mut revoke_error = None;
+    if delete_on_server {
+        to_server::to_server("delete_account", server_request(username).await?)
+            .await
+            .map_err(|e| format!("Server refused to delete the account, nothing was removed: {}", e))?;
+        //deleting the account revokes all of its devices
+        deleted_on_server = true;
+        device_revoked = true;
+    } else {
+        match revoke_device(username).await {
+            Ok(()) => device_revoked = true,
+            Err(e) => revoke_error = Some(e.to_string()),
+        }
+    }
+
+    let wiped = wipe_local(username).await?;
+    Ok(RemovalReport { deleted_on_server, device_revoked, revoke_error, ..wiped })
+}
+
+//Asks the server to stop accepting this device's token and delivering messages to it
+pub async fn revoke_device(username: &str) -> Result<(), BoxError> {
+    to_server::to_server("revoke_device", server_request(username).await?).await?;
+    Ok(())
+}
+
+/*
+Deletes everything this client keeps for username: credentials and private keys, the
+account directory with its database, a database left in the working directory by older
+versions, and the account registry entry. Safe to run again after a partial wipe.
+*/
+pub async fn wipe_local(username: &str) -> Result<RemovalReport, BoxError> {
+    let peers = known_peers(username).await?;
+    let credentials = manage_keys::delete_all_credentials(username, &peers).await?;
+    let data_removed = paths::remove_account_data(username).await?;
+    let registry_removed = accounts::remove_account(username).await?;
+
+    Ok(RemovalReport {
+        credentials,
+        data_removed,
+        registry_removed,
+        ..RemovalReport::default()
+    })
+}
+
+async fn server_request(username: &str) -> Result<serde_json::Value, BoxError> {
+    Ok(json!({
+        "username": username,
+        "token": manage_keys::get_token(username).await?,
+        "device_id": manage_keys::get_device_id(username).await?,
+        "uuid": manage_keys::get_uuid(username).await?,
+    }))
+}
+
+/*
+Users and device ids in the account's database, which is where ratchet roots may be kept
+for. Reads the file directly rather than through db::connect, which would create, migrate
+and re-encrypt a database that is about to be deleted.
+*/
+async fn known_peers(username: &str) -> Result<Vec<String>, BoxError> {
+    let Some(path) = paths::existing_user_db_path(username).await? else {
+        return Ok(Vec::new());
+    };
+    let conn = Connection::open(&path).await?;
+    let peers = conn.call(|call| {
+        let mut stmt = call.prepare(
+            "SELECT user_id FROM users UNION SELECT CAST(device_id AS TEXT) FROM devices"
+        )?;
+        let peers = stmt.query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok::<_, rusqlite::Error>(peers)
+    }).await?;
+    conn.close().await?;
+    Ok(peers)
+}
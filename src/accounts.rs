@@ -0,0 +1,199 @@
+/**
+ * Registry of the accounts that have logged in on this client.
+ * Lives in its own database in the data directory, separate from the per-user {user}.database files,
+ * so the login menu can be built before any user database is opened.
+ */
+use tokio_rusqlite::{params, Connection, OptionalExtension};
+use csv::ReaderBuilder;
+use tokio::sync::OnceCell;
+
+use crate::paths;
+
+//accounts used to be appended to this file in the working directory, it is imported once and then removed
+const LEGACY_USERS_FILE: &str = "users.csv";
+
+static SCHEMA_READY: OnceCell<()> = OnceCell::const_new();
+
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub username: String,
+    pub server: String,
+    pub device_id: Option<String>,
+    pub last_login: Option<String>,
+    pub is_default: bool,
+}
+
+pub async fn open_registry() -> Result<Connection, Box<dyn std::error::Error + Send + Sync>> {
+    let registry_path = paths::registry_path().await?;
+    let conn = Connection::open(&registry_path).await?;
+    SCHEMA_READY.get_or_try_init(|| init_registry(&conn)).await?;
+    Ok(conn)
+}
+
+//Creates the table, restricts the file and imports users.csv, once per process
+async fn init_registry(conn: &Connection) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    paths::restrict_file(&paths::registry_path().await?).await?;
+    let accounts =
+    "CREATE TABLE IF NOT EXISTS accounts (
+        username TEXT PRIMARY KEY,
+        server TEXT NOT NULL,
+        device_id TEXT,
+        last_login TIMESTAMP,
+        is_default INTEGER NOT NULL DEFAULT 0
+    );";
+
+    conn.call(move |call| {
+        call.execute(accounts, [])?;
+        Ok::<(), tokio_rusqlite::Error>(())
+    }).await?;
+
+    import_legacy_users(conn).await
+}
+
+/*
+Adds an account or updates the server/device id of an existing one.
+The first account registered on this client becomes the default.
+*/
+pub async fn add_account(username: &str, server: &str, device_id: Option<&str>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let conn = open_registry().await?;
+    let username = username.to_string();
+    let server = server.to_string();
+    let device_id = device_id.map(|d| d.to_string());
+
+    conn.call(move |call| {
+        call.execute(
+            "INSERT INTO accounts (username, server, device_id, is_default)
+             VALUES (?1, ?2, ?3, NOT EXISTS (SELECT 1 FROM accounts WHERE is_default = 1))
+             ON CONFLICT(username) DO UPDATE SET
+                server = excluded.server,
+                device_id = COALESCE(excluded.device_id, accounts.device_id)",
+            params![username, server, device_id],
+        )?;
+        Ok::<(), tokio_rusqlite::Error>(())
+    }).await?;
+    Ok(())
+}
+
+//Removes an account; if it was the default, the most recently used remaining account takes over
+pub async fn remove_account(username: &str) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let conn = open_registry().await?;
+    let username = username.to_string();
+
+    let removed = conn.call(move |call| {
+        let tx = call.transaction()?;
+        let removed = tx.execute("DELETE FROM accounts WHERE username = ?1", [&username])?;
+        tx.execute(
+            "UPDATE accounts SET is_default = 1
+             WHERE username = (SELECT username FROM accounts ORDER BY last_login DESC LIMIT 1)
+             AND NOT EXISTS (SELECT 1 FROM accounts WHERE is_default = 1)",
+            [],
+        )?;
+        tx.commit()?;
+        Ok::<bool, tokio_rusqlite::Error>(removed > 0)
+    }).await?;
+    Ok(removed)
+}
+
+//Default account first, then most recently used
+pub async fn list_accounts() -> Result<Vec<Account>, Box<dyn std::error::Error + Send + Sync>> {
+    let conn = open_registry().await?;
+
+    let accounts = conn.call(|call| {
+        let mut stmt = call.prepare(
+            "SELECT username, server, device_id, last_login, is_default FROM accounts
+             ORDER BY is_default DESC, last_login DESC, username",
+        )?;
+        let accounts = stmt.query_map([], account_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok::<_, tokio_rusqlite::Error>(accounts)
+    }).await?;
+    Ok(accounts)
+}
+
+pub async fn get_account(username: &str) -> Result<Option<Account>, Box<dyn std::error::Error + Send + Sync>> {
+    let conn = open_registry().await?;
+    let username = username.to_string();
+
+    let account = conn.call(move |call| {
+        let account = call.query_row(
+            "SELECT username, server, device_id, last_login, is_default FROM accounts WHERE username = ?1",
+            [&username],
+            account_from_row,
+        ).optional()?;
+        Ok::<_, tokio_rusqlite::Error>(account)
+    }).await?;
+    Ok(account)
+}
+
+pub async fn get_default() -> Result<Option<Account>, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(list_accounts().await?.into_iter().find(|a| a.is_default))
+}
+
+pub async fn set_default(username: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let conn = open_registry().await?;
+    let username = username.to_string();
+
+    conn.call(move |call| {
+        let tx = call.transaction()?;
+        if tx.execute("UPDATE accounts SET is_default = 1 WHERE username = ?1", [&username])? == 0 {
+            return Err(tokio_rusqlite::Error::Error(tokio_rusqlite::rusqlite::Error::QueryReturnedNoRows));
+        }
+        tx.execute("UPDATE accounts SET is_default = 0 WHERE username != ?1", [&username])?;
+        tx.commit()?;
+        Ok(())
+    }).await?;
+    Ok(())
+}
+
+pub async fn record_login(username: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let conn = open_registry().await?;
+    let username = username.to_string();
+
+    conn.call(move |call| {
+        call.execute("UPDATE accounts SET last_login = CURRENT_TIMESTAMP WHERE username = ?1", [&username])?;
+        Ok::<(), tokio_rusqlite::Error>(())
+    }).await?;
+    Ok(())
+}
+
+fn account_from_row(row: &tokio_rusqlite::Row<'_>) -> tokio_rusqlite::rusqlite::Result<Account> {
+    Ok(Account {
+        username: row.get(0)?,
+        server: row.get(1)?,
+        device_id: row.get(2)?,
+        last_login: row.get(3)?,
+        is_default: row.get(4)?,
+    })
+}
+
+//Carries usernames over from users.csv (deduplicated) so existing installs keep their login menu
+async fn import_legacy_users(conn: &Connection) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if tokio::fs::metadata(LEGACY_USERS_FILE).await.is_err() {
+        return Ok(());
+    }
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .from_path(LEGACY_USERS_FILE)?;
+    let mut usernames = Vec::new();
+    for result in reader.records() {
+        if let Some(username) = result?.get(0) {
+            usernames.push(username.trim().to_string());
+        }
+    }
+
+    conn.call(move |call| {
+        let tx = call.transaction()?;
+        for username in usernames.iter().filter(|u| !u.is_empty()) {
+            tx.execute(
+                "INSERT OR IGNORE INTO accounts (username, server, is_default)
+                 VALUES (?1, ?2, NOT EXISTS (SELECT 1 FROM accounts WHERE is_default = 1))",
+                params![username, crate::to_server::SERVER],
+            )?;
+        }
+        tx.commit()?;
+        Ok::<(), tokio_rusqlite::Error>(())
+    }).await?;
+
+    tokio::fs::remove_file(LEGACY_USERS_FILE).await?;
+    Ok(())
+}
@@ -0,0 +1,263 @@
+/**
+ * Encrypted history archives, for moving history between machines and keeping backups.
+ * An archive holds the contacts, conversations and messages of one account as JSON,
+ * sealed with XChaCha20-Poly1305 under a key derived from a passphrase with Argon2id.
+ *
+ * File layout (integers little endian):
+ *   MAGIC | version u8 | m_cost u32 | t_cost u32 | p_cost u32 | salt | nonce | ciphertext
+ * Everything before the ciphertext is authenticated as associated data, so the KDF
+ * parameters can't be tampered with. They are stored so stronger defaults can be
+ * adopted later without breaking old archives.
+ */
+use argon2::Params;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::Path;
+use tokio_rusqlite::Connection;
+
+use crate::clock::unix_now;
+use crate::encryption::{self, FieldCipher};
+use crate::paths;
+use crate::repository::{self, Message};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+const MAGIC: &[u8; 8] = b"E2EMARC\0";
+//version of the file layout, bump when the header or payload changes incompatibly
+pub const ARCHIVE_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 12 + SALT_LEN + NONCE_LEN;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ArchiveContents {
+    pub username: String,
+    pub exported_at: i64,
+    pub contacts: Vec<ArchiveContact>,
+    pub conversations: Vec<ArchiveConversation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ArchiveContact {
+    pub user_id: String,
+    pub email: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ArchiveConversation {
+    pub participants: Vec<String>,
+    pub created_at: String,
+    pub expiry_seconds: Option<i64>,
+    pub retention_days: Option<i64>,
+    pub retention_messages: Option<i64>,
+    pub messages: Vec<ArchiveMessage>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ArchiveMessage {
+    pub sender_id: String,
+    pub content: String,
+    pub created_at: String,
+    pub received_at: String,
+    pub expires_at: Option<i64>,
+}
+
+//What an import added to the database
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ImportReport {
+    pub contacts: usize,
+    pub conversations: usize,
+    pub messages: usize,
+    //messages already in the database
+    pub duplicates: usize,
+    //disappearing messages whose time ran out since the export
+    pub expired: usize,
+}
+
+//Reads every contact, conversation and message of the account, decrypted
+pub async fn collect(conn: &Connection, cipher: &FieldCipher, username: &str) -> Result<ArchiveContents, BoxError> {
+    let contacts = repository::list_users(conn).await?
+        .into_iter()
+        .map(|u| ArchiveContact { user_id: u.user_id, email: u.email })
+        .collect();
+
+    let mut conversations = Vec::new();
+    for conversation in repository::list_conversations(conn).await? {
+        let messages = repository::list_messages(conn, cipher, conversation.conversation_id).await?
+            .into_iter()
+            .map(|m| ArchiveMessage {
+                sender_id: m.sender_id,
+                content: m.content,
+                created_at: m.created_at,
+                received_at: m.received_at,
+                expires_at: m.expires_at,
+            })
+            .collect();
+        conversations.push(ArchiveConversation {
+            participants: repository::list_participants(conn, conversation.conversation_id).await?,
+            created_at: conversation.created_at,
+            expiry_seconds: conversation.expiry_seconds,
+            retention_days: conversation.retention_days,
+            retention_messages: conversation.retention_messages,
+            messages,
+        });
+    }
+
+    Ok(ArchiveContents {
+        username: username.to_string(),
+        exported_at: unix_now(),
+        contacts,
+        conversations,
+    })
+}
+
+/*
+Merges archived history into the database. Conversations are matched by participants and a
+message is skipped when the conversation already has one from the same sender with the same
+time and content, so importing an archive twice (or one that overlaps) adds nothing new.
+Local conversation settings win over archived ones.
+*/
+pub async fn merge(conn: &Connection, cipher: &FieldCipher, contents: &ArchiveContents) -> Result<ImportReport, BoxError> {
+    let mut report = ImportReport::default();
+
+    for contact in &contents.contacts {
+        match repository::get_user(conn, &contact.user_id).await? {
+            Some(user) if !user.email.is_empty() || contact.email.is_empty() => {}
+            existing => {
+                repository::upsert_user(conn, &contact.user_id, &contact.email).await?;
+                if existing.is_none() {
+                    report.contacts += 1;
+                }
+            }
+        }
+    }
+
+    let now = unix_now();
+    for archived in &contents.conversations {
+        if archived.participants.is_empty() {
+            continue;
+        }
+        for user_id in &archived.participants {
+            repository::ensure_user(conn, user_id).await?;
+        }
+        let conversation_id = match archived.participants.as_slice() {
+            [peer] => repository::find_or_create_direct_conversation(conn, peer).await?,
+            participants => repository::find_or_create_conversation_with(conn, participants).await?,
+        };
+
+        let existing = repository::list_messages(conn, cipher, conversation_id).await?;
+        if existing.is_empty() {
+            report.conversations += 1;
+        }
+        let conversation = repository::get_conversation(conn, conversation_id).await?.ok_or("Conversation vanished during import")?;
+        if conversation.expiry_seconds.is_none() && archived.expiry_seconds.is_some() {
+            repository::set_conversation_expiry(conn, conversation_id, archived.expiry_seconds).await?;
+        }
+        if conversation.retention_days.is_none() && conversation.retention_messages.is_none() {
+            repository::set_conversation_retention(conn, conversation_id, archived.retention_days, archived.retention_messages).await?;
+        }
+
+        let mut seen: HashSet<(String, String, String)> = existing.into_iter()
+            .map(|m| (m.sender_id, m.created_at, m.content))
+            .collect();
+        for message in &archived.messages {
+            if message.expires_at.is_some_and(|at| at <= now) {
+                report.expired += 1;
+                continue;
+            }
+            let key = (message.sender_id.clone(), message.created_at.clone(), message.content.clone());
+            if !seen.insert(key) {
+                report.duplicates += 1;
+                continue;
+            }
+            repository::ensure_user(conn, &message.sender_id).await?;
+            repository::insert_message_at(conn, cipher, &Message {
+                message_id: 0,
+                conversation_id,
+                sender_id: message.sender_id.clone(),
+                content: message.content.clone(),
+                created_at: message.created_at.clone(),
+                received_at: message.received_at.clone(),
+                expires_at: message.expires_at,
+            }).await?;
+            report.messages += 1;
+        }
+    }
+    Ok(report)
+}
+
+//Encrypts the contents into an archive file image under a key derived from passphrase
+pub fn seal(contents: &ArchiveContents, passphrase: &str) -> Result<Vec<u8>, BoxError> {
+    let params = Params::default();
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.push(ARCHIVE_VERSION);
+    header.extend_from_slice(&params.m_cost().to_le_bytes());
+    header.extend_from_slice(&params.t_cost().to_le_bytes());
+    header.extend_from_slice(&params.p_cost().to_le_bytes());
+    header.extend_from_slice(&salt);
+    header.extend_from_slice(&nonce);
+
+    let cipher = archive_cipher(passphrase, &salt, params)?;
+    let plaintext = serde_json::to_vec(contents)?;
+    let ciphertext = cipher
+        .encrypt(&nonce, Payload { msg: &plaintext, aad: &header })
+        .map_err(|_| "Failed to encrypt archive")?;
+
+    header.extend_from_slice(&ciphertext);
+    Ok(header)
+}
+
+//Decrypts an archive file image. Fails on a wrong passphrase or any modification of the file
+pub fn unseal(data: &[u8], passphrase: &str) -> Result<ArchiveContents, BoxError> {
+    if data.len() < HEADER_LEN || &data[..MAGIC.len()] != MAGIC {
+        return Err(Box::from("Not a history archive"));
+    }
+    let version = data[MAGIC.len()];
+    if version != ARCHIVE_VERSION {
+        return Err(Box::from(format!(
+            "Archive version {} is not supported by this client ({})", version, ARCHIVE_VERSION
+        )));
+    }
+
+    let (header, ciphertext) = data.split_at(HEADER_LEN);
+    let cost = |at: usize| {
+        let start = MAGIC.len() + 1 + at * 4;
+        u32::from_le_bytes(header[start..start + 4].try_into().expect("slice is 4 bytes"))
+    };
+    let params = Params::new(cost(0), cost(1), cost(2), Some(32)).map_err(|e| format!("Invalid archive parameters: {}", e))?;
+    let salt = &header[MAGIC.len() + 13..MAGIC.len() + 13 + SALT_LEN];
+    let nonce = &header[HEADER_LEN - NONCE_LEN..];
+
+    let cipher = archive_cipher(passphrase, salt, params)?;
+    let plaintext = cipher
+        .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: header })
+        .map_err(|_| "Failed to decrypt archive, wrong passphrase or corrupted file")?;
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
+//Writes the account's history to an encrypted archive at path, readable only by the owner
+pub async fn export_archive(conn: &Connection, cipher: &FieldCipher, username: &str, path: &Path, passphrase: &str) -> Result<ArchiveContents, BoxError> {
+    let contents = collect(conn, cipher, username).await?;
+    let sealed = seal(&contents, passphrase)?;
+    paths::write_private(path, &sealed)?;
+    Ok(contents)
+}
+
+pub async fn import_archive(conn: &Connection, cipher: &FieldCipher, path: &Path, passphrase: &str) -> Result<ImportReport, BoxError> {
+    let data = tokio::fs::read(path).await?;
+    let contents = unseal(&data, passphrase)?;
+    merge(conn, cipher, &contents).await
+}
+
+fn archive_cipher(passphrase: &str, salt: &[u8], params: Params) -> Result<XChaCha20Poly1305, BoxError> {
+    let key = encryption::derive_key(passphrase, salt, params)?;
+    Ok(XChaCha20Poly1305::new(key.as_ref().into()))
+}
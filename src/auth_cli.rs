@@ -2,10 +2,14 @@ use tokio_tungstenite::{tungstenite::protocol::Message, WebSocketStream};
 use futures_util::stream::{SplitStream, SplitSink};
 use tokio::io::{self, AsyncBufReadExt, BufReader};
 use tokio::io::{AsyncWriteExt};
-use csv::ReaderBuilder;
 use tokio::net::TcpStream;
 
 use crate::auth_commands;
+use crate::establish_websocket;
+use crate::accounts;
+use crate::credential_store;
+use crate::totp::Totp;
+use crate::prompt;
 /**
  * A simple CLI for loggin in with the messenger client.
  * NOT INTEDED FOR PRODUCTION USE.
@@ -13,12 +17,13 @@ use crate::auth_commands;
 
 pub async fn cli() ->Result<
     (
+        String,
         SplitSink<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>, Message>,
         SplitStream<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>>
     ), Box<dyn std::error::Error + Send + Sync>> {
 
     let mut stdout = io::stdout();
-    stdout.write_all(b"Welcome to the End-to-End Encrypted Messenger CLI!\n1. New Account\n2. Login\n").await?;
+    stdout.write_all(b"Welcome to the End-to-End Encrypted Messenger CLI!\n1. New Account\n2. Login\n3. Recover account with a recovery code\n").await?;
     stdout.flush().await?;
 
     let mut input = String::new();
@@ -29,9 +34,9 @@ pub async fn cli() ->Result<
         "1" => {
             // Call new_account function to collect user input and send request to server
             match new_account().await {
-                Ok((send, recv)) => {
+                Ok((username, send, recv)) => {
                     println!("Account created successfully , logging in...");
-                    Ok((send, recv))
+                    Ok((username, send, recv))
                 }
                 Err(e) => {
                     eprintln!("Failed to create account: {}", e);
@@ -42,9 +47,9 @@ pub async fn cli() ->Result<
         "2" => {
             // Call login function
             match login().await {
-                Ok((send, recv)) => {
+                Ok((username, send, recv)) => {
                     println!("Logged in successfully!");
-                    Ok((send, recv))
+                    Ok((username, send, recv))
                 }
                 Err(e) => {
                     eprintln!("Failed to login: {}", e);
@@ -52,6 +57,18 @@ pub async fn cli() ->Result<
                 }
             }
         }
+        "3" => {
+            match recover().await {
+                Ok((username, send, recv)) => {
+                    println!("Password reset, logged in successfully!");
+                    Ok((username, send, recv))
+                }
+                Err(e) => {
+                    eprintln!("Failed to recover account: {}", e);
+                    Err(e)
+                }
+            }
+        }
         _ => {
             eprintln!("Invalid option");
             Err(Box::from("Invalid option selected"))
@@ -59,48 +76,144 @@ pub async fn cli() ->Result<
     }
 }
 /*
-Takes user input for username, email, and password, generates a UUID (if it doesn't exist), and sends a request
+Asks for username, email and password (twice, without echo), generates a UUID (if it doesn't exist), and sends a request
 to the server to create a new account.
 Eventually, should return a websocket connection to the server for further communication.
 */
 async fn new_account() -> Result<
     (
+        String,
         SplitSink<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>, Message>,
         SplitStream<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>>
     ), Box<dyn std::error::Error + Send + Sync>> {
-    // Implement the logic for creating a new account
-    let mut stdout = io::stdout();
-    stdout.write_all(b"Enter username, email, and password in format 'username,email,password': ").await?;
-    stdout.flush().await?;
+    let username = prompt::username().await?;
+    let email = prompt::email().await?;
+    let password = prompt::new_password().await?;
 
-    let mut input = String::new();
-    let mut reader = BufReader::new(io::stdin());
-    reader.read_line(&mut input).await?;
+    let (send, recv, recovery_codes) = auth_commands::new_account(&username, &email, &password).await?;
+    print_recovery_codes(&recovery_codes);
+    Ok((username, send, recv))
+}
+
+fn print_recovery_codes(codes: &[String]) {
+    println!("Recovery codes, each resets your password once if you lose it.");
+    println!("Store them somewhere safe, they won't be shown again:");
+    for code in codes {
+        println!("  {}", code);
+    }
+}
+
+/*
+Resets a forgotten password with one of the recovery codes shown when the account was
+created, then logs in. Works on a device the account has never used.
+*/
+async fn recover() -> Result<
+    (
+        String,
+        SplitSink<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>, Message>,
+        SplitStream<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>>
+    ), Box<dyn std::error::Error + Send + Sync>> {
+    let username = prompt::username().await?;
+    let code = prompt::line("Recovery code: ").await?;
+    let password = prompt::new_password().await?;
+
+    print_warning(auth_commands::recover_account(&username, &code, &password).await?);
+    let (send, recv, warning) = auth_commands::login_existing(&username).await?;
+    print_warning(warning);
+    Ok((username, send, recv))
+}
 
-    //split input by commas
-    let parts: Vec<&str> = input.trim().split(',').collect();
-    if parts.len() != 3 {
-        eprintln!("Invalid input format. Please provide username, email, and password separated by commas.");
-        return Err(Box::from("Invalid input format"));
+//Changes the password of a logged in account, asking for the old password first
+pub async fn change_password_command(username: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let old_password = prompt::secret("Current password: ").await?;
+    let new_password = prompt::new_password().await?;
+    print_warning(auth_commands::change_password(username, &old_password, &new_password).await?);
+    println!("Password changed, other devices have to log in again");
+    Ok(())
+}
+
+//Shows what a login step warned about, before any session takes over the terminal
+fn print_warning(warning: Option<String>) {
+    if let Some(warning) = warning {
+        eprintln!("{}", warning);
     }
-    let username = parts[0].trim();
-    let email = parts[1].trim();
-    let password = parts[2].trim();
+}
+
+//Issues a new batch of recovery codes, invalidating the unused ones
+pub async fn recovery_codes_command(username: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let codes = auth_commands::regenerate_recovery_codes(username).await?;
+    print_recovery_codes(&codes);
+    Ok(())
+}
 
-    Ok(auth_commands::new_account(username, email, password).await?)
+const SECOND_FACTOR_PROMPT: &str = "Authentication code (or a backup code): ";
+
+/*
+Upgrades an account created before passwords were hashed on the client. The server only
+knows the plaintext password, so it is sent one last time, after the user agrees to it.
+*/
+pub async fn upgrade_password_command(username: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    println!("{} still has its password stored on the server as typed.", username);
+    println!("Upgrading sends the password to the server once more, after which only a hash derived from it is ever sent.");
+    if prompt::line(&format!("Type {} to continue: ", username)).await? != username {
+        return Err(Box::from("Upgrade cancelled"));
+    }
+    let password = prompt::secret(&format!("Password for {}: ", username)).await?;
+    let warning = match auth_commands::upgrade_legacy_password(username, &password, None).await {
+        Err(e) if auth_commands::is_two_factor_required(e.as_ref()) => {
+            let code = prompt::line(SECOND_FACTOR_PROMPT).await?;
+            auth_commands::upgrade_legacy_password(username, &password, Some(&code)).await?
+        }
+        result => result?,
+    };
+    print_warning(warning);
+    println!("Password upgraded, log in as usual from now on");
+    Ok(())
+}
+
+/*
+Turns two-factor login on or off. Enabling shows the secret to add to an authenticator app
+and asks for a code from it before anything changes on the server.
+*/
+pub async fn two_factor_command(username: &str, action: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    match action {
+        "enable" => {
+            let totp = Totp::generate();
+            println!("Add this account to your authenticator app with the link below, or enter the key by hand.");
+            println!("  {}", totp.provisioning_uri(username));
+            println!("  Key: {}", totp.secret_base32());
+            let code = prompt::line("Code shown by the app: ").await?;
+            let backup_codes = auth_commands::enable_two_factor(username, &totp, &code).await?;
+            println!("Two-factor login enabled.");
+            println!("Backup codes, each works once in place of an authenticator code.");
+            println!("Store them somewhere safe, they won't be shown again:");
+            for code in backup_codes {
+                println!("  {}", code);
+            }
+        }
+        "disable" => {
+            let code = prompt::line(SECOND_FACTOR_PROMPT).await?;
+            auth_commands::disable_two_factor(username, &code).await?;
+            println!("Two-factor login disabled");
+        }
+        _ => return Err(Box::from("Usage: two-factor <username> enable|disable")),
+    }
+    Ok(())
 }
 
+
 async fn login() -> Result<
 (
+    String,
     SplitSink<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>, Message>,
     SplitStream<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>>
 ), Box<dyn std::error::Error + Send + Sync>> {
     //check for previously logged in users
     let mut stdout = io::stdout();
-    
-    // Check if file exists first
-    //If not, present options to login with new account or quit
-    if tokio::fs::metadata("users.csv").await.is_err() {
+    let accounts = accounts::list_accounts().await?;
+
+    //If there are none, present options to login with new account or quit
+    if accounts.is_empty() {
         stdout.write_all(b"No previous users found.\n1. Login with new account\n2. Quit\nSelect option: ").await?;
         stdout.flush().await?;
         
@@ -109,7 +222,7 @@ async fn login() -> Result<
         reader.read_line(&mut input).await?;
         let input = input.trim();
         if input == "1" {
-            return Ok(login_new().await?);
+            return login_new().await;
         } else if input == "2" {
             return Err(Box::from("User chose to quit"));
         } else {
@@ -118,27 +231,16 @@ async fn login() -> Result<
         }
         
     }
-    //if users.csv exists...
-    let mut readcsv = ReaderBuilder::new()
-        .has_headers(false)
-        .from_path("users.csv")?;
-    let mut option_number = 0;
-    let mut records: Vec<String> = Vec::new();
-
-    //print options for existing users
-    for result in readcsv.records() {
-        option_number += 1;
-        let record = result?;
-        if let Some(username) = record.get(0) {
-            let username = username.trim();
-            stdout.write_all(format!("{}. {}\n", option_number, username).as_bytes()).await?;
-            stdout.flush().await?;
-            records.push(username.to_string());
-        }
+
+    //print options for existing users, the default account is listed first
+    for (i, account) in accounts.iter().enumerate() {
+        let marker = if account.is_default { " (default)" } else { "" };
+        let last_login = account.last_login.as_deref().map(|at| format!(", last login {}", at)).unwrap_or_default();
+        stdout.write_all(format!("{}. {}{} @ {}{}\n", i + 1, account.username, marker, account.server, last_login).as_bytes()).await?;
     }
     //print new account option
-    option_number += 1;
-    stdout.write_all(format!("{}. Login with new account\nSelect option from above: ", option_number).as_bytes()).await?;
+    let option_number = accounts.len() + 1;
+    stdout.write_all(format!("{}. Login with new account\nSelect option from above (blank for default): ", option_number).as_bytes()).await?;
     stdout.flush().await?;
     
     //read user input for option selection
@@ -146,50 +248,78 @@ async fn login() -> Result<
     let mut reader = BufReader::new(io::stdin());
     reader.read_line(&mut input).await?;
     let input = input.trim();
-    let selected_option = input.parse::<usize>().unwrap_or(0);
+    let selected_option = if input.is_empty() { 1 } else { input.parse::<usize>().unwrap_or(0) };
 
     if selected_option == option_number {
-        return Ok(login_new().await?);
-    } else if selected_option > 0 && selected_option <= records.len() {
-        let username = &records[selected_option - 1];
-        return Ok(login_existing(username).await?);
+        login_new().await
+    } else if selected_option > 0 && selected_option <= accounts.len() {
+        let username = &accounts[selected_option - 1].username;
+        login_existing(username).await
     } else {
         eprintln!("Invalid option selected.");
-        return Err(Box::from("Invalid option selected"));
+        Err(Box::from("Invalid option selected"))
     }
 }
 
 async fn login_existing(username: &str) -> Result<
     (
+        String,
         SplitSink<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>, Message>,
         SplitStream<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>>
     ), Box<dyn std::error::Error + Send + Sync>> {
-    Ok(auth_commands::login_existing(username).await?)
+    match auth_commands::login_existing(username).await {
+        Ok((send, recv, warning)) => {
+            print_warning(warning);
+            Ok((username.to_string(), send, recv))
+        }
+        Err(e) if establish_websocket::is_auth_failure(e.as_ref()) => {
+            //stored session is no longer valid, fall back to a password login for the same user
+            eprintln!("{}", e);
+            relogin(username).await
+        }
+        Err(e) => Err(e),
+    }
 }
 
-async fn login_new() -> Result<
+async fn relogin(username: &str) -> Result<
     (
+        String,
         SplitSink<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>, Message>,
         SplitStream<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>>
     ), Box<dyn std::error::Error + Send + Sync>> {
-    let mut stdout = io::stdout();
-    stdout.write_all(b"Enter username and password in format 'username,password': ").await?;
-    stdout.flush().await?;
+    let password = prompt::secret(&format!("Password for {}: ", username)).await?;
 
-    let mut input = String::new();
-    let mut reader = BufReader::new(io::stdin());
-    reader.read_line(&mut input).await?;
+    let warning = match auth_commands::authenticate(username, &password, None).await {
+        Err(e) if auth_commands::is_two_factor_required(e.as_ref()) => {
+            let code = prompt::line(SECOND_FACTOR_PROMPT).await?;
+            auth_commands::authenticate(username, &password, Some(&code)).await?
+        }
+        result => result?,
+    };
+    print_warning(warning);
+    let (send, recv, warning) = auth_commands::login_existing(username).await?;
+    print_warning(warning);
+    Ok((username.to_string(), send, recv))
+}
 
-    //split input by commas
-    let parts: Vec<&str> = input.trim().split(',').collect();
-    if parts.len() != 3 {
-        eprintln!("Invalid input format. Please provide username, email, and password separated by commas.");
-        return Err(Box::from("Invalid input format"));
-    }
-    let username = parts[0].trim();
-    let password = parts[1].trim();
+async fn login_new() -> Result<
+    (
+        String,
+        SplitSink<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>, Message>,
+        SplitStream<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>>
+    ), Box<dyn std::error::Error + Send + Sync>> {
+    let username = prompt::username().await?;
+    let password = prompt::secret("Password: ").await?;
 
-    Ok(auth_commands::login_new(username, password).await?)
+    let (send, recv, warning) = match auth_commands::login_new(&username, &password, None).await {
+        Err(e) if auth_commands::is_two_factor_required(e.as_ref()) => {
+            let code = prompt::line(SECOND_FACTOR_PROMPT).await?;
+            auth_commands::login_new(&username, &password, Some(&code)).await?
+        }
+        result => result?,
+    };
+    print_warning(warning);
+    Ok((username, send, recv))
 }
 
 
@@ -198,3 +328,34 @@ async fn login_new() -> Result<
 
 
 
+
+//attempts at the vault passphrase before giving up
+const VAULT_ATTEMPTS: usize = 3;
+
+/*
+Unlocks the credential vault when config.json selects the "vault" store, creating it
+(passphrase asked twice) on first use. Nothing to do for the other stores.
+*/
+pub async fn unlock_credentials() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let store = credential_store::current().await?;
+    let Some(vault) = store.vault() else {
+        return Ok(());
+    };
+    if !vault.is_locked() {
+        return Ok(());
+    }
+
+    if !vault.exists() {
+        let passphrase = prompt::new_secret("Choose a passphrase for the credential vault: ", "Repeat the passphrase: ", 0).await?;
+        return vault.unlock(&passphrase);
+    }
+
+    for _ in 0..VAULT_ATTEMPTS {
+        let passphrase = prompt::secret("Credential vault passphrase: ").await?;
+        match vault.unlock(&passphrase) {
+            Ok(()) => return Ok(()),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+    Err(Box::from("Could not unlock the credential vault"))
+}
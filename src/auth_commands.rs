@@ -1,20 +1,29 @@
-use tokio::fs::OpenOptions;
-use tokio::io::AsyncWriteExt;
-use csv::ReaderBuilder;
 use serde_json::{json};
 use tokio_tungstenite::{tungstenite::protocol::Message, WebSocketStream};
 use futures_util::stream::{SplitSink, SplitStream};
 use tokio::net::TcpStream;
+use std::fmt;
 
 use crate::manage_keys;
 use crate::establish_websocket;
 use crate::to_server;
 use crate::db;
+use crate::accounts;
+use crate::clock::unix_now;
+use crate::recovery;
+use crate::totp::Totp;
+use crate::password_auth::{self, PasswordKeys};
+use crate::credential_store;
 
+/*
+Creates the account on the server and logs in. Also returns the account's recovery codes:
+the server only keeps their digests, so this is the one time they can be shown to the user.
+*/
 pub async fn new_account(username: &str, email: &str, password: &str) -> Result<
     (
         SplitSink<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>, Message>,
-        SplitStream<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>>
+        SplitStream<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>>,
+        Vec<String>
     ), Box<dyn std::error::Error + Send + Sync>> {
     //create uuid for the new account
     let dev_id = match manage_keys::get_uuid(username).await {
@@ -24,41 +33,76 @@ pub async fn new_account(username: &str, email: &str, password: &str) -> Result<
         }
     };
 
+    let keys = PasswordKeys::derive(username, password)?;
+    let recovery_codes = recovery::generate_codes();
     let request = json!({
         "type": "new_account",
         "username": username,
         "email": email,
-        "password": password,
-        "uuid": dev_id
+        "password": keys.verifier(),
+        "auth_version": password_auth::AUTH_VERSION,
+        "uuid": dev_id,
+        "recovery_codes": recovery::digests(&recovery_codes)?
     });
 
     let resp = to_server::to_server("new_account", request).await?;
-    let token = resp.get("token").and_then(|t| t.as_str()).unwrap_or("");
     let device_id = resp.get("device_id").and_then(|d| d.as_str()).unwrap_or("");
     //store token & device_id securely in WCM for future auth
-    manage_keys::store_token(token, username).await?;
+    store_session_token(username, &resp).await?;
     manage_keys::store_device_id(username, device_id).await?;
     manage_keys::store_uuid(username, &dev_id).await?;
     
     db::initialize_db(username).await?;
+    /*
+    initialize_db just made sure the database key exists, so nothing gets restored here and the
+    warning is always None. This only wraps the key under the new password, replacing any copy
+    an earlier account of the same name left behind.
+    */
+    password_auth::unlock_local_state(username, &keys).await?;
 
-    //store the user in a local file for future login
-    store_user(username).await?;
+    //register the account on this client for future login
+    accounts::add_account(username, to_server::SERVER, Some(device_id)).await?;
 
-    Ok(login_existing(username).await?)
+    //the token was issued just now, it never needs a refresh here
+    let (send, recv, _) = login_existing(username).await?;
+    Ok((send, recv, recovery_codes))
 }
 
-pub async fn login_new(username: &str, password: &str) -> Result<
+/*
+Logs in with username and password on a device without a usable session. second_factor is
+the authenticator or backup code for accounts with two-factor login; without one such an
+account fails with TwoFactorRequired, and the caller can ask for a code and try again.
+Also returns login_existing's warning, or authenticate's if the password was needed.
+*/
+pub async fn login_new(username: &str, password: &str, second_factor: Option<&str>) -> Result<
     (
         SplitSink<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>, Message>,
-        SplitStream<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>>
+        SplitStream<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>>,
+        Option<String>
     ), Box<dyn std::error::Error + Send + Sync>> {
 
-    if check_for_user(username).await? {
-        
-        return Ok(login_existing(username).await?);
+    let known_user = accounts::get_account(username).await?.is_some();
+    if known_user {
+        match login_existing(username).await {
+            //stored session was rejected, re-authenticate with the password we were given
+            Err(e) if establish_websocket::is_auth_failure(e.as_ref()) => {}
+            result => return result,
+        }
     }
 
+    let warning = authenticate(username, password, second_factor).await?;
+
+    let (send, recv, refresh_warning) = login_existing(username).await?;
+    Ok((send, recv, warning.or(refresh_warning)))
+}
+
+/*
+Exchanges username and password for a fresh token and device id.
+Used for first login on this device and to recover once the stored token has been rejected.
+Returns a warning for the user when the database key couldn't be restored from its
+password-wrapped copy, see password_auth::unlock_local_state.
+*/
+pub async fn authenticate(username: &str, password: &str, second_factor: Option<&str>) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
     let dev_id = match manage_keys::get_uuid(username).await {
         Ok(id) => id,
         Err(_) => {
@@ -66,55 +110,292 @@ pub async fn login_new(username: &str, password: &str) -> Result<
         }
     };
 
-    let resp = to_server::to_server("authenticate", json!({
+    let keys = PasswordKeys::derive(username, password)?;
+    let mut request = json!({
+        "username": username,
+        "password": keys.verifier(),
+        "auth_version": password_auth::AUTH_VERSION,
+        "uuid": dev_id
+    });
+    if let Some(code) = second_factor {
+        request["second_factor"] = json!(code.trim());
+    }
+    let resp = to_server::to_server("authenticate", request).await?;
+    /*
+    Accounts created before passwords were hashed on the client still have the plaintext
+    password on record. A server flag is never a reason to send the plaintext, only
+    upgrade_legacy_password does that and the user has to run it.
+    */
+    if resp.get("password_upgrade_required").and_then(|r| r.as_bool()).unwrap_or(false) {
+        return Err(Box::new(PasswordUpgradeRequired { username: username.to_string() }));
+    }
+    finish_authentication(username, &dev_id, &keys, &resp).await
+}
+
+/*
+Moves an account created before passwords were hashed on the client over to the verifier.
+The server only has the plaintext password on record, so this sends it one last time along
+with the verifier that replaces it. Only ever run on the user's explicit request (the
+upgrade-password command), never in answer to the server. Logs in like authenticate.
+*/
+pub async fn upgrade_legacy_password(username: &str, password: &str, second_factor: Option<&str>) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+    let dev_id = match manage_keys::get_uuid(username).await {
+        Ok(id) => id,
+        Err(_) => manage_keys::generate_uuid(username).await?,
+    };
+
+    let keys = PasswordKeys::derive(username, password)?;
+    let mut request = json!({
         "username": username,
         "password": password,
+        "new_password": keys.verifier(),
+        "auth_version": password_auth::AUTH_VERSION,
         "uuid": dev_id
-    })).await?;
+    });
+    if let Some(code) = second_factor {
+        request["second_factor"] = json!(code.trim());
+    }
+    let resp = to_server::to_server("authenticate", request).await?;
+    finish_authentication(username, &dev_id, &keys, &resp).await
+}
+
+//Stores what a successful authenticate response carries and registers the account
+async fn finish_authentication(username: &str, dev_id: &str, keys: &PasswordKeys, resp: &serde_json::Value) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+    //the password was right but the account wants a code as well
+    if resp.get("two_factor_required").and_then(|r| r.as_bool()).unwrap_or(false) {
+        return Err(Box::new(TwoFactorRequired { username: username.to_string() }));
+    }
 
     //store token & device id securely in WCM for future auth
-    manage_keys::store_token(resp.get("token").unwrap().as_str().unwrap(), username).await?;
-    manage_keys::store_device_id(username, resp.get("device_id").unwrap().as_str().unwrap()).await?;
-    manage_keys::store_uuid(username, &dev_id).await?;
-    //store the user in a local file for future login
-    store_user(username).await?;
+    store_session_token(username, resp).await?;
+    let device_id = resp.get("device_id").and_then(|d| d.as_str()).ok_or("Device ID not found")?;
+    manage_keys::store_device_id(username, device_id).await?;
+    manage_keys::store_uuid(username, dev_id).await?;
+    let warning = password_auth::unlock_local_state(username, keys).await?;
+    //register (or update) the account on this client for future login
+    accounts::add_account(username, to_server::SERVER, Some(device_id)).await?;
 
-    Ok(login_existing(username).await?)
+    Ok(warning)
 }
 
+/*
+Connects with the stored session. Also returns a warning for the user when the token was due
+for a refresh and the refresh failed; the old token is still tried, it may not have expired yet.
+*/
 pub async fn login_existing(username: &str) -> Result<
     (
         SplitSink<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>, Message>,
-        SplitStream<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>>
+        SplitStream<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>>,
+        Option<String>
     ), Box<dyn std::error::Error + Send + Sync>> {
 
-    Ok(establish_websocket::establish_websocket(username).await?)
+    //swap the token before connecting rather than have the server reject it mid-handshake
+    let mut warning = None;
+    if token_needs_refresh(username).await
+        && let Err(e) = refresh_token(username).await {
+        warning = Some(format!("Token refresh failed: {}", e));
+    }
+
+    let (send, recv) = establish_websocket::establish_websocket(username).await?;
+    accounts::record_login(username).await?;
+    Ok((send, recv, warning))
+}
+
+/*
+Returned by authenticate when the account has two-factor login and no code (or a wrong one)
+was given. Callers downcast to this to ask for a code rather than for the password again.
+*/
+#[derive(Debug)]
+pub struct TwoFactorRequired {
+    pub username: String,
+}
+
+impl fmt::Display for TwoFactorRequired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} requires an authentication code to log in", self.username)
+    }
+}
+
+impl std::error::Error for TwoFactorRequired {}
+
+pub fn is_two_factor_required(e: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+    e.downcast_ref::<TwoFactorRequired>().is_some()
+}
+
+/*
+Returned by authenticate when the server still has the account's plaintext password on
+record. Logging in needs a one-time upgrade_legacy_password first, which the user starts.
+*/
+#[derive(Debug)]
+pub struct PasswordUpgradeRequired {
+    pub username: String,
+}
+
+impl fmt::Display for PasswordUpgradeRequired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{0} was created before passwords were hashed on the client. Run \"e_to_e_msgr upgrade-password {0}\" once to upgrade it",
+            self.username
+        )
+    }
+}
+
+impl std::error::Error for PasswordUpgradeRequired {}
+
+//refresh this long before the server-side expiry so in-flight requests don't race it
+pub const TOKEN_REFRESH_MARGIN_SECS: i64 = 60;
+
+/*
+Stores the token from an auth response along with its expiry.
+The server may send either "expires_at" (unix seconds) or "expires_in" (seconds from now);
+if neither is present the expiry is left unknown and the token is only replaced on rejection.
+*/
+pub async fn store_session_token(username: &str, resp: &serde_json::Value) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let token = resp.get("token").and_then(|t| t.as_str()).ok_or("Token not found")?;
+    manage_keys::store_token(token, username).await?;
+
+    let expires_at = resp.get("expires_at").and_then(|e| e.as_i64())
+        .or_else(|| resp.get("expires_in").and_then(|e| e.as_i64()).map(|secs| unix_now() + secs));
+    if let Some(expires_at) = expires_at {
+        manage_keys::store_token_expiry(username, expires_at).await?;
+    }
+
+    Ok(())
+}
+
+//seconds until the stored token should be refreshed, or None if its lifetime is unknown
+pub async fn seconds_until_refresh(username: &str) -> Option<i64> {
+    let expires_at = manage_keys::get_token_expiry(username).await.ok()?;
+    Some(expires_at - TOKEN_REFRESH_MARGIN_SECS - unix_now())
+}
+
+pub async fn token_needs_refresh(username: &str) -> bool {
+    matches!(seconds_until_refresh(username).await, Some(secs) if secs <= 0)
 }
 
+/*
+Trades the current token for a new one before it expires.
+Fails if the server no longer accepts the current token, in which case the user has to log in again.
+*/
+pub async fn refresh_token(username: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    //the server retires the old token, so the vault must not lock before the new one is stored
+    let _hold = credential_store::hold(credential_store::current().await?);
+    let resp = to_server::to_server("refresh_token", json!({
+        "username": username,
+        "token": manage_keys::get_token(username).await?,
+        "device_id": manage_keys::get_device_id(username).await?,
+        "uuid": manage_keys::get_uuid(username).await?
+    })).await?;
 
-async fn store_user(username: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open("users.csv")
-        .await?;
-    let content = format!("{}\n", username);
-    file.write_all(content.as_bytes()).await?;
+    store_session_token(username, &resp).await?;
     Ok(())
 }
 
-async fn check_for_user(username: &str) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
-    let mut reader = ReaderBuilder::new()
-        .has_headers(false)  // Add this since you don't have headers
-        .from_path("users.csv")?;
-    
-    for result in reader.records() {
-        let record = result?;
-        if let Some(stored_username) = record.get(0) {
-            if stored_username.trim() == username.trim() {
-                return Ok(true);
-            }
-        }
+
+/*
+Changes the password after the server has checked the old one. The server ends every other
+session of the account and answers with a new token for this device, so a stolen token
+stops working along with the old password. Returns unlock_local_state's warning.
+*/
+pub async fn change_password(username: &str, old_password: &str, new_password: &str) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+    if old_password == new_password {
+        return Err(Box::from("The new password is the same as the old one"));
     }
-    Ok(false)
-}
\ No newline at end of file
+    let new_keys = PasswordKeys::derive(username, new_password)?;
+    let resp = to_server::to_server("change_password", json!({
+        "username": username,
+        "token": manage_keys::get_token(username).await?,
+        "device_id": manage_keys::get_device_id(username).await?,
+        "uuid": manage_keys::get_uuid(username).await?,
+        "old_password": PasswordKeys::derive(username, old_password)?.verifier(),
+        "new_password": new_keys.verifier(),
+        "auth_version": password_auth::AUTH_VERSION
+    })).await?;
+
+    store_session_token(username, &resp).await?;
+    //the wrapped database key follows the password
+    password_auth::unlock_local_state(username, &new_keys).await
+}
+
+//Replaces every unused recovery code of the account with a fresh batch, returned for showing once
+pub async fn regenerate_recovery_codes(username: &str) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+    let codes = recovery::generate_codes();
+    to_server::to_server("recovery_codes", json!({
+        "username": username,
+        "token": manage_keys::get_token(username).await?,
+        "device_id": manage_keys::get_device_id(username).await?,
+        "uuid": manage_keys::get_uuid(username).await?,
+        "recovery_codes": recovery::digests(&codes)?
+    })).await?;
+    Ok(codes)
+}
+
+/*
+Sets a new password with a recovery code, for when the password is lost. Works from a device
+that has never logged in: like authenticate, it registers this device and stores its
+credentials. The server spends the code and ends every other session of the account.
+Returns unlock_local_state's warning.
+*/
+pub async fn recover_account(username: &str, recovery_code: &str, new_password: &str) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+    let recovery_code = recovery::normalize(recovery_code)?;
+    let dev_id = match manage_keys::get_uuid(username).await {
+        Ok(id) => id,
+        Err(_) => manage_keys::generate_uuid(username).await?,
+    };
+
+    let keys = PasswordKeys::derive(username, new_password)?;
+    let resp = to_server::to_server("recover_account", json!({
+        "username": username,
+        "recovery_code": recovery_code,
+        "new_password": keys.verifier(),
+        "auth_version": password_auth::AUTH_VERSION,
+        "uuid": dev_id
+    })).await?;
+
+    store_session_token(username, &resp).await?;
+    let device_id = resp.get("device_id").and_then(|d| d.as_str()).ok_or("Device ID not found")?;
+    manage_keys::store_device_id(username, device_id).await?;
+    manage_keys::store_uuid(username, &dev_id).await?;
+    let warning = password_auth::unlock_local_state(username, &keys).await?;
+    accounts::add_account(username, to_server::SERVER, Some(device_id)).await?;
+
+    Ok(warning)
+}
+
+/*
+Turns on two-factor login with the secret of totp, once code shows the user's authenticator
+app produces the same codes. Returns backup codes, each usable once instead of an
+authenticator code; like recovery codes, only their digests are sent.
+*/
+pub async fn enable_two_factor(username: &str, totp: &Totp, code: &str) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+    if !totp.verify(code, unix_now()) {
+        return Err(Box::from("The code doesn't match, check the authenticator app and the device clock"));
+    }
+    let backup_codes = recovery::generate_codes();
+    to_server::to_server("enable_2fa", json!({
+        "username": username,
+        "token": manage_keys::get_token(username).await?,
+        "device_id": manage_keys::get_device_id(username).await?,
+        "uuid": manage_keys::get_uuid(username).await?,
+        "totp_secret": totp.secret_base32(),
+        "algorithm": totp.algorithm.name(),
+        "digits": totp.digits,
+        "period": totp.step,
+        "code": code.trim(),
+        "backup_codes": recovery::digests(&backup_codes)?
+    })).await?;
+    Ok(backup_codes)
+}
+
+//Turns two-factor login off. Needs a current authenticator or backup code
+pub async fn disable_two_factor(username: &str, code: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    to_server::to_server("disable_2fa", json!({
+        "username": username,
+        "token": manage_keys::get_token(username).await?,
+        "device_id": manage_keys::get_device_id(username).await?,
+        "uuid": manage_keys::get_uuid(username).await?,
+        "code": code.trim()
+    })).await?;
+    Ok(())
+}
@@ -0,0 +1,99 @@
+/**
+ * Client configuration, read from config.json in the config directory.
+ * Every field is optional so a missing or partial file falls back to defaults.
+ */
+use serde::Deserialize;
+use std::env;
+use std::path::PathBuf;
+
+use crate::credential_store::StoreKind;
+
+const APP_DIR: &str = "e_to_e_msgr";
+const CONFIG_FILE: &str = "config.json";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    //overrides where databases and other client state are kept
+    pub data_dir: Option<PathBuf>,
+    pub retention: RetentionConfig,
+    //answer history transfer requests from the user's other devices, off unless set
+    pub serve_history_sync: bool,
+    //where credentials are kept: "keyring", "file", "vault" or "memory", see credential_store
+    pub credential_store: StoreKind,
+    //location of the "file" credential store, defaults to credentials.enc in the data directory
+    pub credential_file: Option<PathBuf>,
+    //seconds the "vault" credential store stays unlocked without being used before it locks and forgets its key
+    pub vault_idle_lock_secs: u64,
+}
+
+//vault stays unlocked for 15 minutes of inactivity unless configured otherwise
+const DEFAULT_VAULT_IDLE_LOCK_SECS: u64 = 15 * 60;
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            data_dir: None,
+            retention: RetentionConfig::default(),
+            serve_history_sync: false,
+            credential_store: StoreKind::default(),
+            credential_file: None,
+            vault_idle_lock_secs: DEFAULT_VAULT_IDLE_LOCK_SECS,
+        }
+    }
+}
+
+/*
+Global message retention, applied by retention::maintenance_task. Conversations can
+override the age and count limits. Limits that are unset keep everything.
+*/
+#[derive(Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RetentionConfig {
+    //delete messages older than this many days
+    pub max_age_days: Option<i64>,
+    //keep at most this many messages per conversation
+    pub max_messages: Option<i64>,
+    //delete the oldest messages while the database holds more than this many bytes
+    pub max_db_bytes: Option<i64>,
+    //VACUUM once deletes leave enough of the file unused
+    pub auto_vacuum: bool,
+}
+
+/*
+E_TO_E_MSGR_CONFIG_DIR if set, otherwise the platform config directory:
+$XDG_CONFIG_HOME/e_to_e_msgr (~/.config/e_to_e_msgr) on Linux, %APPDATA%\e_to_e_msgr on Windows.
+*/
+pub fn config_dir() -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    if let Some(dir) = env::var_os("E_TO_E_MSGR_CONFIG_DIR") {
+        return Ok(PathBuf::from(dir));
+    }
+    #[cfg(windows)]
+    {
+        let appdata = env::var_os("APPDATA").ok_or("APPDATA is not set")?;
+        Ok(PathBuf::from(appdata).join(APP_DIR))
+    }
+    #[cfg(not(windows))]
+    {
+        Ok(xdg_dir("XDG_CONFIG_HOME", ".config")?.join(APP_DIR))
+    }
+}
+
+pub async fn load() -> Result<Config, Box<dyn std::error::Error + Send + Sync>> {
+    let path = config_dir()?.join(CONFIG_FILE);
+    match tokio::fs::read_to_string(&path).await {
+        Ok(contents) => Ok(serde_json::from_str(&contents)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+//$VAR if it is set to an absolute path (as the XDG spec requires), otherwise $HOME/<fallback>
+#[cfg(not(windows))]
+pub fn xdg_dir(var: &str, fallback: &str) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    if let Some(dir) = env::var_os(var).map(PathBuf::from).filter(|d| d.is_absolute()) {
+        return Ok(dir);
+    }
+    let home = env::var_os("HOME").ok_or("HOME is not set")?;
+    Ok(PathBuf::from(home).join(fallback))
+}
@@ -0,0 +1,528 @@
+/**
+ * Where secrets (tokens, device ids, the database key...) are kept.
+ * manage_keys goes through the CredentialStore selected by credential_store in config.json:
+ *   "keyring" (default) - the OS keyring via the keyring crate: Credential Manager on Windows,
+ *               the Keychain on macOS, Secret Service (cached in the kernel keyring) on Linux.
+ *               Other platforms have no keyring backend and must pick another store
+ *   "file"    - an encrypted file, for machines without a keyring daemon. The 32 byte key
+ *               is read base64 encoded from E_TO_E_MSGR_CREDENTIAL_KEY
+ *   "vault"   - an encrypted file whose key is derived from a passphrase, see VaultStore.
+ *               For headless Linux, where there is no keyring daemon to hold the key
+ *   "memory"  - kept in process memory only, for tests and CI
+ * Credentials are addressed like keyring entries, by service name and username.
+ */
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use argon2::Params;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rand::RngCore;
+use zeroize::Zeroizing;
+use tokio::sync::{Notify, OnceCell};
+
+use crate::config;
+use crate::encryption::{self, FieldCipher};
+use crate::paths;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+const KEY_VAR: &str = "E_TO_E_MSGR_CREDENTIAL_KEY";
+const CREDENTIAL_FILE: &str = "credentials.enc";
+const VAULT_FILE: &str = "credentials.vault";
+//longest a vault stays unlocked past its idle timeout before idle_lock_task locks it
+const VAULT_IDLE_CHECK_SECS: u64 = 30;
+//binds the file's ciphertext to its purpose, so a database field can't be passed off as the file
+const FILE_AAD: &[u8] = b"e_to_e_msgr:credentials";
+
+pub trait CredentialStore: Send + Sync {
+    fn set(&self, service: &str, username: &str, secret: &str) -> Result<(), BoxError>;
+    //Errors when nothing is stored. The secret is wiped when the caller drops it
+    fn get(&self, service: &str, username: &str) -> Result<Zeroizing<String>, BoxError>;
+    fn delete(&self, service: &str, username: &str) -> Result<(), BoxError>;
+
+    //The passphrase vault behind this store, if it is one, for unlocking and locking
+    fn vault(&self) -> Option<&VaultStore> {
+        None
+    }
+}
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    #[default]
+    Keyring,
+    File,
+    Vault,
+    Memory,
+}
+
+static STORE: OnceCell<Arc<dyn CredentialStore>> = OnceCell::const_new();
+
+//The store selected by config.json, opened on first use
+pub async fn current() -> Result<Arc<dyn CredentialStore>, BoxError> {
+    let store = STORE.get_or_try_init(|| async {
+        let config = config::load().await?;
+        open(config.credential_store, config.credential_file, config.vault_idle_lock_secs).await
+    }).await?;
+    Ok(store.clone())
+}
+
+pub async fn open(kind: StoreKind, file: Option<PathBuf>, idle_lock_secs: u64) -> Result<Arc<dyn CredentialStore>, BoxError> {
+    Ok(match kind {
+        StoreKind::Keyring => {
+            //without a native backend the keyring crate falls back to a mock that keeps nothing
+            if !cfg!(any(target_os = "windows", target_os = "macos", target_os = "linux")) {
+                return Err(Box::from("This platform has no OS keyring, set credential_store to \"vault\" or \"file\" in config.json"));
+            }
+            Arc::new(KeyringStore)
+        }
+        StoreKind::Memory => Arc::new(MemoryStore::default()),
+        StoreKind::File => {
+            let path = match file {
+                Some(path) => path,
+                None => paths::data_dir().await?.join(CREDENTIAL_FILE),
+            };
+            let encoded = std::env::var(KEY_VAR)
+                .map_err(|_| format!("{} must be set to use the file credential store", KEY_VAR))?;
+            let key: [u8; 32] = STANDARD.decode(encoded.trim())?
+                .try_into()
+                .map_err(|_| format!("{} must be 32 bytes, base64 encoded", KEY_VAR))?;
+            Arc::new(EncryptedFileStore::new(path, &key))
+        }
+        StoreKind::Vault => {
+            let path = match file {
+                Some(path) => path,
+                None => paths::data_dir().await?.join(VAULT_FILE),
+            };
+            let idle_timeout = Duration::from_secs(idle_lock_secs);
+            let vault = Arc::new(VaultStore::new(path, idle_timeout));
+            let interval = idle_timeout.clamp(Duration::from_secs(1), Duration::from_secs(VAULT_IDLE_CHECK_SECS));
+            tokio::spawn(idle_lock_task(Arc::downgrade(&vault), interval));
+            vault
+        }
+    })
+}
+
+/*
+Locks vault once it has gone unused for its idle timeout, which wipes the key, instead of
+leaving the key in memory until the next credential operation notices. Ends with the vault.
+*/
+async fn idle_lock_task(vault: Weak<VaultStore>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(vault) = vault.upgrade() else {
+            return;
+        };
+        vault.lock_if_idle();
+    }
+}
+
+//Decrypted credentials of the file and vault stores, by "service/username", wiped when dropped
+type Entries = HashMap<String, Zeroizing<String>>;
+
+//Nothing is stored under the service and username. Any other error means the store couldn't be read
+#[derive(Debug)]
+pub struct NotFound {
+    service: String,
+    username: String,
+}
+
+impl std::fmt::Display for NotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No {} credential stored for {}", self.service, self.username)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+fn not_found(service: &str, username: &str) -> BoxError {
+    Box::new(NotFound { service: service.to_string(), username: username.to_string() })
+}
+
+//Whether e says the credential doesn't exist, as opposed to the store failing (locked vault, keyring error...)
+pub fn is_not_found(e: &BoxError) -> bool {
+    e.downcast_ref::<NotFound>().is_some()
+}
+
+/*
+KEYRING
+*/
+pub struct KeyringStore;
+
+impl CredentialStore for KeyringStore {
+    fn set(&self, service: &str, username: &str, secret: &str) -> Result<(), BoxError> {
+        keyring::Entry::new(service, username)
+            .and_then(|entry| entry.set_password(secret))
+            .map_err(keyring_error)
+    }
+
+    fn get(&self, service: &str, username: &str) -> Result<Zeroizing<String>, BoxError> {
+        match keyring::Entry::new(service, username).and_then(|entry| entry.get_password()) {
+            Ok(secret) => Ok(Zeroizing::new(secret)),
+            Err(keyring::Error::NoEntry) => Err(not_found(service, username)),
+            Err(e) => Err(keyring_error(e)),
+        }
+    }
+
+    fn delete(&self, service: &str, username: &str) -> Result<(), BoxError> {
+        match keyring::Entry::new(service, username).and_then(|entry| entry.delete_credential()) {
+            Ok(()) => Ok(()),
+            Err(keyring::Error::NoEntry) => Err(not_found(service, username)),
+            Err(e) => Err(keyring_error(e)),
+        }
+    }
+}
+
+//A keyring that can't be reached, typically no Secret Service daemon on a headless Linux machine, points at the vault
+fn keyring_error(e: keyring::Error) -> BoxError {
+    match e {
+        keyring::Error::PlatformFailure(_) | keyring::Error::NoStorageAccess(_) => Box::from(format!(
+            "The OS keyring is unavailable ({}). Without a keyring daemon, set credential_store to \"vault\" in config.json",
+            e
+        )),
+        e => Box::new(e),
+    }
+}
+
+/*
+MEMORY
+*/
+#[derive(Default)]
+pub struct MemoryStore {
+    entries: Mutex<HashMap<(String, String), Zeroizing<String>>>,
+}
+
+impl CredentialStore for MemoryStore {
+    fn set(&self, service: &str, username: &str, secret: &str) -> Result<(), BoxError> {
+        let mut entries = self.entries.lock().map_err(|_| "Credential store lock poisoned")?;
+        entries.insert((service.to_string(), username.to_string()), Zeroizing::new(secret.to_string()));
+        Ok(())
+    }
+
+    fn get(&self, service: &str, username: &str) -> Result<Zeroizing<String>, BoxError> {
+        let entries = self.entries.lock().map_err(|_| "Credential store lock poisoned")?;
+        entries.get(&(service.to_string(), username.to_string()))
+            .cloned()
+            .ok_or_else(|| not_found(service, username))
+    }
+
+    fn delete(&self, service: &str, username: &str) -> Result<(), BoxError> {
+        let mut entries = self.entries.lock().map_err(|_| "Credential store lock poisoned")?;
+        entries.remove(&(service.to_string(), username.to_string()))
+            .map(|_| ())
+            .ok_or_else(|| not_found(service, username))
+    }
+}
+
+/*
+ENCRYPTED FILE
+The whole file is one encrypted JSON object of "service/username" -> secret. Every change
+rewrites it through a temporary file and a rename, so a crash can't leave it half written.
+*/
+pub struct EncryptedFileStore {
+    path: PathBuf,
+    cipher: FieldCipher,
+    //serialises read-modify-write cycles within the process
+    lock: Mutex<()>,
+}
+
+impl EncryptedFileStore {
+    pub fn new(path: PathBuf, key: &[u8; 32]) -> Self {
+        EncryptedFileStore { path, cipher: FieldCipher::new(key), lock: Mutex::new(()) }
+    }
+
+    fn entry_key(service: &str, username: &str) -> String {
+        format!("{}/{}", service, username)
+    }
+
+    fn read(&self) -> Result<Entries, BoxError> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(contents) => {
+                let plaintext = Zeroizing::new(self.cipher.decrypt_for(FILE_AAD, contents.trim())?);
+                Ok(serde_json::from_str(&plaintext)?)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    fn write(&self, entries: &Entries) -> Result<(), BoxError> {
+        let sealed = self.cipher.encrypt_for(FILE_AAD, &Zeroizing::new(serde_json::to_string(entries)?))?;
+        paths::write_private(&self.path, sealed.as_bytes())
+    }
+}
+
+impl CredentialStore for EncryptedFileStore {
+    fn set(&self, service: &str, username: &str, secret: &str) -> Result<(), BoxError> {
+        let _guard = self.lock.lock().map_err(|_| "Credential store lock poisoned")?;
+        let mut entries = self.read()?;
+        entries.insert(Self::entry_key(service, username), Zeroizing::new(secret.to_string()));
+        self.write(&entries)
+    }
+
+    fn get(&self, service: &str, username: &str) -> Result<Zeroizing<String>, BoxError> {
+        let _guard = self.lock.lock().map_err(|_| "Credential store lock poisoned")?;
+        self.read()?
+            .remove(&Self::entry_key(service, username))
+            .ok_or_else(|| not_found(service, username))
+    }
+
+    fn delete(&self, service: &str, username: &str) -> Result<(), BoxError> {
+        let _guard = self.lock.lock().map_err(|_| "Credential store lock poisoned")?;
+        let mut entries = self.read()?;
+        if entries.remove(&Self::entry_key(service, username)).is_none() {
+            return Err(not_found(service, username));
+        }
+        self.write(&entries)
+    }
+}
+
+/*
+PASSPHRASE VAULT
+Like the encrypted file, but the key is derived from a passphrase with Argon2id and only held
+in memory between unlock and lock. The vault locks itself once it has gone unused for its idle
+timeout, unless a VaultHold keeps it open; while locked every credential operation fails until
+it is unlocked again. A store opened with open also gets an idle_lock_task, so the key is wiped
+on time rather than on the next credential operation.
+
+File layout (integers little endian):
+  VAULT_MAGIC | version u8 | m_cost u32 | t_cost u32 | p_cost u32 | salt | nonce | ciphertext
+The header is authenticated as associated data. Each write uses a fresh nonce but keeps the salt,
+so the derived key stays valid until the passphrase changes.
+*/
+const VAULT_MAGIC: &[u8; 8] = b"E2EMVLT\0";
+const VAULT_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+//everything before the nonce
+const VAULT_PREFIX_LEN: usize = VAULT_MAGIC.len() + 1 + 12 + SALT_LEN;
+
+pub struct VaultStore {
+    path: PathBuf,
+    idle_timeout: Duration,
+    state: Mutex<Option<UnlockedVault>>,
+    //live VaultHolds, the vault doesn't idle-lock while there are any
+    holds: AtomicUsize,
+    //woken by unlock, for whoever waits in unlocked
+    unlocked: Notify,
+}
+
+struct UnlockedVault {
+    key: Zeroizing<[u8; 32]>,
+    //the header up to the nonce, reused for every write
+    prefix: Vec<u8>,
+    last_used: Instant,
+}
+
+impl VaultStore {
+    pub fn new(path: PathBuf, idle_timeout: Duration) -> Self {
+        VaultStore { path, idle_timeout, state: Mutex::new(None), holds: AtomicUsize::new(0), unlocked: Notify::new() }
+    }
+
+    //Whether the vault file has been created yet, unlock creates it otherwise
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    //Whether credential operations fail until unlock, including a vault idle long enough to lock at its next use
+    pub fn is_locked(&self) -> bool {
+        self.state.lock().map(|state| state.as_ref().is_none_or(|s| self.is_idle(s))).unwrap_or(true)
+    }
+
+    //Waits until the vault is unlocked, returns straight away if it is
+    pub async fn unlocked(&self) {
+        loop {
+            //registered before the check, so an unlock in between still wakes it
+            let notified = self.unlocked.notified();
+            if !self.is_locked() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /*
+    Derives the key from passphrase and checks it against the vault file.
+    Creates an empty vault protected by passphrase if there is no file yet.
+    */
+    pub fn unlock(&self, passphrase: &str) -> Result<(), BoxError> {
+        let mut state = self.state.lock().map_err(|_| "Credential vault lock poisoned")?;
+        let unlocked = if self.exists() {
+            let data = std::fs::read(&self.path)?;
+            let (prefix, params, salt) = parse_vault_prefix(&data)?;
+            let key = encryption::derive_key(passphrase, &salt, params)?;
+            open_vault(&key, &data)
+                .map_err(|_| "Wrong passphrase, or the credential vault is corrupted")?;
+            UnlockedVault { key, prefix, last_used: Instant::now() }
+        } else {
+            let params = Params::default();
+            let mut salt = [0u8; SALT_LEN];
+            OsRng.fill_bytes(&mut salt);
+            let mut prefix = Vec::with_capacity(VAULT_PREFIX_LEN);
+            prefix.extend_from_slice(VAULT_MAGIC);
+            prefix.push(VAULT_VERSION);
+            prefix.extend_from_slice(&params.m_cost().to_le_bytes());
+            prefix.extend_from_slice(&params.t_cost().to_le_bytes());
+            prefix.extend_from_slice(&params.p_cost().to_le_bytes());
+            prefix.extend_from_slice(&salt);
+            let unlocked = UnlockedVault {
+                key: encryption::derive_key(passphrase, &salt, params)?,
+                prefix,
+                last_used: Instant::now(),
+            };
+            self.write(&unlocked, &HashMap::new())?;
+            unlocked
+        };
+        *state = Some(unlocked);
+        self.unlocked.notify_waiters();
+        Ok(())
+    }
+
+    //Forgets the key, the key bytes are wiped as it is dropped
+    pub fn lock(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            *state = None;
+        }
+    }
+
+    //Locks the vault if it has been idle longer than its timeout and isn't held. Returns true if it locked
+    pub fn lock_if_idle(&self) -> bool {
+        let Ok(mut state) = self.state.lock() else {
+            return false;
+        };
+        if state.as_ref().is_some_and(|s| self.is_idle(s)) {
+            *state = None;
+            return true;
+        }
+        false
+    }
+
+    fn is_idle(&self, unlocked: &UnlockedVault) -> bool {
+        self.holds.load(Ordering::SeqCst) == 0 && unlocked.last_used.elapsed() >= self.idle_timeout
+    }
+
+    //The idle timeout starts over when the last hold goes, rather than locking the moment a long session ends
+    fn release(&self) {
+        if self.holds.fetch_sub(1, Ordering::SeqCst) == 1
+            && let Ok(mut state) = self.state.lock()
+            && let Some(unlocked) = state.as_mut() {
+            unlocked.last_used = Instant::now();
+        }
+    }
+
+    //Runs f on the decrypted entries, writing them back if f returns true
+    fn with_entries<T>(&self, f: impl FnOnce(&mut Entries) -> Result<(T, bool), BoxError>) -> Result<T, BoxError> {
+        let mut state = self.state.lock().map_err(|_| "Credential vault lock poisoned")?;
+        let unlocked = match state.as_mut() {
+            Some(unlocked) if !self.is_idle(unlocked) => unlocked,
+            _ => {
+                *state = None;
+                return Err(Box::from("Credential vault is locked, unlock it with its passphrase"));
+            }
+        };
+        unlocked.last_used = Instant::now();
+
+        let mut entries: Entries = serde_json::from_slice(&open_vault(&unlocked.key, &std::fs::read(&self.path)?)?)?;
+        let (result, changed) = f(&mut entries)?;
+        if changed {
+            self.write(unlocked, &entries)?;
+        }
+        Ok(result)
+    }
+
+    fn write(&self, unlocked: &UnlockedVault, entries: &Entries) -> Result<(), BoxError> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut header = unlocked.prefix.clone();
+        header.extend_from_slice(&nonce);
+        let plaintext = Zeroizing::new(serde_json::to_vec(entries)?);
+        let ciphertext = XChaCha20Poly1305::new(unlocked.key.as_ref().into())
+            .encrypt(&nonce, Payload { msg: &plaintext, aad: &header })
+            .map_err(|_| "Failed to encrypt credential vault")?;
+        header.extend_from_slice(&ciphertext);
+        paths::write_private(&self.path, &header)
+    }
+}
+
+impl CredentialStore for VaultStore {
+    fn set(&self, service: &str, username: &str, secret: &str) -> Result<(), BoxError> {
+        self.with_entries(|entries| {
+            entries.insert(EncryptedFileStore::entry_key(service, username), Zeroizing::new(secret.to_string()));
+            Ok(((), true))
+        })
+    }
+
+    fn get(&self, service: &str, username: &str) -> Result<Zeroizing<String>, BoxError> {
+        self.with_entries(|entries| {
+            let secret = entries.remove(&EncryptedFileStore::entry_key(service, username))
+                .ok_or_else(|| not_found(service, username))?;
+            Ok((secret, false))
+        })
+    }
+
+    fn delete(&self, service: &str, username: &str) -> Result<(), BoxError> {
+        self.with_entries(|entries| {
+            entries.remove(&EncryptedFileStore::entry_key(service, username))
+                .ok_or_else(|| not_found(service, username))?;
+            Ok(((), true))
+        })
+    }
+
+    fn vault(&self) -> Option<&VaultStore> {
+        Some(self)
+    }
+}
+
+//Checks the header and returns it (up to the nonce) with the KDF parameters and salt it names
+fn parse_vault_prefix(data: &[u8]) -> Result<(Vec<u8>, Params, Vec<u8>), BoxError> {
+    if data.len() < VAULT_PREFIX_LEN + NONCE_LEN || &data[..VAULT_MAGIC.len()] != VAULT_MAGIC {
+        return Err(Box::from("Not a credential vault"));
+    }
+    let version = data[VAULT_MAGIC.len()];
+    if version != VAULT_VERSION {
+        return Err(Box::from(format!("Credential vault version {} is not supported", version)));
+    }
+    let cost = |at: usize| {
+        let start = VAULT_MAGIC.len() + 1 + at * 4;
+        u32::from_le_bytes(data[start..start + 4].try_into().expect("slice is 4 bytes"))
+    };
+    let params = Params::new(cost(0), cost(1), cost(2), Some(32)).map_err(|e| format!("Invalid vault parameters: {}", e))?;
+    let salt = data[VAULT_PREFIX_LEN - SALT_LEN..VAULT_PREFIX_LEN].to_vec();
+    Ok((data[..VAULT_PREFIX_LEN].to_vec(), params, salt))
+}
+
+fn open_vault(key: &[u8; 32], data: &[u8]) -> Result<Zeroizing<Vec<u8>>, BoxError> {
+    parse_vault_prefix(data)?;
+    let (header, ciphertext) = data.split_at(VAULT_PREFIX_LEN + NONCE_LEN);
+    let plaintext = XChaCha20Poly1305::new(key.into())
+        .decrypt(XNonce::from_slice(&header[VAULT_PREFIX_LEN..]), Payload { msg: ciphertext, aad: header })
+        .map_err(|_| "Failed to decrypt credential vault")?;
+    Ok(Zeroizing::new(plaintext))
+}
+
+/*
+Keeps a vault unlocked until dropped, however long it goes unused. Held for the length of
+a credential operation that reads and writes several entries, such as a token refresh, so
+the vault can't lock half way through; never for longer.
+*/
+pub struct VaultHold {
+    store: Arc<dyn CredentialStore>,
+}
+
+impl Drop for VaultHold {
+    fn drop(&mut self) {
+        if let Some(vault) = self.store.vault() {
+            vault.release();
+        }
+    }
+}
+
+//Holds store open if it is a vault, other stores don't lock
+pub fn hold(store: Arc<dyn CredentialStore>) -> Option<VaultHold> {
+    store.vault()?.holds.fetch_add(1, Ordering::SeqCst);
+    Some(VaultHold { store })
+}
@@ -1,42 +1,44 @@
 /**
  * The end-to-end encryption process requires a device table that connects device to users
+ *
+ * The schema is versioned through sqlite's user_version pragma. MIGRATIONS[n] upgrades a
+ * database from version n to n + 1, so opening a database runs whatever it is missing.
+ * Never edit a migration that has shipped, append a new one instead.
  */
 use tokio_rusqlite::Connection;
 
-pub async fn initialize_db(user_id: &str) -> Result<Connection, Box<dyn std::error::Error + Send + Sync>> {
-    let db_name = format!("{}.database", user_id);
+use crate::paths;
+use crate::encryption::{self, FieldCipher};
+use crate::repository;
 
-    let conn = Connection::open(db_name).await?;
-    let users =  
-    "CREATE TABLE users (
+const MIGRATIONS: &[&str] = &[
+    // v1: initial schema. IF NOT EXISTS lets databases created before migrations
+    // existed (user_version 0, same tables) be adopted as-is.
+    "CREATE TABLE IF NOT EXISTS users (
         user_id TEXT PRIMARY KEY,
         email TEXT NOT NULL,
         created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-    );";
-    let devices = 
-    "CREATE TABLE devices (
+    );
+    CREATE TABLE IF NOT EXISTS devices (
         device_id INTEGER PRIMARY KEY,
         user_id TEXT NOT NULL UNIQUE,
         shared_key TEXT,
         msg_sequence_num INTEGER NOT NULL,
         FOREIGN KEY (user_id) REFERENCES users(user_id)
-    );";
-    let conversations = 
-    "CREATE TABLE conversations (
+    );
+    CREATE TABLE IF NOT EXISTS conversations (
         conversation_id INTEGER PRIMARY KEY,
         created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
         last_active TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-    );";
-    let user_conversations = 
-    "CREATE TABLE user_conversations (
+    );
+    CREATE TABLE IF NOT EXISTS user_conversations (
         user_id TEXT NOT NULL,
         conversation_id INTEGER NOT NULL,
         PRIMARY KEY (user_id, conversation_id),
         FOREIGN KEY (user_id) REFERENCES users(user_id),
         FOREIGN KEY (conversation_id) REFERENCES conversations(conversation_id)
-    );";
-    let messages = 
-    "CREATE TABLE messages (
+    );
+    CREATE TABLE IF NOT EXISTS messages (
         message_id INTEGER PRIMARY KEY,
         conversation_id INTEGER NOT NULL,
         sender_id TEXT NOT NULL,
@@ -45,23 +47,140 @@ pub async fn initialize_db(user_id: &str) -> Result<Connection, Box<dyn std::err
         received_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
         FOREIGN KEY (conversation_id) REFERENCES conversations(conversation_id),
         FOREIGN KEY (sender_id) REFERENCES users(user_id)
-    );";
+    );",
+    // v2: a user can have many devices. Drops the UNIQUE on devices.user_id and adds
+    // per-device identity key, verification, last-seen and revocation state.
+    "CREATE TABLE devices_v2 (
+        device_id INTEGER PRIMARY KEY,
+        user_id TEXT NOT NULL,
+        identity_key TEXT,
+        shared_key TEXT,
+        msg_sequence_num INTEGER NOT NULL DEFAULT 0,
+        verified INTEGER NOT NULL DEFAULT 0,
+        last_seen TIMESTAMP,
+        revoked INTEGER NOT NULL DEFAULT 0,
+        FOREIGN KEY (user_id) REFERENCES users(user_id)
+    );
+    INSERT INTO devices_v2 (device_id, user_id, shared_key, msg_sequence_num)
+        SELECT device_id, user_id, shared_key, msg_sequence_num FROM devices;
+    DROP TABLE devices;
+    ALTER TABLE devices_v2 RENAME TO devices;
+    CREATE INDEX idx_devices_user_id ON devices(user_id);",
+    // v3: read state for unread counts, plus indexes for listing conversations by
+    // activity and paging through a conversation's messages by id.
+    "ALTER TABLE conversations ADD COLUMN last_read_message_id INTEGER NOT NULL DEFAULT 0;
+    CREATE INDEX idx_conversations_last_active ON conversations(last_active);
+    CREATE INDEX idx_messages_conversation_id ON messages(conversation_id, message_id);
+    CREATE INDEX idx_user_conversations_conversation_id ON user_conversations(conversation_id);",
+    // v4: full-text index over message content. Contentless, and the tokens are keyed hashes
+    // of words (see search.rs), so the index holds no readable copy of the message. Content is
+    // encrypted, so existing rows are queued in messages_fts_pending and indexed from Rust once
+    // the key is loaded.
+    "CREATE VIRTUAL TABLE messages_fts USING fts5(content, content='', contentless_delete=1);
+    CREATE TABLE messages_fts_pending (message_id INTEGER PRIMARY KEY);
+    INSERT INTO messages_fts_pending (message_id) SELECT message_id FROM messages;",
+    // v5: disappearing messages. expiry_seconds is the conversation's timer (NULL = off),
+    // expires_at the unix time after which a message is deleted (NULL = kept).
+    "ALTER TABLE conversations ADD COLUMN expiry_seconds INTEGER;
+    ALTER TABLE messages ADD COLUMN expires_at INTEGER;
+    CREATE INDEX idx_messages_expires_at ON messages(expires_at) WHERE expires_at IS NOT NULL;",
+    // v6: per-conversation retention overrides. NULL follows the global policy in
+    // config.json, 0 keeps everything for that conversation.
+    "ALTER TABLE conversations ADD COLUMN retention_days INTEGER;
+    ALTER TABLE conversations ADD COLUMN retention_messages INTEGER;",
+    // v7: history transfers between the user's devices. Kept in the database so an
+    // interrupted transfer can resume. secret_key is the ephemeral x448 secret,
+    // encrypted like message content; chunks hold the ciphertext as received.
+    "CREATE TABLE history_transfers (
+        transfer_id TEXT PRIMARY KEY,
+        role TEXT NOT NULL,
+        peer_device TEXT NOT NULL,
+        secret_key TEXT NOT NULL,
+        peer_commitment TEXT,
+        peer_public_key TEXT,
+        snapshot_at INTEGER,
+        digest TEXT,
+        total_chunks INTEGER,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE history_transfer_chunks (
+        transfer_id TEXT NOT NULL,
+        chunk_index INTEGER NOT NULL,
+        data TEXT NOT NULL,
+        PRIMARY KEY (transfer_id, chunk_index),
+        FOREIGN KEY (transfer_id) REFERENCES history_transfers(transfer_id)
+    );",
+    // v8: devices.shared_key held secrets in plaintext. Secrets shared with a peer device are
+    // kept in the credential store instead (manage_keys::KeyKind::RatchetRoot), so the column
+    // is dropped; secure_delete overwrites the values it held.
+    "ALTER TABLE devices DROP COLUMN shared_key;",
+];
 
-    conn.call(move |call| {
-        //Remove newlines from query so that sqlite accepts them
-        call.execute(users,  []).map_err(tokio_rusqlite::Error::from)?;
-        call.execute(devices,  []).map_err(tokio_rusqlite::Error::from)?;
-        call.execute(conversations,  []).map_err(tokio_rusqlite::Error::from)?;
-        call.execute(user_conversations,  []).map_err(tokio_rusqlite::Error::from)?;
-        call.execute(messages,  []).map_err(tokio_rusqlite::Error::from)?;
+//user_version of a database with every migration applied
+pub const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64;
 
-        Ok(())
-    }).await?;
-    Ok(conn)
+pub async fn initialize_db(user_id: &str) -> Result<Connection, Box<dyn std::error::Error + Send + Sync>> {
+    connect(user_id).await
 }
 
+/*
+Opens {user}.database, creating it if needed, upgrades it to the current schema,
+encrypts any message content left in plaintext by older versions and indexes
+messages the search index hasn't seen yet.
+*/
 pub async fn connect(user_id: &str) -> Result<Connection, Box<dyn std::error::Error + Send + Sync>> {
-    let db_name = format!("{}.database", user_id);
-    let conn = Connection::open(db_name).await?;
-    Ok(conn)
-}
\ No newline at end of file
+    Ok(open(user_id).await?.0)
+}
+
+//Like connect, but also returns the cipher for the database's encrypted fields
+pub async fn open(user_id: &str) -> Result<(Connection, FieldCipher), Box<dyn std::error::Error + Send + Sync>> {
+    let db_path = paths::user_db_path(user_id).await?;
+    let conn = Connection::open(&db_path).await?;
+    paths::restrict_file(&db_path).await?;
+    //overwrite deleted content instead of leaving it in free pages
+    conn.call(|call| {
+        call.pragma_update(None, "secure_delete", true)?;
+        Ok::<(), tokio_rusqlite::Error>(())
+    }).await?;
+    migrate(&conn).await?;
+
+    let cipher = encryption::load_cipher(user_id).await?;
+    encryption::encrypt_existing(&conn, &cipher).await?;
+    repository::index_pending_messages(&conn, &cipher).await?;
+    Ok((conn, cipher))
+}
+
+/*
+Applies every migration the database is missing in a single transaction, so a
+failure part way leaves the file at its previous version. Returns the new version.
+*/
+pub async fn migrate(conn: &Connection) -> Result<i64, Box<dyn std::error::Error + Send + Sync>> {
+    let version = schema_version(conn).await?;
+    if version > SCHEMA_VERSION {
+        return Err(Box::from(format!(
+            "Database schema version {} is newer than this client supports ({})", version, SCHEMA_VERSION
+        )));
+    }
+    if version == SCHEMA_VERSION {
+        return Ok(version);
+    }
+
+    conn.call(move |call| {
+        let tx = call.transaction()?;
+        for migration in &MIGRATIONS[version as usize..] {
+            tx.execute_batch(migration)?;
+        }
+        tx.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        tx.commit()?;
+        Ok::<(), tokio_rusqlite::Error>(())
+    }).await?;
+    Ok(SCHEMA_VERSION)
+}
+
+pub async fn schema_version(conn: &Connection) -> Result<i64, Box<dyn std::error::Error + Send + Sync>> {
+    let version = conn.call(|call| {
+        let version: i64 = call.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        Ok::<i64, tokio_rusqlite::Error>(version)
+    }).await?;
+    Ok(version)
+}
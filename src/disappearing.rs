@@ -0,0 +1,81 @@
+/**
+ * Disappearing messages.
+ * A conversation can carry a timer (conversations.expiry_seconds). Whoever changes it sends an
+ * "expiry" control message and the other participant adopts it. Outgoing messages also carry
+ * "expires_in", so the recipient deletes them even if it missed the control message, and each
+ * stored message gets an expires_at that the sweeper task enforces.
+ */
+use std::time::Duration;
+use tokio_rusqlite::Connection;
+
+use crate::clock::unix_now;
+use crate::history;
+use crate::messages;
+use crate::repository;
+use crate::session_manager::{SessionContext, SessionEvent};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+//upper bound on how long the sweeper sleeps, so timers set mid-sleep are picked up
+const SWEEP_INTERVAL_SECS: i64 = 30;
+
+/*
+When a message in the conversation should disappear. The stricter of the timer the message
+arrived with and the conversation's own timer wins. None if neither is set.
+*/
+pub async fn expires_at(conn: &Connection, conversation_id: i64, expires_in: Option<i64>) -> Result<Option<i64>, BoxError> {
+    let conversation_timer = repository::get_conversation(conn, conversation_id).await?
+        .and_then(|c| c.expiry_seconds);
+    //a zero or negative timer from the peer can't switch off ours
+    let timer = match (expires_in.filter(|secs| *secs > 0), conversation_timer) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+    Ok(timer.map(|secs| unix_now() + secs))
+}
+
+//The disappearing timer of the conversation with peer (a user or device id)
+pub async fn timer_for(conn: &Connection, peer: &str) -> Result<Option<i64>, BoxError> {
+    let peer_id = history::resolve_user(conn, peer).await?;
+    repository::ensure_user(conn, &peer_id).await?;
+    let conversation_id = repository::find_or_create_direct_conversation(conn, &peer_id).await?;
+    Ok(repository::get_conversation(conn, conversation_id).await?.and_then(|c| c.expiry_seconds))
+}
+
+/*
+Sets the timer for the conversation with peer (None or 0 turns it off) and returns
+the control message that tells the peer. Messages already stored keep their expiry.
+*/
+pub async fn set_timer(conn: &Connection, username: &str, peer: &str, expiry_seconds: Option<i64>) -> Result<serde_json::Value, BoxError> {
+    let expiry_seconds = apply_timer(conn, peer, expiry_seconds).await?;
+    messages::expiry_control(username, peer, expiry_seconds).await
+}
+
+//Adopts a timer announced by peer in an "expiry" control message. Returns the timer now in effect
+pub async fn apply_timer(conn: &Connection, peer: &str, expiry_seconds: Option<i64>) -> Result<Option<i64>, BoxError> {
+    let expiry_seconds = expiry_seconds.filter(|secs| *secs > 0);
+    let peer_id = history::resolve_user(conn, peer).await?;
+    repository::ensure_user(conn, &peer_id).await?;
+    let conversation_id = repository::find_or_create_direct_conversation(conn, &peer_id).await?;
+    repository::set_conversation_expiry(conn, conversation_id, expiry_seconds).await?;
+    Ok(expiry_seconds)
+}
+
+//Deletes every expired message. Returns how many were removed
+pub async fn sweep(conn: &Connection) -> Result<usize, BoxError> {
+    repository::delete_expired_messages(conn, unix_now()).await
+}
+
+//Runs for the lifetime of the session, waking when the next message is due to expire
+pub async fn sweeper_task(ctx: SessionContext) -> Result<(), BoxError> {
+    loop {
+        if let Err(e) = sweep(&ctx.conn).await {
+            ctx.notify(SessionEvent::Error(format!("Failed to delete expired messages: {}", e)));
+        }
+        let wait = match repository::next_expiry(&ctx.conn).await {
+            Ok(Some(next)) => (next - unix_now()).clamp(1, SWEEP_INTERVAL_SECS),
+            _ => SWEEP_INTERVAL_SECS,
+        };
+        tokio::time::sleep(Duration::from_secs(wait as u64)).await;
+    }
+}
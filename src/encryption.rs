@@ -0,0 +1,152 @@
+/**
+ * Field-level encryption for sensitive columns in {user}.database.
+ * Values are stored as "enc1:" + base64(nonce || ciphertext) using XChaCha20-Poly1305
+ * with a per-account key kept in the credential store (see manage_keys::get_db_key).
+ */
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use tokio_rusqlite::{params, Connection};
+use tokio_rusqlite::rusqlite;
+use argon2::{Algorithm, Argon2, Params, Version};
+use data_encoding::HEXLOWER;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+use crate::credential_store;
+use crate::manage_keys;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+const PREFIX: &str = "enc1:";
+const NONCE_LEN: usize = 24;
+//binds ciphertexts to the column they were written for
+const CONTENT_AAD: &[u8] = b"e_to_e_msgr:messages.content";
+//keeps index hashes apart from any other use of the key
+const INDEX_LABEL: &[u8] = b"e_to_e_msgr:search_index:";
+//bytes of the HMAC kept per index term, 128 bits keeps collisions between terms out of reach
+const INDEX_TOKEN_LEN: usize = 16;
+
+#[derive(Clone)]
+pub struct FieldCipher {
+    cipher: XChaCha20Poly1305,
+    //keyed with the same key, for search index terms
+    index_mac: Hmac<Sha256>,
+}
+
+impl FieldCipher {
+    pub fn new(key: &[u8; 32]) -> Self {
+        FieldCipher {
+            cipher: XChaCha20Poly1305::new(key.into()),
+            index_mac: <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length"),
+        }
+    }
+
+    //Keyed hash of a search term: equal terms give equal hashes, but without the key they can't be reversed or guessed
+    pub fn index_token(&self, term: &str) -> String {
+        let mut mac = self.index_mac.clone();
+        mac.update(INDEX_LABEL);
+        mac.update(term.as_bytes());
+        HEXLOWER.encode(&mac.finalize().into_bytes()[..INDEX_TOKEN_LEN])
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> Result<String, BoxError> {
+        self.encrypt_for(CONTENT_AAD, plaintext)
+    }
+
+    pub fn decrypt(&self, stored: &str) -> Result<String, BoxError> {
+        self.decrypt_for(CONTENT_AAD, stored)
+    }
+
+    //Encrypts a value for a column other than messages.content, aad names that column
+    pub fn encrypt_for(&self, aad: &[u8], plaintext: &str) -> Result<String, BoxError> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.cipher
+            .encrypt(&nonce, Payload { msg: plaintext.as_bytes(), aad })
+            .map_err(|_| "Failed to encrypt field")?;
+
+        let mut blob = nonce.to_vec();
+        blob.extend_from_slice(&ciphertext);
+        Ok(format!("{}{}", PREFIX, STANDARD.encode(blob)))
+    }
+
+    pub fn decrypt_for(&self, aad: &[u8], stored: &str) -> Result<String, BoxError> {
+        let encoded = stored.strip_prefix(PREFIX).ok_or("Field is not encrypted")?;
+        let blob = STANDARD.decode(encoded)?;
+        if blob.len() < NONCE_LEN {
+            return Err(Box::from("Encrypted field is truncated"));
+        }
+        let (nonce, ciphertext) = blob.split_at(NONCE_LEN);
+        let plaintext = self.cipher
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+            .map_err(|_| "Failed to decrypt field, wrong key or corrupted data")?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+}
+
+//Derives a 32 byte key from a passphrase with Argon2id, wiped from memory when dropped
+pub fn derive_key(passphrase: &str, salt: &[u8], params: Params) -> Result<Zeroizing<[u8; 32]>, BoxError> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, key.as_mut())
+        .map_err(|e| format!("Failed to derive key: {}", e))?;
+    Ok(key)
+}
+
+pub fn is_encrypted(stored: &str) -> bool {
+    stored.starts_with(PREFIX)
+}
+
+/*
+Loads the account's database key from the credential store, generating one on first use.
+Only a key that doesn't exist is generated: if the store can't be read (locked vault,
+keyring failure) the error is returned, replacing the key would make every encrypted
+field unreadable.
+*/
+pub async fn load_cipher(username: &str) -> Result<FieldCipher, BoxError> {
+    let key = match manage_keys::get_db_key(username).await {
+        Ok(key) => key,
+        Err(e) if credential_store::is_not_found(&e) => {
+            let key: [u8; 32] = XChaCha20Poly1305::generate_key(&mut OsRng).into();
+            manage_keys::store_db_key(username, &key).await?;
+            key
+        }
+        Err(e) => return Err(e),
+    };
+    Ok(FieldCipher::new(&key))
+}
+
+/*
+Encrypts any messages.content still stored in plaintext (databases written before
+encryption at rest). When rows were rewritten the file is vacuumed so the old
+plaintext doesn't linger in free pages. Returns the number of rows encrypted.
+*/
+pub async fn encrypt_existing(conn: &Connection, cipher: &FieldCipher) -> Result<usize, BoxError> {
+    let plaintext_rows = conn.call(|call| {
+        let mut stmt = call.prepare("SELECT message_id, content FROM messages WHERE content NOT LIKE 'enc1:%'")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok::<_, rusqlite::Error>(rows)
+    }).await?;
+    if plaintext_rows.is_empty() {
+        return Ok(0);
+    }
+
+    let mut encrypted = Vec::with_capacity(plaintext_rows.len());
+    for (message_id, content) in plaintext_rows {
+        encrypted.push((message_id, cipher.encrypt(&content)?));
+    }
+    let count = encrypted.len();
+
+    conn.call(move |call| {
+        let tx = call.transaction()?;
+        for (message_id, content) in &encrypted {
+            tx.execute("UPDATE messages SET content = ?1 WHERE message_id = ?2", params![content, message_id])?;
+        }
+        tx.commit()?;
+        call.execute_batch("VACUUM;")?;
+        Ok::<(), rusqlite::Error>(())
+    }).await?;
+    Ok(count)
+}
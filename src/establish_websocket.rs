@@ -1,13 +1,36 @@
 use tokio_tungstenite::{connect_async, tungstenite::protocol::Message, WebSocketStream};
 use tungstenite::client::IntoClientRequest;
-use tungstenite::http::{header::HeaderValue, HeaderMap};
+use tungstenite::http::{header::HeaderValue, HeaderMap, StatusCode};
 use futures_util::{StreamExt};
 use futures_util::stream::{SplitSink, SplitStream};
 use tokio::net::TcpStream;
 use serde_json::json;
+use std::fmt;
 
 use crate::manage_keys;
-use crate::session_manager::process_message;
+use crate::to_server;
+use crate::session_manager::process_auth_message;
+
+/*
+Returned when the server rejects (or we no longer hold) the credentials for a user.
+Callers downcast to this to tell "log in again" apart from network failures.
+*/
+#[derive(Debug)]
+pub struct AuthFailed {
+    pub username: String,
+}
+
+impl fmt::Display for AuthFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Authentication failed for {}, please log in again", self.username)
+    }
+}
+
+impl std::error::Error for AuthFailed {}
+
+pub fn is_auth_failure(e: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+    e.downcast_ref::<AuthFailed>().is_some()
+}
 
 pub async fn establish_websocket(username: &str) -> Result<
     (
@@ -16,21 +39,34 @@ pub async fn establish_websocket(username: &str) -> Result<
     ),
     Box<dyn std::error::Error + Send + Sync>
 > {
-    let url = "ws://localhost:3000/ws";
+    let url = format!("ws://{}/ws", to_server::SERVER);
     let mut request = url.into_client_request()?;
 
+    let auth_failed = || AuthFailed { username: username.to_string() };
+    //a missing token means the session can only be recovered by logging in again
+    let token = manage_keys::get_token(username).await.map_err(|_| auth_failed())?;
+
     let headers = request.headers_mut();
     headers.insert("x-user-id", HeaderValue::from_str(&username)?);
-    headers.insert("x-auth-token", HeaderValue::from_str(&manage_keys::get_token(username).await?)?);
+    headers.insert("x-auth-token", HeaderValue::from_str(&token)?);
     headers.insert("x-device-id", HeaderValue::from_str(&manage_keys::get_device_id(username).await?)?);
     headers.insert("x-device-uuid", HeaderValue::from_str(&manage_keys::get_uuid(username).await?)?);
 
-    let (ws_stream, _) = connect_async(request).await.expect("Failed to connect to WebSocket");
+    let ws_stream = match connect_async(request).await {
+        Ok((ws_stream, _)) => ws_stream,
+        Err(tungstenite::Error::Http(resp))
+            if resp.status() == StatusCode::UNAUTHORIZED || resp.status() == StatusCode::FORBIDDEN => {
+            return Err(Box::new(auth_failed()));
+        }
+        Err(e) => return Err(Box::new(e)),
+    };
     let (send, mut recv) = ws_stream.split();
 
     // receive the message with the new token.
-    let initial_message = recv.next().await.unwrap()?.to_string();
-    process_message(&initial_message).await?;
+    let initial_message = recv.next().await
+        .ok_or("Connection closed before authentication completed")??
+        .to_string();
+    process_auth_message(&initial_message).await?;
 
     Ok((send, recv))
 }
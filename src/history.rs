@@ -0,0 +1,67 @@
+/**
+ * Message history: turns messages sent and received during a session into rows in
+ * conversations / user_conversations / messages so they survive restarts.
+ * One-to-one conversations are keyed by the peer; the local user is implicit and
+ * not listed as a participant.
+ */
+use tokio_rusqlite::Connection;
+
+use crate::encryption::FieldCipher;
+use crate::repository;
+use crate::disappearing;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/*
+The relay addresses messages by device id. Known devices are mapped to their owning
+user so every device of a contact shares one conversation; anything else is used as-is.
+*/
+pub async fn resolve_user(conn: &Connection, id: &str) -> Result<String, BoxError> {
+    if let Ok(device_id) = id.parse::<i64>()
+        && let Some(device) = repository::get_device(conn, device_id).await? {
+        return Ok(device.user_id);
+    }
+    Ok(id.to_string())
+}
+
+/*
+expires_in is the disappearing timer the message was sent with. Without one the
+conversation's own timer applies, so a peer that ignores timers can't make us keep messages.
+Returns (conversation_id, message_id)
+*/
+pub async fn store_incoming(conn: &Connection, cipher: &FieldCipher, sender: &str, content: &str, expires_in: Option<i64>) -> Result<(i64, i64), BoxError> {
+    let sender_id = resolve_user(conn, sender).await?;
+    repository::ensure_user(conn, &sender_id).await?;
+
+    let conversation_id = repository::find_or_create_direct_conversation(conn, &sender_id).await?;
+    let expires_at = disappearing::expires_at(conn, conversation_id, expires_in).await?;
+    let message_id = repository::insert_message(conn, cipher, conversation_id, &sender_id, content, expires_at).await?;
+    Ok((conversation_id, message_id))
+}
+
+//Returns (conversation_id, message_id)
+pub async fn store_outgoing(conn: &Connection, cipher: &FieldCipher, username: &str, recipient: &str, content: &str, expires_in: Option<i64>) -> Result<(i64, i64), BoxError> {
+    let recipient_id = resolve_user(conn, recipient).await?;
+    repository::ensure_user(conn, &recipient_id).await?;
+    //messages.sender_id references users, so the local user needs a row too
+    repository::ensure_user(conn, username).await?;
+
+    let conversation_id = repository::find_or_create_direct_conversation(conn, &recipient_id).await?;
+    let expires_at = disappearing::expires_at(conn, conversation_id, expires_in).await?;
+    let message_id = repository::insert_message(conn, cipher, conversation_id, username, content, expires_at).await?;
+    Ok((conversation_id, message_id))
+}
+
+//length of the last-message preview in conversation lists
+pub const PREVIEW_CHARS: usize = 60;
+
+//First line of a message, cut to PREVIEW_CHARS characters
+pub fn preview(content: &str) -> String {
+    let first_line = content.lines().next().unwrap_or("");
+    if first_line.chars().count() > PREVIEW_CHARS || first_line.len() < content.trim_end().len() {
+        let cut: String = first_line.chars().take(PREVIEW_CHARS).collect();
+        format!("{}...", cut.trim_end())
+    } else {
+        first_line.to_string()
+    }
+}
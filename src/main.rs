@@ -15,15 +15,94 @@ mod tests;
 mod messages;
 mod json_structures;
 mod db;
+mod accounts;
+mod config;
+mod paths;
+mod repository;
+mod encryption;
+mod history;
+mod search;
+mod clock;
+mod disappearing;
+mod retention;
+mod archive;
+mod transcript;
+mod transfer;
+mod credential_store;
+mod account_removal;
+mod recovery;
+mod totp;
+mod password_auth;
+mod prompt;
+mod tui;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn::std::error::Error + Send + Sync>> {
     // Initialize the CLI for authentication
     /*
-    let (tx, rx) = auth_cli::cli().await?;
-    session_manager::session(tx, rx).await?;
+    auth_cli::unlock_credentials().await?;
+    let (username, tx, rx) = auth_cli::cli().await?;
+    session_manager::session(&username, tx, rx, session_manager::Frontend::Shell).await?;
     */
-    tests::run_all_tests().await;
+    let args: Vec<String> = std::env::args().collect();
+    // Ask for the vault passphrase up front when credentials are kept in the vault.
+    // The tests run against an in-memory store instead
+    if args.len() > 1 {
+        auth_cli::unlock_credentials().await?;
+    }
+    match args.get(1).map(|a| a.as_str()) {
+        // e_to_e_msgr chat
+        Some("chat") => {
+            let (username, tx, rx) = auth_cli::cli().await?;
+            session_manager::session(&username, tx, rx, session_manager::Frontend::Shell).await?;
+        }
+        // e_to_e_msgr tui
+        Some("tui") => {
+            let (username, tx, rx) = auth_cli::cli().await?;
+            session_manager::session(&username, tx, rx, session_manager::Frontend::Tui).await?;
+        }
+        // e_to_e_msgr search <username> <words...>
+        Some("search") if args.len() >= 4 => {
+            session_cli::search_command(&args[2], &args[3..].join(" ")).await?;
+        }
+        // e_to_e_msgr retention <username> <peer> <days|default> <messages|default>
+        Some("retention") if args.len() == 6 => {
+            session_cli::retention_command(&args[2], &args[3], &args[4], &args[5]).await?;
+        }
+        // e_to_e_msgr export <username> <archive file>
+        Some("export") if args.len() == 4 => {
+            session_cli::export_command(&args[2], &args[3]).await?;
+        }
+        // e_to_e_msgr import <username> <archive file>
+        Some("import") if args.len() == 4 => {
+            session_cli::import_command(&args[2], &args[3]).await?;
+        }
+        // e_to_e_msgr transcript <username> <jsonl|csv|md> <file> [--conversation <id>] [--since <date>] [--until <date>]
+        Some("transcript") if args.len() >= 5 => {
+            session_cli::transcript_command(&args[2], &args[3], &args[4], &args[5..]).await?;
+        }
+        // e_to_e_msgr remove-account <username> [--delete-server]
+        Some("remove-account") if args.len() >= 3 => {
+            session_cli::remove_account_command(&args[2], &args[3..]).await?;
+        }
+        // e_to_e_msgr change-password <username>
+        Some("change-password") if args.len() == 3 => {
+            auth_cli::change_password_command(&args[2]).await?;
+        }
+        // e_to_e_msgr recovery-codes <username>
+        Some("recovery-codes") if args.len() == 3 => {
+            auth_cli::recovery_codes_command(&args[2]).await?;
+        }
+        // e_to_e_msgr upgrade-password <username>
+        Some("upgrade-password") if args.len() == 3 => {
+            auth_cli::upgrade_password_command(&args[2]).await?;
+        }
+        // e_to_e_msgr two-factor <username> enable|disable
+        Some("two-factor") if args.len() == 4 => {
+            auth_cli::two_factor_command(&args[2], &args[3]).await?;
+        }
+        _ => tests::run_all_tests().await,
+    }
 
 
     Ok(())
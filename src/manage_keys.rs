@@ -1,6 +1,11 @@
 use uuid::Uuid;
-use keyring::Entry;
 use std::error::Error;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+use zeroize::Zeroizing;
+
+use crate::credential_store::{self, CredentialStore};
+use crate::clock::unix_now;
 
 // Define a type alias for Box<dyn Error + Send + Sync>
 type BoxError = Box<dyn Error + Send + Sync>;
@@ -12,62 +17,257 @@ pub async fn generate_uuid(username: &str) -> Result<String, BoxError> {
 }
 
 pub async fn store_uuid(username: &str, device_key: &str) -> Result<(), BoxError> {
-    let keyring = Entry::new("e_to_e_msgr_uuid", username)?;
-    keyring.set_password(device_key)?;
+    credential_store::current().await?.set("e_to_e_msgr_uuid", username, device_key)?;
 
     Ok(())
 }
 
 pub async fn get_uuid(username: &str) -> Result<String, BoxError> {
-    let keyring = Entry::new("e_to_e_msgr_uuid", username)?;
-
-    match keyring.get_password() {
-        Ok(device_key) => Ok(device_key),
-        Err(e) => Err(Box::new(e)),
-    }
+    Ok(credential_store::current().await?.get("e_to_e_msgr_uuid", username)?.as_str().to_owned())
 }
 
 pub async fn store_token(token: &str, username: &str) -> Result<(), BoxError> {
-    let keyring = Entry::new("e_to_e_msgr_token", username)?;
+    credential_store::current().await?.set("e_to_e_msgr_token", username, token)?;
 
-    match keyring.set_password(token) {
-        Ok(()) => Ok(()),
-        Err(e) => Err(Box::new(e)),
-    }
+    Ok(())
 }
 
+//a copy the caller owns, it goes into request bodies and headers that aren't wiped either
 pub async fn get_token(username: &str) -> Result<String, BoxError> {
-    let keyring = Entry::new("e_to_e_msgr_token", username)?;
+    Ok(credential_store::current().await?.get("e_to_e_msgr_token", username)?.as_str().to_owned())
+}
 
-    match keyring.get_password() {
-        Ok(token) => Ok(token),
-        Err(e) => Err(Box::new(e)),
-    }
+pub async fn store_token_expiry(username: &str, expires_at: i64) -> Result<(), BoxError> {
+    credential_store::current().await?.set("e_to_e_msgr_token_expiry", username, &expires_at.to_string())?;
+
+    Ok(())
 }
 
+//unix timestamp (seconds) after which the stored token is no longer accepted
+pub async fn get_token_expiry(username: &str) -> Result<i64, BoxError> {
+    let expires_at = credential_store::current().await?.get("e_to_e_msgr_token_expiry", username)?;
+    Ok(expires_at.parse::<i64>()?)
+}
+//key used to encrypt sensitive fields in the local database, stored base64 encoded
+pub async fn store_db_key(username: &str, key: &[u8; 32]) -> Result<(), BoxError> {
+    credential_store::current().await?.set("e_to_e_msgr_db_key", username, &STANDARD.encode(key))?;
 
-pub async fn delete_credential(username: &str, cred_type: &str) -> Result<(), BoxError> {
-    let keyring = Entry::new(cred_type, username)?;
+    Ok(())
+}
 
-    match keyring.delete_credential() {
-        Ok(()) => Ok(()),
-        Err(e) => Err(Box::new(e)),
-    }
+pub async fn get_db_key(username: &str) -> Result<[u8; 32], BoxError> {
+    let encoded = credential_store::current().await?.get("e_to_e_msgr_db_key", username)?;
+    let key: [u8; 32] = STANDARD.decode(encoded.as_str())?
+        .try_into()
+        .map_err(|_| "Stored database key has the wrong length")?;
+    Ok(key)
+}
+
+pub async fn delete_credential(username: &str, cred_type: &str) -> Result<(), BoxError> {
+    credential_store::current().await?.delete(cred_type, username)
 }
 
 
 pub async fn store_device_id(username: &str, device_id: &str) -> Result<(), BoxError> {
-    let keyring = Entry::new("e_to_e_msgr_device_id", username)?;
-    keyring.set_password(device_id)?;
+    credential_store::current().await?.set("e_to_e_msgr_device_id", username, device_id)?;
 
     Ok(())
 }
 
 pub async fn get_device_id(username: &str) -> Result<String, BoxError> {
-    let keyring = Entry::new("e_to_e_msgr_device_id", username)?;
+    Ok(credential_store::current().await?.get("e_to_e_msgr_device_id", username)?.as_str().to_owned())
+}
+
+/*
+PRIVATE KEY MATERIAL
+Private keys never go into {user}.database; they are kept in the credential store, one entry
+for the current key and one for the key it replaced (kept so messages encrypted to it while a
+rotation propagates can still be read). Copies held by this module are wiped when dropped, and
+Debug never prints key bytes. What the store backend does with the value is up to the backend.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyKind {
+    //long-term identity key of this device
+    Identity,
+    //medium-term prekey other devices use to start sessions
+    SignedPrekey,
+    //root secret of the ratchet session with a peer device
+    RatchetRoot { peer: String },
+}
+
+impl KeyKind {
+    fn service(&self) -> &'static str {
+        match self {
+            KeyKind::Identity => "e_to_e_msgr_identity_key",
+            KeyKind::SignedPrekey => "e_to_e_msgr_signed_prekey",
+            KeyKind::RatchetRoot { .. } => "e_to_e_msgr_ratchet_root",
+        }
+    }
+
+    //ratchet roots are per peer, so the peer is part of the entry name
+    fn account(&self, username: &str) -> String {
+        match self {
+            KeyKind::RatchetRoot { peer } => format!("{}/{}", username, peer),
+            _ => username.to_string(),
+        }
+    }
+}
+
+pub struct PrivateKeyMaterial {
+    //increases by one with every rotation
+    pub key_id: u32,
+    pub created_at: i64,
+    bytes: Zeroizing<Vec<u8>>,
+}
+
+impl PrivateKeyMaterial {
+    pub fn new(key_id: u32, bytes: Vec<u8>) -> Self {
+        PrivateKeyMaterial { key_id, created_at: unix_now(), bytes: Zeroizing::new(bytes) }
+    }
+
+    //len random bytes, for keys that are plain random secrets
+    pub fn generate(key_id: u32, len: usize) -> Self {
+        let mut bytes = vec![0u8; len];
+        rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, &mut bytes);
+        PrivateKeyMaterial::new(key_id, bytes)
+    }
 
-    match keyring.get_password() {
-        Ok(device_id) => Ok(device_id),
-        Err(e) => Err(Box::new(e)),
+    pub fn expose(&self) -> &[u8] {
+        &self.bytes
     }
-}
\ No newline at end of file
+
+    //"v1:<key_id>:<created_at>:<base64 key>", wiped once written to the store
+    fn encode(&self) -> Zeroizing<String> {
+        use std::fmt::Write;
+        let encoded = Zeroizing::new(STANDARD.encode(self.bytes.as_slice()));
+        //sized up front so the string never reallocates and leaves a copy behind
+        let mut stored = Zeroizing::new(String::with_capacity(48 + encoded.len()));
+        let _ = write!(stored, "v1:{}:{}:{}", self.key_id, self.created_at, encoded.as_str());
+        stored
+    }
+
+    fn decode(stored: &str) -> Result<Self, BoxError> {
+        let mut parts = stored.splitn(4, ':');
+        let (Some("v1"), Some(key_id), Some(created_at), Some(key)) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+            //the stored value is never echoed back, it holds the key
+            return Err(Box::from("Stored private key is malformed"));
+        };
+        Ok(PrivateKeyMaterial {
+            key_id: key_id.parse().map_err(|_| "Stored private key is malformed")?,
+            created_at: created_at.parse().map_err(|_| "Stored private key is malformed")?,
+            bytes: Zeroizing::new(STANDARD.decode(key).map_err(|_| "Stored private key is malformed")?),
+        })
+    }
+}
+
+impl std::fmt::Debug for PrivateKeyMaterial {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PrivateKeyMaterial")
+            .field("key_id", &self.key_id)
+            .field("created_at", &self.created_at)
+            .field("bytes", &"[redacted]")
+            .finish()
+    }
+}
+
+const PREVIOUS_SUFFIX: &str = "_previous";
+
+//Stores key as the current key of its kind, replacing any current key without keeping it
+pub async fn store_private_key(username: &str, kind: &KeyKind, key: &PrivateKeyMaterial) -> Result<(), BoxError> {
+    credential_store::current().await?.set(kind.service(), &kind.account(username), &key.encode())
+}
+
+pub async fn get_private_key(username: &str, kind: &KeyKind) -> Result<PrivateKeyMaterial, BoxError> {
+    let stored = credential_store::current().await?.get(kind.service(), &kind.account(username))?;
+    PrivateKeyMaterial::decode(&stored)
+}
+
+//The key replaced by the last rotation, None if none is kept. A store that can't be read is an error
+pub async fn get_previous_private_key(username: &str, kind: &KeyKind) -> Result<Option<PrivateKeyMaterial>, BoxError> {
+    let service = format!("{}{}", kind.service(), PREVIOUS_SUFFIX);
+    match credential_store::current().await?.get(&service, &kind.account(username)) {
+        Ok(stored) => Ok(Some(PrivateKeyMaterial::decode(&stored)?)),
+        Err(e) if credential_store::is_not_found(&e) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/*
+Makes bytes the current key with the next key_id and keeps the old current key as the
+previous one, dropping whatever was previous before. Returns the new key.
+Fails without changing anything if the current key can't be read, rather than replacing
+it as if there were none.
+*/
+pub async fn rotate_private_key(username: &str, kind: &KeyKind, bytes: Vec<u8>) -> Result<PrivateKeyMaterial, BoxError> {
+    let store = credential_store::current().await?;
+    let account = kind.account(username);
+    let current = match store.get(kind.service(), &account) {
+        Ok(stored) => Some(PrivateKeyMaterial::decode(&stored)?),
+        Err(e) if credential_store::is_not_found(&e) => None,
+        Err(e) => return Err(e),
+    };
+
+    let next_id = current.as_ref().map_or(1, |k| k.key_id.wrapping_add(1));
+    let rotated = PrivateKeyMaterial::new(next_id, bytes);
+    if let Some(current) = &current {
+        store.set(&format!("{}{}", kind.service(), PREVIOUS_SUFFIX), &account, &current.encode())?;
+    }
+    store.set(kind.service(), &account, &rotated.encode())?;
+    Ok(rotated)
+}
+
+//Removes the current and previous key of the kind. Succeeds if there was nothing to remove
+pub async fn delete_private_key(username: &str, kind: &KeyKind) -> Result<(), BoxError> {
+    let store = credential_store::current().await?;
+    let account = kind.account(username);
+    delete_if_present(store.as_ref(), &format!("{}{}", kind.service(), PREVIOUS_SUFFIX), &account)?;
+    delete_if_present(store.as_ref(), kind.service(), &account)?;
+    Ok(())
+}
+
+//Deletes the entry, returning whether there was one. Only a missing entry is skipped, other failures are errors
+fn delete_if_present(store: &dyn CredentialStore, service: &str, account: &str) -> Result<bool, BoxError> {
+    match store.delete(service, account) {
+        Ok(()) => Ok(true),
+        Err(e) if credential_store::is_not_found(&e) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+//every per-account credential kept outside the private keys
+const ACCOUNT_SERVICES: [&str; 5] = [
+    "e_to_e_msgr_uuid",
+    "e_to_e_msgr_token",
+    "e_to_e_msgr_token_expiry",
+    "e_to_e_msgr_device_id",
+    "e_to_e_msgr_db_key",
+];
+
+/*
+Removes every credential of the account from the store: session and device credentials,
+the database key, and the current and previous private keys of every kind, with ratchet
+roots looked up for each of peers. Entries that don't exist are skipped.
+Returns how many entries were removed.
+*/
+pub async fn delete_all_credentials(username: &str, peers: &[String]) -> Result<usize, BoxError> {
+    let store = credential_store::current().await?;
+    let mut kinds = vec![KeyKind::Identity, KeyKind::SignedPrekey];
+    kinds.extend(peers.iter().map(|peer| KeyKind::RatchetRoot { peer: peer.clone() }));
+
+    let mut entries: Vec<(String, String)> = ACCOUNT_SERVICES.iter()
+        .map(|service| (service.to_string(), username.to_string()))
+        .collect();
+    for kind in &kinds {
+        let account = kind.account(username);
+        entries.push((format!("{}{}", kind.service(), PREVIOUS_SUFFIX), account.clone()));
+        entries.push((kind.service().to_string(), account));
+    }
+
+    let mut removed = 0;
+    for (service, account) in entries {
+        if delete_if_present(store.as_ref(), &service, &account)? {
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
@@ -19,4 +19,100 @@ pub async fn get_devices(username: &str) -> Result<Value, Box<dyn std::error::Er
     });
 
     Ok(payload)
-}
\ No newline at end of file
+}
+//Tells the recipient the disappearing message timer for the conversation, None turns it off
+pub async fn expiry_control(username: &str, recipient: &str, expiry_seconds: Option<i64>) -> Result<Value, Box<dyn std::error::Error + Send + Sync>> {
+    let payload = json!({
+        "type": "control",
+        "subtype": "expiry",
+        "sender": username,
+        "recipient": recipient,
+        "expiry_seconds": expiry_seconds
+    });
+
+    Ok(payload)
+}
+
+//Asks another of the user's devices for its history, see transfer::request_history
+pub async fn sync_request(device_id: &str, recipient: &str, transfer_id: &str, key_commitment: &str) -> Result<Value, Box<dyn std::error::Error + Send + Sync>> {
+    let payload = json!({
+        "type": "sync",
+        "action": "request",
+        "sender": device_id,
+        "recipient": recipient,
+        "transfer_id": transfer_id,
+        "key_commitment": key_commitment
+    });
+
+    Ok(payload)
+}
+
+pub async fn sync_reveal(device_id: &str, recipient: &str, transfer_id: &str, public_key: &str, resume_from: i64, digest: Option<&str>) -> Result<Value, Box<dyn std::error::Error + Send + Sync>> {
+    let payload = json!({
+        "type": "sync",
+        "action": "reveal",
+        "sender": device_id,
+        "recipient": recipient,
+        "transfer_id": transfer_id,
+        "public_key": public_key,
+        "resume_from": resume_from,
+        "digest": digest
+    });
+
+    Ok(payload)
+}
+
+pub async fn sync_key(device_id: &str, recipient: &str, transfer_id: &str, public_key: &str) -> Result<Value, Box<dyn std::error::Error + Send + Sync>> {
+    let payload = json!({
+        "type": "sync",
+        "action": "key",
+        "sender": device_id,
+        "recipient": recipient,
+        "transfer_id": transfer_id,
+        "public_key": public_key
+    });
+
+    Ok(payload)
+}
+
+pub async fn sync_offer(device_id: &str, recipient: &str, transfer_id: &str, public_key: &str, total_chunks: i64, digest: &str, restart: bool) -> Result<Value, Box<dyn std::error::Error + Send + Sync>> {
+    let payload = json!({
+        "type": "sync",
+        "action": "offer",
+        "sender": device_id,
+        "recipient": recipient,
+        "transfer_id": transfer_id,
+        "public_key": public_key,
+        "total_chunks": total_chunks,
+        "digest": digest,
+        "restart": restart
+    });
+
+    Ok(payload)
+}
+
+pub async fn sync_chunk(device_id: &str, recipient: &str, transfer_id: &str, index: i64, data: &str) -> Result<Value, Box<dyn std::error::Error + Send + Sync>> {
+    let payload = json!({
+        "type": "sync",
+        "action": "chunk",
+        "sender": device_id,
+        "recipient": recipient,
+        "transfer_id": transfer_id,
+        "index": index,
+        "data": data
+    });
+
+    Ok(payload)
+}
+
+pub async fn sync_complete(device_id: &str, recipient: &str, transfer_id: &str) -> Result<Value, Box<dyn std::error::Error + Send + Sync>> {
+    let payload = json!({
+        "type": "sync",
+        "action": "complete",
+        "sender": device_id,
+        "recipient": recipient,
+        "transfer_id": transfer_id
+    });
+
+    Ok(payload)
+}
@@ -0,0 +1,117 @@
+/**
+ * Client-side password hashing. The password never leaves the device: Argon2id stretches
+ * it into a master key, and two keys are split off that:
+ *   - the auth key, sent base64 encoded wherever the server used to get the password.
+ *     The server hashes it again before storing it, like any password.
+ *   - the local key, which never leaves the device. It wraps a copy of the database key
+ *     in the account directory, so logging in with the password can restore the key to
+ *     a credential store that lost it (new keyring, reinstalled OS) and old history
+ *     becomes readable again.
+ *
+ * Knowing the auth key doesn't help with the local key, both are one-way from the master.
+ *
+ * The salt is derived from the username so every device derives the same keys without
+ * asking the server first. The Argon2 parameters are fixed by AUTH_VERSION: the server
+ * stores verifiers, not passwords, so changing them means a new version that old
+ * verifiers stay valid under.
+ */
+use argon2::Params;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use zeroize::Zeroizing;
+
+use crate::credential_store;
+use crate::encryption::{self, FieldCipher};
+use crate::manage_keys;
+use crate::paths;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+//sent along with verifiers so the server can tell them from legacy plaintext passwords
+pub const AUTH_VERSION: u32 = 1;
+//Argon2id with the argon2 crate defaults of version 1 (19 MiB, 2 passes, 1 lane), pinned
+const M_COST: u32 = 19 * 1024;
+const T_COST: u32 = 2;
+const P_COST: u32 = 1;
+const WRAPPED_KEY_FILE: &str = "db_key.wrapped";
+
+pub struct PasswordKeys {
+    auth: Zeroizing<[u8; 32]>,
+    local: Zeroizing<[u8; 32]>,
+}
+
+impl PasswordKeys {
+    pub fn derive(username: &str, password: &str) -> Result<Self, BoxError> {
+        let salt = Sha256::new()
+            .chain_update(b"e_to_e_msgr:password_salt:")
+            .chain_update(username.as_bytes())
+            .finalize();
+        let params = Params::new(M_COST, T_COST, P_COST, Some(32)).map_err(|e| format!("Invalid password parameters: {}", e))?;
+        let master = encryption::derive_key(password, &salt, params)?;
+
+        let split = |label: &[u8]| {
+            let mut key = Zeroizing::new([0u8; 32]);
+            key.copy_from_slice(&Sha256::new().chain_update(label).chain_update(master.as_ref()).finalize());
+            key
+        };
+        Ok(PasswordKeys { auth: split(b"auth"), local: split(b"local") })
+    }
+
+    //What the server gets in place of the password
+    pub fn verifier(&self) -> String {
+        STANDARD.encode(self.auth.as_ref())
+    }
+
+    fn local_cipher(&self) -> FieldCipher {
+        FieldCipher::new(&self.local)
+    }
+}
+
+/*
+Makes the account's local state available after a password login. If the credential store
+has no database key but the account directory holds a wrapped copy, the copy is unwrapped
+into the store. Then the current key (generated if there is none) is wrapped again under
+these keys, which also moves the copy over to a new password.
+A copy that doesn't unwrap, e.g. one wrapped before the password was changed on another
+device, is left alone so the old password can still restore it. That isn't an error, the
+login goes on, but the returned warning says why the old history can't be read.
+*/
+pub async fn unlock_local_state(username: &str, keys: &PasswordKeys) -> Result<Option<String>, BoxError> {
+    let path = wrapped_key_path(username).await?;
+    let key_missing = matches!(manage_keys::get_db_key(username).await, Err(e) if credential_store::is_not_found(&e));
+    if key_missing
+        && let Ok(wrapped) = tokio::fs::read_to_string(&path).await {
+        match unwrap_db_key(username, keys, &wrapped) {
+            Ok(key) => manage_keys::store_db_key(username, &key).await?,
+            Err(e) => return Ok(Some(format!("Could not restore the database key of {}: {}", username, e))),
+        }
+    }
+
+    encryption::load_cipher(username).await?;
+    let key = Zeroizing::new(manage_keys::get_db_key(username).await?);
+    let wrapped = keys.local_cipher().encrypt_for(&wrap_aad(username), &STANDARD.encode(key.as_ref()))?;
+    paths::write_private(&path, wrapped.as_bytes())?;
+    Ok(None)
+}
+
+fn unwrap_db_key(username: &str, keys: &PasswordKeys, wrapped: &str) -> Result<Zeroizing<[u8; 32]>, BoxError> {
+    let encoded = Zeroizing::new(keys.local_cipher().decrypt_for(&wrap_aad(username), wrapped.trim())
+        .map_err(|_| "the wrapped key was made with a different password")?);
+    let mut key = Zeroizing::new([0u8; 32]);
+    let decoded = Zeroizing::new(STANDARD.decode(encoded.as_bytes())?);
+    if decoded.len() != key.len() {
+        return Err(Box::from("Wrapped database key has the wrong length"));
+    }
+    key.copy_from_slice(&decoded);
+    Ok(key)
+}
+
+//binds the wrapped key to its account, so copying the file between accounts doesn't work
+fn wrap_aad(username: &str) -> Vec<u8> {
+    format!("e_to_e_msgr:db_key:{}", username).into_bytes()
+}
+
+async fn wrapped_key_path(username: &str) -> Result<PathBuf, BoxError> {
+    Ok(paths::account_dir(username).await?.join(WRAPPED_KEY_FILE))
+}
@@ -0,0 +1,162 @@
+/**
+ * Locations of on-disk client state.
+ * Everything lives under one data directory with a subdirectory per account:
+ *   <data_dir>/accounts.database
+ *   <data_dir>/accounts/<user>/<user>.database
+ * Directories are created owner-only (0700) and files restricted to 0600 on unix.
+ */
+use std::env;
+use std::path::{Path, PathBuf};
+
+use crate::config;
+
+const APP_DIR: &str = "e_to_e_msgr";
+
+/*
+Resolution order: E_TO_E_MSGR_DATA_DIR, data_dir from config.json, then the platform default:
+$XDG_DATA_HOME/e_to_e_msgr (~/.local/share/e_to_e_msgr) on Linux, %LOCALAPPDATA%\e_to_e_msgr on Windows.
+*/
+pub async fn data_dir() -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    let dir = if let Some(dir) = env::var_os("E_TO_E_MSGR_DATA_DIR") {
+        PathBuf::from(dir)
+    } else if let Some(dir) = config::load().await?.data_dir {
+        dir
+    } else {
+        default_data_dir()?
+    };
+    create_private_dir(&dir).await?;
+    Ok(dir)
+}
+
+pub async fn account_dir(username: &str) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    let dir = account_dir_path(username).await?;
+    create_private_dir(dir.parent().ok_or("Account directory has no parent")?).await?;
+    create_private_dir(&dir).await?;
+    Ok(dir)
+}
+
+//Where account_dir is, without creating it
+async fn account_dir_path(username: &str) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    //usernames become path components, so anything that could escape the accounts directory is refused
+    if username.is_empty() || username == "." || username == ".." || username.contains(['/', '\\']) {
+        return Err(Box::from(format!("Invalid username for a data directory: {:?}", username)));
+    }
+    Ok(data_dir().await?.join("accounts").join(username))
+}
+
+/*
+Deletes the account directory (database, WAL and anything else kept per account) and a
+database left in the working directory by older versions. Returns whether anything was removed.
+*/
+pub async fn remove_account_data(username: &str) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let mut removed = false;
+    let dir = account_dir_path(username).await?;
+    if tokio::fs::metadata(&dir).await.is_ok() {
+        tokio::fs::remove_dir_all(&dir).await?;
+        removed = true;
+    }
+    let legacy = PathBuf::from(format!("{}.database", username));
+    if tokio::fs::metadata(&legacy).await.is_ok() {
+        tokio::fs::remove_file(&legacy).await?;
+        removed = true;
+    }
+    Ok(removed)
+}
+
+/*
+Path of {user}.database. A database left in the working directory by older
+versions is copied into the account directory the first time it is asked for.
+The original is left in place for the user to remove once they've checked the copy.
+*/
+pub async fn user_db_path(username: &str) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    let path = account_dir(username).await?.join(format!("{}.database", username));
+    let legacy = PathBuf::from(format!("{}.database", username));
+    if tokio::fs::metadata(&path).await.is_err() && tokio::fs::metadata(&legacy).await.is_ok() {
+        tokio::fs::copy(&legacy, &path).await?;
+        eprintln!("Copied {} to {}, the old file can be deleted", legacy.display(), path.display());
+    }
+    Ok(path)
+}
+
+//The user's database if one exists, in the account directory or where older versions left it. Creates nothing
+pub async fn existing_user_db_path(username: &str) -> Result<Option<PathBuf>, Box<dyn std::error::Error + Send + Sync>> {
+    let path = account_dir_path(username).await?.join(format!("{}.database", username));
+    let legacy = PathBuf::from(format!("{}.database", username));
+    for candidate in [path, legacy] {
+        if tokio::fs::metadata(&candidate).await.is_ok() {
+            return Ok(Some(candidate));
+        }
+    }
+    Ok(None)
+}
+
+pub async fn registry_path() -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(data_dir().await?.join("accounts.database"))
+}
+
+//Restricts an existing file to owner read/write, a no-op on platforms without unix permissions
+pub async fn restrict_file(path: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/*
+Replaces path with data, readable and writable by the owner only (0600 on unix). The data
+goes to a temporary file next to path that is then renamed over it, so a crash or a full
+disk leaves the old file or the new one, never a truncated mix of both.
+*/
+pub fn write_private(path: &Path, data: &[u8]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut tmp_name = path.file_name().ok_or("Not a file path")?.to_os_string();
+    tmp_name.push(".tmp");
+    let tmp = path.with_file_name(tmp_name);
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp)?;
+    //the mode only applies to new files, not to one a crash left behind
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    std::io::Write::write_all(&mut file, data)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+async fn create_private_dir(dir: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    tokio::fs::create_dir_all(dir).await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        tokio::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700)).await?;
+    }
+    Ok(())
+}
+
+fn default_data_dir() -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    #[cfg(windows)]
+    {
+        let local = env::var_os("LOCALAPPDATA").ok_or("LOCALAPPDATA is not set")?;
+        Ok(PathBuf::from(local).join(APP_DIR))
+    }
+    #[cfg(not(windows))]
+    {
+        Ok(config::xdg_dir("XDG_DATA_HOME", ".local/share")?.join(APP_DIR))
+    }
+}
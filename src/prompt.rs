@@ -0,0 +1,109 @@
+/**
+ * Terminal prompts shared by the CLIs: one field per prompt, secrets read without echo,
+ * and validation of account fields before anything is sent to the server.
+ */
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+//times an invalid field is asked for again before giving up
+const FIELD_ATTEMPTS: usize = 3;
+const USERNAME_MAX_LEN: usize = 32;
+const PASSWORD_MIN_LEN: usize = 8;
+
+//Reads one line after showing prompt, without the line ending. Fails at end of input
+pub async fn line(prompt: &str) -> Result<String, BoxError> {
+    let mut stdout = io::stdout();
+    stdout.write_all(prompt.as_bytes()).await?;
+    stdout.flush().await?;
+
+    let mut input = String::new();
+    if BufReader::new(io::stdin()).read_line(&mut input).await? == 0 {
+        return Err(Box::from("No input"));
+    }
+    Ok(input.trim_end_matches(['\r', '\n']).to_string())
+}
+
+//Reads a password or passphrase from the terminal without echoing it
+pub async fn secret(prompt: &str) -> Result<String, BoxError> {
+    let prompt = prompt.to_string();
+    Ok(tokio::task::spawn_blocking(move || rpassword::prompt_password(prompt)).await??)
+}
+
+/*
+Asks for a new secret twice and returns it once both entries agree. min_len is the
+shortest secret accepted, 0 only refuses empty ones.
+*/
+pub async fn new_secret(prompt: &str, repeat_prompt: &str, min_len: usize) -> Result<String, BoxError> {
+    for _ in 0..FIELD_ATTEMPTS {
+        let secret = self::secret(prompt).await?;
+        if secret.is_empty() || secret.chars().count() < min_len {
+            eprintln!("Use at least {} characters", min_len.max(1));
+            continue;
+        }
+        if self::secret(repeat_prompt).await? != secret {
+            eprintln!("The entries don't match");
+            continue;
+        }
+        return Ok(secret);
+    }
+    Err(Box::from("No new secret was chosen"))
+}
+
+//A new account password, with confirmation
+pub async fn new_password() -> Result<String, BoxError> {
+    new_secret("New password: ", "Repeat the new password: ", PASSWORD_MIN_LEN).await
+}
+
+//Asks for a field until validate accepts it, printing why it didn't
+pub async fn field(prompt: &str, validate: fn(&str) -> Result<(), String>) -> Result<String, BoxError> {
+    for _ in 0..FIELD_ATTEMPTS {
+        let value = line(prompt).await?.trim().to_string();
+        match validate(&value) {
+            Ok(()) => return Ok(value),
+            Err(reason) => eprintln!("{}", reason),
+        }
+    }
+    Err(Box::from(format!("No valid input for '{}'", prompt.trim_end_matches([':', ' ']))))
+}
+
+pub async fn username() -> Result<String, BoxError> {
+    field("Username: ", validate_username).await
+}
+
+pub async fn email() -> Result<String, BoxError> {
+    field("Email: ", validate_email).await
+}
+
+/*
+Usernames name the account's data directory and show up in other users' clients, so they
+are kept to letters, digits, '.', '_' and '-', starting with a letter or digit.
+*/
+pub fn validate_username(username: &str) -> Result<(), String> {
+    if username.is_empty() || username.len() > USERNAME_MAX_LEN {
+        return Err(format!("A username has 1 to {} characters", USERNAME_MAX_LEN));
+    }
+    if !username.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Err(String::from("A username starts with a letter or a digit"));
+    }
+    if let Some(c) = username.chars().find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))) {
+        return Err(format!("A username can't contain '{}', only letters, digits, '.', '_' and '-'", c));
+    }
+    Ok(())
+}
+
+//A plausibility check only, the server confirms the address is real
+pub fn validate_email(email: &str) -> Result<(), String> {
+    let invalid = || Err(format!("'{}' is not an email address", email));
+    if email.chars().any(char::is_whitespace) {
+        return invalid();
+    }
+    let Some((local, domain)) = email.split_once('@') else {
+        return invalid();
+    };
+    let labels_ok = domain.split('.').count() >= 2 && domain.split('.').all(|label| !label.is_empty());
+    if local.is_empty() || domain.contains('@') || !labels_ok {
+        return invalid();
+    }
+    Ok(())
+}
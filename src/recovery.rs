@@ -0,0 +1,56 @@
+/**
+ * One-time recovery codes, for resetting a forgotten password from a device that holds
+ * no credentials. Codes are generated on the client and shown to the user once; only
+ * their digests leave the device, so the server can check a code without being able
+ * to hand one out.
+ *
+ * A code is 12 characters from an alphabet without look-alikes (no 0/O, 1/I/L, U),
+ * printed in groups of four. The digest is base64(SHA-256(normalized code)), where
+ * normalizing drops dashes and whitespace and uppercases, so codes can be typed loosely.
+ */
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use rand::rngs::OsRng;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+//codes issued at a time, a new batch replaces the old one
+pub const RECOVERY_CODE_COUNT: usize = 10;
+const CODE_LEN: usize = 12;
+const GROUP_LEN: usize = 4;
+const ALPHABET: &[u8] = b"ABCDEFGHJKMNPQRSTVWXYZ23456789";
+
+//A fresh batch of codes, formatted for display
+pub fn generate_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT).map(|_| generate_code()).collect()
+}
+
+fn generate_code() -> String {
+    let chars: Vec<char> = (0..CODE_LEN)
+        .map(|_| ALPHABET[OsRng.gen_range(0..ALPHABET.len())] as char)
+        .collect();
+    chars.chunks(GROUP_LEN)
+        .map(|group| group.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+//The code as sent to the server, or an error if it can't be one of ours
+pub fn normalize(code: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let normalized: String = code.chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if normalized.len() != CODE_LEN || !normalized.bytes().all(|b| ALPHABET.contains(&b)) {
+        return Err(Box::from("Not a recovery code, expected 12 letters and digits like ABCD-EFGH-JKMN"));
+    }
+    Ok(normalized)
+}
+
+pub fn digest(code: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(STANDARD.encode(Sha256::digest(normalize(code)?.as_bytes())))
+}
+
+//Digests of a batch, in the form registered with the server
+pub fn digests(codes: &[String]) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+    codes.iter().map(|code| digest(code)).collect()
+}
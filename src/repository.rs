@@ -0,0 +1,916 @@
+/**
+ * Typed access to the tables in {user}.database.
+ * All SQL against the local database lives here; callers work with the structs below
+ * and a Connection from db::connect.
+ * Message content is encrypted at rest, so message functions take the account's FieldCipher
+ * and hand back plaintext.
+ */
+use tokio_rusqlite::{params, Connection, OptionalExtension, Row};
+use tokio_rusqlite::rusqlite;
+
+use crate::encryption::FieldCipher;
+use crate::search;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    pub user_id: String,
+    pub email: String,
+    pub created_at: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Device {
+    pub device_id: i64,
+    pub user_id: String,
+    pub identity_key: Option<String>,
+    pub msg_sequence_num: i64,
+    pub verified: bool,
+    pub last_seen: Option<String>,
+    pub revoked: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conversation {
+    pub conversation_id: i64,
+    pub created_at: String,
+    pub last_active: String,
+    //disappearing message timer, None when messages are kept
+    pub expiry_seconds: Option<i64>,
+    //retention overrides, None follows the global policy and Some(0) keeps everything
+    pub retention_days: Option<i64>,
+    pub retention_messages: Option<i64>,
+}
+
+//A conversation as shown in a conversation list
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversationSummary {
+    pub conversation_id: i64,
+    pub participants: Vec<String>,
+    pub last_active: String,
+    pub unread_count: i64,
+    pub last_message: Option<Message>,
+}
+
+//Where a page of messages starts, pages are always returned oldest first
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cursor {
+    //the newest messages
+    Latest,
+    //messages older than this message_id
+    Before(i64),
+    //messages newer than this message_id
+    After(i64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub message_id: i64,
+    pub conversation_id: i64,
+    pub sender_id: String,
+    pub content: String,
+    pub created_at: String,
+    pub received_at: String,
+    //unix time after which the message is deleted
+    pub expires_at: Option<i64>,
+}
+
+//A history transfer in progress, see the transfer module
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transfer {
+    pub transfer_id: String,
+    //"send" on the device holding the history, "receive" on the new device
+    pub role: String,
+    pub peer_device: String,
+    //ephemeral x448 secret, still encrypted
+    pub secret_key: String,
+    //sender only: the receiver's hash of its public key, sent before the key itself
+    pub peer_commitment: Option<String>,
+    pub peer_public_key: Option<String>,
+    //sender only: exported_at of the snapshot being sent, so a resumed transfer sends the same bytes
+    pub snapshot_at: Option<i64>,
+    pub digest: Option<String>,
+    pub total_chunks: Option<i64>,
+}
+
+/*
+USERS
+*/
+//Inserts the user, or updates the email if they are already known
+pub async fn upsert_user(conn: &Connection, user_id: &str, email: &str) -> Result<(), BoxError> {
+    let (user_id, email) = (user_id.to_string(), email.to_string());
+    conn.call(move |call| {
+        call.execute(
+            "INSERT INTO users (user_id, email) VALUES (?1, ?2)
+             ON CONFLICT(user_id) DO UPDATE SET email = excluded.email",
+            params![user_id, email],
+        )?;
+        Ok::<(), rusqlite::Error>(())
+    }).await?;
+    Ok(())
+}
+
+//Makes sure a row exists for user_id without touching an existing one, the email is filled in later if ever learned
+pub async fn ensure_user(conn: &Connection, user_id: &str) -> Result<(), BoxError> {
+    let user_id = user_id.to_string();
+    conn.call(move |call| {
+        call.execute("INSERT OR IGNORE INTO users (user_id, email) VALUES (?1, '')", [&user_id])?;
+        Ok::<(), rusqlite::Error>(())
+    }).await?;
+    Ok(())
+}
+
+pub async fn get_user(conn: &Connection, user_id: &str) -> Result<Option<User>, BoxError> {
+    let user_id = user_id.to_string();
+    let user = conn.call(move |call| {
+        call.query_row(
+            "SELECT user_id, email, created_at FROM users WHERE user_id = ?1",
+            [&user_id],
+            user_from_row,
+        ).optional()
+    }).await?;
+    Ok(user)
+}
+
+pub async fn list_users(conn: &Connection) -> Result<Vec<User>, BoxError> {
+    let users = conn.call(|call| {
+        let mut stmt = call.prepare("SELECT user_id, email, created_at FROM users ORDER BY user_id")?;
+        let users = stmt.query_map([], user_from_row)?.collect::<Result<Vec<_>, _>>()?;
+        Ok::<_, rusqlite::Error>(users)
+    }).await?;
+    Ok(users)
+}
+
+/*
+DEVICES
+*/
+//Inserts the device or replaces every field of an existing one with the same device_id
+pub async fn upsert_device(conn: &Connection, device: &Device) -> Result<(), BoxError> {
+    let device = device.clone();
+    conn.call(move |call| {
+        call.execute(
+            "INSERT INTO devices (device_id, user_id, identity_key, msg_sequence_num, verified, last_seen, revoked)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT(device_id) DO UPDATE SET
+                user_id = excluded.user_id,
+                identity_key = excluded.identity_key,
+                msg_sequence_num = excluded.msg_sequence_num,
+                verified = excluded.verified,
+                last_seen = excluded.last_seen,
+                revoked = excluded.revoked",
+            params![
+                device.device_id, device.user_id, device.identity_key,
+                device.msg_sequence_num, device.verified, device.last_seen, device.revoked
+            ],
+        )?;
+        Ok::<(), rusqlite::Error>(())
+    }).await?;
+    Ok(())
+}
+
+pub async fn get_device(conn: &Connection, device_id: i64) -> Result<Option<Device>, BoxError> {
+    let device = conn.call(move |call| {
+        call.query_row(
+            &format!("{} WHERE device_id = ?1", DEVICE_SELECT),
+            [device_id],
+            device_from_row,
+        ).optional()
+    }).await?;
+    Ok(device)
+}
+
+//Every device known for a user, including revoked ones
+pub async fn list_devices(conn: &Connection, user_id: &str) -> Result<Vec<Device>, BoxError> {
+    let user_id = user_id.to_string();
+    let devices = conn.call(move |call| {
+        let mut stmt = call.prepare(&format!("{} WHERE user_id = ?1 ORDER BY device_id", DEVICE_SELECT))?;
+        let devices = stmt.query_map([&user_id], device_from_row)?.collect::<Result<Vec<_>, _>>()?;
+        Ok::<_, rusqlite::Error>(devices)
+    }).await?;
+    Ok(devices)
+}
+
+/*
+Records the server's list of user_id's devices: listed ids are added or un-revoked, and
+devices of user_id that are no longer listed are marked revoked. Ids that aren't numbers
+are skipped, and an id already known for another user is left alone.
+*/
+pub async fn record_device_list(conn: &Connection, user_id: &str, device_ids: &[String]) -> Result<(), BoxError> {
+    let user_id = user_id.to_string();
+    let device_ids: Vec<i64> = device_ids.iter().filter_map(|id| id.parse().ok()).collect();
+    conn.call(move |call| {
+        let tx = call.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+        tx.execute("INSERT OR IGNORE INTO users (user_id, email) VALUES (?1, '')", [&user_id])?;
+        tx.execute("UPDATE devices SET revoked = 1 WHERE user_id = ?1", [&user_id])?;
+        for device_id in &device_ids {
+            tx.execute(
+                "INSERT INTO devices (device_id, user_id) VALUES (?1, ?2)
+                 ON CONFLICT(device_id) DO UPDATE SET revoked = 0 WHERE devices.user_id = excluded.user_id",
+                params![device_id, user_id],
+            )?;
+        }
+        tx.commit()?;
+        Ok::<(), rusqlite::Error>(())
+    }).await?;
+    Ok(())
+}
+
+/*
+CONVERSATIONS
+*/
+pub async fn insert_conversation(conn: &Connection) -> Result<i64, BoxError> {
+    let conversation_id = conn.call(|call| {
+        call.execute("INSERT INTO conversations DEFAULT VALUES", [])?;
+        Ok::<i64, rusqlite::Error>(call.last_insert_rowid())
+    }).await?;
+    Ok(conversation_id)
+}
+
+/*
+The one-to-one conversation with peer_id, created (with peer_id as its only participant)
+if this is the first contact. Runs in one transaction so two messages arriving together
+can't create two conversations.
+*/
+pub async fn find_or_create_direct_conversation(conn: &Connection, peer_id: &str) -> Result<i64, BoxError> {
+    let peer_id = peer_id.to_string();
+    let conversation_id = conn.call(move |call| {
+        let tx = call.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+        let existing: Option<i64> = tx.query_row(
+            "SELECT uc.conversation_id FROM user_conversations uc
+             WHERE uc.user_id = ?1
+             AND (SELECT COUNT(*) FROM user_conversations o WHERE o.conversation_id = uc.conversation_id) = 1
+             ORDER BY uc.conversation_id LIMIT 1",
+            [&peer_id],
+            |row| row.get(0),
+        ).optional()?;
+        let conversation_id = match existing {
+            Some(id) => id,
+            None => {
+                tx.execute("INSERT INTO conversations DEFAULT VALUES", [])?;
+                let id = tx.last_insert_rowid();
+                tx.execute(
+                    "INSERT INTO user_conversations (user_id, conversation_id) VALUES (?1, ?2)",
+                    params![peer_id, id],
+                )?;
+                id
+            }
+        };
+        tx.commit()?;
+        Ok::<i64, rusqlite::Error>(conversation_id)
+    }).await?;
+    Ok(conversation_id)
+}
+
+/*
+The conversation whose participants are exactly participant_ids, created if there is none.
+Used for conversations with several participants, see find_or_create_direct_conversation
+for one-to-one conversations.
+*/
+pub async fn find_or_create_conversation_with(conn: &Connection, participant_ids: &[String]) -> Result<i64, BoxError> {
+    let mut participant_ids = participant_ids.to_vec();
+    participant_ids.sort();
+    participant_ids.dedup();
+    let conversation_id = conn.call(move |call| {
+        let tx = call.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+        let key = participant_ids.join("\u{1f}");
+        let existing: Option<i64> = tx.query_row(
+            "SELECT conversation_id FROM
+                (SELECT conversation_id, group_concat(user_id, char(31) ORDER BY user_id) AS members
+                 FROM user_conversations GROUP BY conversation_id)
+             WHERE members = ?1 ORDER BY conversation_id LIMIT 1",
+            [&key],
+            |row| row.get(0),
+        ).optional()?;
+        let conversation_id = match existing {
+            Some(id) => id,
+            None => {
+                tx.execute("INSERT INTO conversations DEFAULT VALUES", [])?;
+                let id = tx.last_insert_rowid();
+                for user_id in &participant_ids {
+                    tx.execute(
+                        "INSERT INTO user_conversations (user_id, conversation_id) VALUES (?1, ?2)",
+                        params![user_id, id],
+                    )?;
+                }
+                id
+            }
+        };
+        tx.commit()?;
+        Ok::<i64, rusqlite::Error>(conversation_id)
+    }).await?;
+    Ok(conversation_id)
+}
+
+pub async fn get_conversation(conn: &Connection, conversation_id: i64) -> Result<Option<Conversation>, BoxError> {
+    let conversation = conn.call(move |call| {
+        call.query_row(
+            "SELECT conversation_id, created_at, last_active, expiry_seconds, retention_days, retention_messages FROM conversations WHERE conversation_id = ?1",
+            [conversation_id],
+            conversation_from_row,
+        ).optional()
+    }).await?;
+    Ok(conversation)
+}
+
+//Most recently active first
+pub async fn list_conversations(conn: &Connection) -> Result<Vec<Conversation>, BoxError> {
+    let conversations = conn.call(|call| {
+        let mut stmt = call.prepare(
+            "SELECT conversation_id, created_at, last_active, expiry_seconds, retention_days, retention_messages FROM conversations
+             ORDER BY last_active DESC, conversation_id DESC",
+        )?;
+        let conversations = stmt.query_map([], conversation_from_row)?.collect::<Result<Vec<_>, _>>()?;
+        Ok::<_, rusqlite::Error>(conversations)
+    }).await?;
+    Ok(conversations)
+}
+
+/*
+Every conversation, most recently active first, with its participants, the number of
+messages from others since it was last read, and its newest message.
+*/
+pub async fn list_conversation_summaries(conn: &Connection, cipher: &FieldCipher, username: &str) -> Result<Vec<ConversationSummary>, BoxError> {
+    let username = username.to_string();
+    let rows = conn.call(move |call| {
+        let mut stmt = call.prepare(
+            "SELECT c.conversation_id, c.last_active,
+                (SELECT group_concat(user_id, char(31)) FROM user_conversations uc WHERE uc.conversation_id = c.conversation_id),
+                (SELECT COUNT(*) FROM messages u WHERE u.conversation_id = c.conversation_id
+                    AND u.message_id > c.last_read_message_id AND u.sender_id != ?1),
+                m.message_id, m.conversation_id, m.sender_id, m.content, m.created_at, m.received_at, m.expires_at
+             FROM conversations c
+             LEFT JOIN messages m ON m.message_id =
+                (SELECT MAX(message_id) FROM messages l WHERE l.conversation_id = c.conversation_id)
+             ORDER BY c.last_active DESC, c.conversation_id DESC",
+        )?;
+        let rows = stmt.query_map([&username], |row| {
+            let participants: Option<String> = row.get(2)?;
+            let last_message = match row.get::<_, Option<i64>>(4)? {
+                Some(_) => Some(message_from_row_at(row, 4)?),
+                None => None,
+            };
+            Ok(ConversationSummary {
+                conversation_id: row.get(0)?,
+                participants: participants
+                    .map(|p| p.split('\u{1f}').map(|s| s.to_string()).collect())
+                    .unwrap_or_default(),
+                last_active: row.get(1)?,
+                unread_count: row.get(3)?,
+                last_message,
+            })
+        })?.collect::<Result<Vec<_>, _>>()?;
+        Ok::<_, rusqlite::Error>(rows)
+    }).await?;
+
+    rows.into_iter().map(|mut summary| {
+        summary.last_message = summary.last_message.map(|m| decrypt_message(cipher, m)).transpose()?;
+        Ok(summary)
+    }).collect()
+}
+
+//Marks everything up to and including message_id as read, never moves the marker backwards
+pub async fn mark_read(conn: &Connection, conversation_id: i64, message_id: i64) -> Result<(), BoxError> {
+    conn.call(move |call| {
+        call.execute(
+            "UPDATE conversations SET last_read_message_id = MAX(last_read_message_id, ?2) WHERE conversation_id = ?1",
+            [conversation_id, message_id],
+        )?;
+        Ok::<(), rusqlite::Error>(())
+    }).await?;
+    Ok(())
+}
+
+pub async fn set_conversation_expiry(conn: &Connection, conversation_id: i64, expiry_seconds: Option<i64>) -> Result<(), BoxError> {
+    conn.call(move |call| {
+        call.execute(
+            "UPDATE conversations SET expiry_seconds = ?2 WHERE conversation_id = ?1",
+            params![conversation_id, expiry_seconds],
+        )?;
+        Ok::<(), rusqlite::Error>(())
+    }).await?;
+    Ok(())
+}
+
+pub async fn set_conversation_retention(conn: &Connection, conversation_id: i64, retention_days: Option<i64>, retention_messages: Option<i64>) -> Result<(), BoxError> {
+    conn.call(move |call| {
+        call.execute(
+            "UPDATE conversations SET retention_days = ?2, retention_messages = ?3 WHERE conversation_id = ?1",
+            params![conversation_id, retention_days, retention_messages],
+        )?;
+        Ok::<(), rusqlite::Error>(())
+    }).await?;
+    Ok(())
+}
+
+//Deletes the conversation along with its messages and participants
+pub async fn delete_conversation(conn: &Connection, conversation_id: i64) -> Result<bool, BoxError> {
+    let deleted = conn.call(move |call| {
+        let tx = call.transaction()?;
+        tx.execute(
+            "DELETE FROM messages_fts WHERE rowid IN (SELECT message_id FROM messages WHERE conversation_id = ?1)",
+            [conversation_id],
+        )?;
+        tx.execute("DELETE FROM messages WHERE conversation_id = ?1", [conversation_id])?;
+        tx.execute("DELETE FROM user_conversations WHERE conversation_id = ?1", [conversation_id])?;
+        let deleted = tx.execute("DELETE FROM conversations WHERE conversation_id = ?1", [conversation_id])?;
+        tx.commit()?;
+        Ok::<bool, rusqlite::Error>(deleted > 0)
+    }).await?;
+    Ok(deleted)
+}
+
+/*
+USER_CONVERSATIONS
+*/
+pub async fn add_participant(conn: &Connection, conversation_id: i64, user_id: &str) -> Result<(), BoxError> {
+    let user_id = user_id.to_string();
+    conn.call(move |call| {
+        call.execute(
+            "INSERT OR IGNORE INTO user_conversations (user_id, conversation_id) VALUES (?1, ?2)",
+            params![user_id, conversation_id],
+        )?;
+        Ok::<(), rusqlite::Error>(())
+    }).await?;
+    Ok(())
+}
+
+pub async fn list_participants(conn: &Connection, conversation_id: i64) -> Result<Vec<String>, BoxError> {
+    let participants = conn.call(move |call| {
+        let mut stmt = call.prepare(
+            "SELECT user_id FROM user_conversations WHERE conversation_id = ?1 ORDER BY user_id",
+        )?;
+        let participants = stmt.query_map([conversation_id], |row| row.get(0))?.collect::<Result<Vec<String>, _>>()?;
+        Ok::<_, rusqlite::Error>(participants)
+    }).await?;
+    Ok(participants)
+}
+
+/*
+MESSAGES
+*/
+/*
+Stores and indexes the message and bumps the conversation's last_active. expires_at is the
+unix time the message disappears, None to keep it. Returns the new message_id
+*/
+pub async fn insert_message(conn: &Connection, cipher: &FieldCipher, conversation_id: i64, sender_id: &str, content: &str, expires_at: Option<i64>) -> Result<i64, BoxError> {
+    let (sender_id, terms, content) = (sender_id.to_string(), search::index_terms(cipher, content), cipher.encrypt(content)?);
+    let message_id = conn.call(move |call| {
+        let tx = call.transaction()?;
+        tx.execute(
+            "INSERT INTO messages (conversation_id, sender_id, content, expires_at) VALUES (?1, ?2, ?3, ?4)",
+            params![conversation_id, sender_id, content, expires_at],
+        )?;
+        let message_id = tx.last_insert_rowid();
+        tx.execute("INSERT INTO messages_fts (rowid, content) VALUES (?1, ?2)", params![message_id, terms])?;
+        tx.execute(
+            "UPDATE conversations SET last_active = CURRENT_TIMESTAMP WHERE conversation_id = ?1",
+            [conversation_id],
+        )?;
+        tx.commit()?;
+        Ok::<i64, rusqlite::Error>(message_id)
+    }).await?;
+    Ok(message_id)
+}
+
+/*
+Inserts a message keeping the timestamps it was originally stored with, for imports.
+last_active only moves forward.
+*/
+pub async fn insert_message_at(conn: &Connection, cipher: &FieldCipher, message: &Message) -> Result<i64, BoxError> {
+    let terms = search::index_terms(cipher, &message.content);
+    let content = cipher.encrypt(&message.content)?;
+    let message = message.clone();
+    let message_id = conn.call(move |call| {
+        let tx = call.transaction()?;
+        tx.execute(
+            "INSERT INTO messages (conversation_id, sender_id, content, created_at, received_at, expires_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![message.conversation_id, message.sender_id, content, message.created_at, message.received_at, message.expires_at],
+        )?;
+        let message_id = tx.last_insert_rowid();
+        tx.execute("INSERT INTO messages_fts (rowid, content) VALUES (?1, ?2)", params![message_id, terms])?;
+        tx.execute(
+            "UPDATE conversations SET last_active = MAX(last_active, ?2) WHERE conversation_id = ?1",
+            params![message.conversation_id, message.created_at],
+        )?;
+        tx.commit()?;
+        Ok::<i64, rusqlite::Error>(message_id)
+    }).await?;
+    Ok(message_id)
+}
+
+pub async fn get_message(conn: &Connection, cipher: &FieldCipher, message_id: i64) -> Result<Option<Message>, BoxError> {
+    let message = conn.call(move |call| {
+        call.query_row(
+            &format!("{} WHERE message_id = ?1", MESSAGE_SELECT),
+            [message_id],
+            message_from_row,
+        ).optional()
+    }).await?;
+    message.map(|m| decrypt_message(cipher, m)).transpose()
+}
+
+//Oldest first
+pub async fn list_messages(conn: &Connection, cipher: &FieldCipher, conversation_id: i64) -> Result<Vec<Message>, BoxError> {
+    let messages = conn.call(move |call| {
+        let mut stmt = call.prepare(&format!("{} WHERE conversation_id = ?1 ORDER BY message_id", MESSAGE_SELECT))?;
+        let messages = stmt.query_map([conversation_id], message_from_row)?.collect::<Result<Vec<_>, _>>()?;
+        Ok::<_, rusqlite::Error>(messages)
+    }).await?;
+    messages.into_iter().map(|m| decrypt_message(cipher, m)).collect()
+}
+
+/*
+Messages of one conversation (or all when None) created between the dates since and until
+(YYYY-MM-DD, both inclusive, either may be open), ordered by conversation then oldest first.
+*/
+pub async fn list_messages_in_range(conn: &Connection, cipher: &FieldCipher, conversation_id: Option<i64>, since: Option<String>, until: Option<String>) -> Result<Vec<Message>, BoxError> {
+    let messages = conn.call(move |call| {
+        let mut stmt = call.prepare(&format!(
+            "{} WHERE (?1 IS NULL OR conversation_id = ?1)
+             AND (?2 IS NULL OR created_at >= date(?2))
+             AND (?3 IS NULL OR created_at < date(?3, '+1 day'))
+             ORDER BY conversation_id, message_id",
+            MESSAGE_SELECT
+        ))?;
+        let messages = stmt.query_map(params![conversation_id, since, until], message_from_row)?.collect::<Result<Vec<_>, _>>()?;
+        Ok::<_, rusqlite::Error>(messages)
+    }).await?;
+    messages.into_iter().map(|m| decrypt_message(cipher, m)).collect()
+}
+
+//Up to limit messages on one side of the cursor, oldest first
+pub async fn page_messages(conn: &Connection, cipher: &FieldCipher, conversation_id: i64, cursor: Cursor, limit: i64) -> Result<Vec<Message>, BoxError> {
+    let messages = conn.call(move |call| {
+        let (condition, order, bound) = match cursor {
+            Cursor::Latest => ("", "DESC", 0),
+            Cursor::Before(id) => ("AND message_id < ?2", "DESC", id),
+            Cursor::After(id) => ("AND message_id > ?2", "ASC", id),
+        };
+        let mut stmt = call.prepare(&format!(
+            "{} WHERE conversation_id = ?1 {} ORDER BY message_id {} LIMIT ?3",
+            MESSAGE_SELECT, condition, order
+        ))?;
+        let mut messages = stmt.query_map(params![conversation_id, bound, limit], message_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        if order == "DESC" {
+            messages.reverse();
+        }
+        Ok::<_, rusqlite::Error>(messages)
+    }).await?;
+    messages.into_iter().map(|m| decrypt_message(cipher, m)).collect()
+}
+
+//Replaces a message's content and its search index entry
+pub async fn update_message_content(conn: &Connection, cipher: &FieldCipher, message_id: i64, content: &str) -> Result<bool, BoxError> {
+    let (terms, content) = (search::index_terms(cipher, content), cipher.encrypt(content)?);
+    let updated = conn.call(move |call| {
+        let tx = call.transaction()?;
+        let updated = tx.execute("UPDATE messages SET content = ?1 WHERE message_id = ?2", params![content, message_id])?;
+        if updated > 0 {
+            tx.execute("DELETE FROM messages_fts WHERE rowid = ?1", [message_id])?;
+            tx.execute("INSERT INTO messages_fts (rowid, content) VALUES (?1, ?2)", params![message_id, terms])?;
+        }
+        tx.commit()?;
+        Ok::<bool, rusqlite::Error>(updated > 0)
+    }).await?;
+    Ok(updated)
+}
+
+pub async fn delete_message(conn: &Connection, message_id: i64) -> Result<bool, BoxError> {
+    let deleted = conn.call(move |call| {
+        let tx = call.transaction()?;
+        tx.execute("DELETE FROM messages_fts WHERE rowid = ?1", [message_id])?;
+        let deleted = tx.execute("DELETE FROM messages WHERE message_id = ?1", [message_id])?;
+        tx.commit()?;
+        Ok::<bool, rusqlite::Error>(deleted > 0)
+    }).await?;
+    Ok(deleted)
+}
+
+//Deletes (with their search index entries) every message whose expires_at has passed. Returns how many
+pub async fn delete_expired_messages(conn: &Connection, now: i64) -> Result<usize, BoxError> {
+    let deleted = conn.call(move |call| {
+        let tx = call.transaction()?;
+        tx.execute(
+            "DELETE FROM messages_fts WHERE rowid IN
+                (SELECT message_id FROM messages WHERE expires_at IS NOT NULL AND expires_at <= ?1)",
+            [now],
+        )?;
+        let deleted = tx.execute("DELETE FROM messages WHERE expires_at IS NOT NULL AND expires_at <= ?1", [now])?;
+        tx.commit()?;
+        Ok::<usize, rusqlite::Error>(deleted)
+    }).await?;
+    Ok(deleted)
+}
+
+//Deletes the conversation's messages created before the unix time cutoff. Returns how many
+pub async fn delete_messages_before(conn: &Connection, conversation_id: i64, cutoff: i64) -> Result<usize, BoxError> {
+    delete_messages_where(
+        conn,
+        "conversation_id = ?1 AND created_at < datetime(?2, 'unixepoch')",
+        vec![conversation_id, cutoff],
+    ).await
+}
+
+//Deletes all but the newest keep messages of the conversation. Returns how many
+pub async fn delete_messages_beyond(conn: &Connection, conversation_id: i64, keep: i64) -> Result<usize, BoxError> {
+    delete_messages_where(
+        conn,
+        "conversation_id = ?1 AND message_id NOT IN
+            (SELECT message_id FROM messages WHERE conversation_id = ?1 ORDER BY message_id DESC LIMIT ?2)",
+        vec![conversation_id, keep],
+    ).await
+}
+
+//Deletes the count oldest messages across every conversation. Returns how many
+pub async fn delete_oldest_messages(conn: &Connection, count: i64) -> Result<usize, BoxError> {
+    delete_messages_where(
+        conn,
+        "message_id IN (SELECT message_id FROM messages ORDER BY message_id LIMIT ?1)",
+        vec![count],
+    ).await
+}
+
+//Deletes the messages matching filter together with their search index entries
+async fn delete_messages_where(conn: &Connection, filter: &'static str, args: Vec<i64>) -> Result<usize, BoxError> {
+    let deleted = conn.call(move |call| {
+        let tx = call.transaction()?;
+        tx.execute(
+            &format!("DELETE FROM messages_fts WHERE rowid IN (SELECT message_id FROM messages WHERE {})", filter),
+            rusqlite::params_from_iter(&args),
+        )?;
+        let deleted = tx.execute(&format!("DELETE FROM messages WHERE {}", filter), rusqlite::params_from_iter(&args))?;
+        tx.commit()?;
+        Ok::<usize, rusqlite::Error>(deleted)
+    }).await?;
+    Ok(deleted)
+}
+
+//The soonest expires_at of any stored message
+pub async fn next_expiry(conn: &Connection) -> Result<Option<i64>, BoxError> {
+    let next = conn.call(|call| {
+        call.query_row("SELECT MIN(expires_at) FROM messages WHERE expires_at IS NOT NULL", [], |row| row.get(0))
+    }).await?;
+    Ok(next)
+}
+
+/*
+HISTORY_TRANSFERS
+*/
+//Inserts the transfer, or replaces it along with any chunks already received
+pub async fn save_transfer(conn: &Connection, transfer: &Transfer) -> Result<(), BoxError> {
+    let transfer = transfer.clone();
+    conn.call(move |call| {
+        call.execute(
+            "INSERT INTO history_transfers
+                (transfer_id, role, peer_device, secret_key, peer_commitment, peer_public_key, snapshot_at, digest, total_chunks)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+             ON CONFLICT(transfer_id) DO UPDATE SET
+                role = excluded.role, peer_device = excluded.peer_device, secret_key = excluded.secret_key,
+                peer_commitment = excluded.peer_commitment, peer_public_key = excluded.peer_public_key,
+                snapshot_at = excluded.snapshot_at, digest = excluded.digest, total_chunks = excluded.total_chunks",
+            params![
+                transfer.transfer_id, transfer.role, transfer.peer_device, transfer.secret_key, transfer.peer_commitment,
+                transfer.peer_public_key, transfer.snapshot_at, transfer.digest, transfer.total_chunks,
+            ],
+        )?;
+        Ok::<(), rusqlite::Error>(())
+    }).await?;
+    Ok(())
+}
+
+pub async fn get_transfer(conn: &Connection, transfer_id: &str) -> Result<Option<Transfer>, BoxError> {
+    let transfer_id = transfer_id.to_string();
+    let transfer = conn.call(move |call| {
+        call.query_row(&format!("{} WHERE transfer_id = ?1", TRANSFER_SELECT), [&transfer_id], transfer_from_row).optional()
+    }).await?;
+    Ok(transfer)
+}
+
+//The unfinished transfer with role from or to peer_device, newest first
+pub async fn find_transfer(conn: &Connection, role: &str, peer_device: &str) -> Result<Option<Transfer>, BoxError> {
+    let (role, peer_device) = (role.to_string(), peer_device.to_string());
+    let transfer = conn.call(move |call| {
+        call.query_row(
+            &format!("{} WHERE role = ?1 AND peer_device = ?2 ORDER BY created_at DESC LIMIT 1", TRANSFER_SELECT),
+            [&role, &peer_device],
+            transfer_from_row,
+        ).optional()
+    }).await?;
+    Ok(transfer)
+}
+
+pub async fn delete_transfer(conn: &Connection, transfer_id: &str) -> Result<bool, BoxError> {
+    let transfer_id = transfer_id.to_string();
+    let deleted = conn.call(move |call| {
+        let tx = call.transaction()?;
+        tx.execute("DELETE FROM history_transfer_chunks WHERE transfer_id = ?1", [&transfer_id])?;
+        let deleted = tx.execute("DELETE FROM history_transfers WHERE transfer_id = ?1", [&transfer_id])?;
+        tx.commit()?;
+        Ok::<bool, rusqlite::Error>(deleted > 0)
+    }).await?;
+    Ok(deleted)
+}
+
+pub async fn insert_transfer_chunk(conn: &Connection, transfer_id: &str, chunk_index: i64, data: &str) -> Result<(), BoxError> {
+    let (transfer_id, data) = (transfer_id.to_string(), data.to_string());
+    conn.call(move |call| {
+        call.execute(
+            "INSERT OR REPLACE INTO history_transfer_chunks (transfer_id, chunk_index, data) VALUES (?1, ?2, ?3)",
+            params![transfer_id, chunk_index, data],
+        )?;
+        Ok::<(), rusqlite::Error>(())
+    }).await?;
+    Ok(())
+}
+
+//Chunk data in index order
+pub async fn list_transfer_chunks(conn: &Connection, transfer_id: &str) -> Result<Vec<(i64, String)>, BoxError> {
+    let transfer_id = transfer_id.to_string();
+    let chunks = conn.call(move |call| {
+        let mut stmt = call.prepare(
+            "SELECT chunk_index, data FROM history_transfer_chunks WHERE transfer_id = ?1 ORDER BY chunk_index",
+        )?;
+        let chunks = stmt.query_map([&transfer_id], |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<Result<Vec<_>, _>>()?;
+        Ok::<_, rusqlite::Error>(chunks)
+    }).await?;
+    Ok(chunks)
+}
+
+pub async fn clear_transfer_chunks(conn: &Connection, transfer_id: &str) -> Result<(), BoxError> {
+    let transfer_id = transfer_id.to_string();
+    conn.call(move |call| {
+        call.execute("DELETE FROM history_transfer_chunks WHERE transfer_id = ?1", [&transfer_id])?;
+        Ok::<(), rusqlite::Error>(())
+    }).await?;
+    Ok(())
+}
+
+/*
+STORAGE
+*/
+//Bytes held by live pages, what VACUUM would shrink the file to
+pub async fn database_size(conn: &Connection) -> Result<i64, BoxError> {
+    let size = conn.call(|call| {
+        let page_count: i64 = call.pragma_query_value(None, "page_count", |row| row.get(0))?;
+        let freelist: i64 = call.pragma_query_value(None, "freelist_count", |row| row.get(0))?;
+        let page_size: i64 = call.pragma_query_value(None, "page_size", |row| row.get(0))?;
+        Ok::<i64, rusqlite::Error>((page_count - freelist) * page_size)
+    }).await?;
+    Ok(size)
+}
+
+//Merges the search index, dropping the entries of deleted messages so their pages can be freed
+pub async fn optimize_search_index(conn: &Connection) -> Result<(), BoxError> {
+    conn.call(|call| {
+        call.execute("INSERT INTO messages_fts(messages_fts) VALUES ('optimize')", [])?;
+        Ok::<(), rusqlite::Error>(())
+    }).await?;
+    Ok(())
+}
+
+//Pages left free by deletes, and the total page count
+pub async fn free_pages(conn: &Connection) -> Result<(i64, i64), BoxError> {
+    let pages = conn.call(|call| {
+        let freelist: i64 = call.pragma_query_value(None, "freelist_count", |row| row.get(0))?;
+        let page_count: i64 = call.pragma_query_value(None, "page_count", |row| row.get(0))?;
+        Ok::<_, rusqlite::Error>((freelist, page_count))
+    }).await?;
+    Ok(pages)
+}
+
+pub async fn vacuum(conn: &Connection) -> Result<(), BoxError> {
+    conn.call(|call| {
+        call.execute_batch("VACUUM")?;
+        Ok::<(), rusqlite::Error>(())
+    }).await?;
+    Ok(())
+}
+
+/*
+SEARCH
+*/
+//Messages matching an FTS5 query, best match first. See search::build_query for turning user input into one
+pub async fn search_messages(conn: &Connection, cipher: &FieldCipher, fts_query: &str, limit: i64) -> Result<Vec<Message>, BoxError> {
+    let fts_query = fts_query.to_string();
+    let messages = conn.call(move |call| {
+        let mut stmt = call.prepare(
+            "SELECT m.message_id, m.conversation_id, m.sender_id, m.content, m.created_at, m.received_at, m.expires_at
+             FROM messages_fts f JOIN messages m ON m.message_id = f.rowid
+             WHERE messages_fts MATCH ?1
+             ORDER BY f.rank LIMIT ?2",
+        )?;
+        let messages = stmt.query_map(params![fts_query, limit], message_from_row)?.collect::<Result<Vec<_>, _>>()?;
+        Ok::<_, rusqlite::Error>(messages)
+    }).await?;
+    messages.into_iter().map(|m| decrypt_message(cipher, m)).collect()
+}
+
+//Indexes messages queued by the v4 migration (stored before search existed). Returns how many were indexed
+pub async fn index_pending_messages(conn: &Connection, cipher: &FieldCipher) -> Result<usize, BoxError> {
+    let pending = conn.call(|call| {
+        let mut stmt = call.prepare(
+            "SELECT m.message_id, m.content FROM messages_fts_pending p JOIN messages m ON m.message_id = p.message_id",
+        )?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok::<_, rusqlite::Error>(rows)
+    }).await?;
+
+    let mut indexed = Vec::with_capacity(pending.len());
+    for (message_id, content) in pending {
+        indexed.push((message_id, search::index_terms(cipher, &cipher.decrypt(&content)?)));
+    }
+    let count = indexed.len();
+
+    conn.call(move |call| {
+        let tx = call.transaction()?;
+        for (message_id, terms) in &indexed {
+            tx.execute("INSERT INTO messages_fts (rowid, content) VALUES (?1, ?2)", params![message_id, terms])?;
+        }
+        //rows whose message was deleted in the meantime are dropped as well
+        tx.execute("DELETE FROM messages_fts_pending", [])?;
+        tx.commit()?;
+        Ok::<(), rusqlite::Error>(())
+    }).await?;
+    Ok(count)
+}
+
+/*
+ROW MAPPING
+*/
+const DEVICE_SELECT: &str =
+    "SELECT device_id, user_id, identity_key, msg_sequence_num, verified, last_seen, revoked FROM devices";
+const TRANSFER_SELECT: &str =
+    "SELECT transfer_id, role, peer_device, secret_key, peer_commitment, peer_public_key, snapshot_at, digest, total_chunks FROM history_transfers";
+const MESSAGE_SELECT: &str =
+    "SELECT message_id, conversation_id, sender_id, content, created_at, received_at, expires_at FROM messages";
+
+fn user_from_row(row: &Row<'_>) -> rusqlite::Result<User> {
+    Ok(User {
+        user_id: row.get(0)?,
+        email: row.get(1)?,
+        created_at: row.get(2)?,
+    })
+}
+
+fn transfer_from_row(row: &Row<'_>) -> rusqlite::Result<Transfer> {
+    Ok(Transfer {
+        transfer_id: row.get(0)?,
+        role: row.get(1)?,
+        peer_device: row.get(2)?,
+        secret_key: row.get(3)?,
+        peer_commitment: row.get(4)?,
+        peer_public_key: row.get(5)?,
+        snapshot_at: row.get(6)?,
+        digest: row.get(7)?,
+        total_chunks: row.get(8)?,
+    })
+}
+
+fn device_from_row(row: &Row<'_>) -> rusqlite::Result<Device> {
+    Ok(Device {
+        device_id: row.get(0)?,
+        user_id: row.get(1)?,
+        identity_key: row.get(2)?,
+        msg_sequence_num: row.get(3)?,
+        verified: row.get(4)?,
+        last_seen: row.get(5)?,
+        revoked: row.get(6)?,
+    })
+}
+
+fn conversation_from_row(row: &Row<'_>) -> rusqlite::Result<Conversation> {
+    Ok(Conversation {
+        conversation_id: row.get(0)?,
+        created_at: row.get(1)?,
+        last_active: row.get(2)?,
+        expiry_seconds: row.get(3)?,
+        retention_days: row.get(4)?,
+        retention_messages: row.get(5)?,
+    })
+}
+
+fn decrypt_message(cipher: &FieldCipher, mut message: Message) -> Result<Message, BoxError> {
+    message.content = cipher.decrypt(&message.content)?;
+    Ok(message)
+}
+
+fn message_from_row(row: &Row<'_>) -> rusqlite::Result<Message> {
+    message_from_row_at(row, 0)
+}
+
+//for queries that select the message columns after others
+fn message_from_row_at(row: &Row<'_>, offset: usize) -> rusqlite::Result<Message> {
+    Ok(Message {
+        message_id: row.get(offset)?,
+        conversation_id: row.get(offset + 1)?,
+        sender_id: row.get(offset + 2)?,
+        content: row.get(offset + 3)?,
+        created_at: row.get(offset + 4)?,
+        received_at: row.get(offset + 5)?,
+        expires_at: row.get(offset + 6)?,
+    })
+}
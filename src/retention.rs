@@ -0,0 +1,127 @@
+/**
+ * Local retention rules: how long messages are kept, how many per conversation and how
+ * large the database may grow. The limits come from config.json, conversations can
+ * override the age and count limits (see repository::set_conversation_retention).
+ * Disappearing messages are handled separately by the disappearing module.
+ */
+use std::time::Duration;
+use tokio_rusqlite::Connection;
+
+use crate::clock::unix_now;
+use crate::config::RetentionConfig;
+use crate::history;
+use crate::repository::{self, Conversation};
+use crate::session_manager::{SessionContext, SessionEvent};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+//how often the maintenance task runs during a session
+const MAINTENANCE_INTERVAL_SECS: u64 = 60 * 60;
+//messages deleted per round while the database is over its size quota
+const QUOTA_BATCH: i64 = 100;
+//most messages one maintenance run deletes for the quota, the next run carries on
+const QUOTA_MAX_PER_RUN: usize = 1000;
+//VACUUM when at least 1 / VACUUM_FREE_RATIO of the pages are free
+const VACUUM_FREE_RATIO: i64 = 4;
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+//What a maintenance run removed
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MaintenanceReport {
+    //messages older than their conversation's age limit
+    pub too_old: usize,
+    //messages beyond their conversation's count limit
+    pub over_limit: usize,
+    //messages deleted to get under the size quota
+    pub over_quota: usize,
+    pub vacuumed: bool,
+}
+
+//The (max_age_days, max_messages) that apply to the conversation, None meaning no limit
+pub fn effective_limits(config: &RetentionConfig, conversation: &Conversation) -> (Option<i64>, Option<i64>) {
+    let days = conversation.retention_days.or(config.max_age_days).filter(|d| *d > 0);
+    let messages = conversation.retention_messages.or(config.max_messages).filter(|m| *m > 0);
+    (days, messages)
+}
+
+/*
+Sets the retention override of the conversation with peer (a user or device id).
+None follows the global policy, Some(0) keeps everything in this conversation.
+*/
+pub async fn set_conversation_policy(conn: &Connection, peer: &str, retention_days: Option<i64>, retention_messages: Option<i64>) -> Result<(), BoxError> {
+    if retention_days.is_some_and(|d| d < 0) || retention_messages.is_some_and(|m| m < 0) {
+        return Err(Box::from("Retention limits can't be negative"));
+    }
+    let peer_id = history::resolve_user(conn, peer).await?;
+    repository::ensure_user(conn, &peer_id).await?;
+    let conversation_id = repository::find_or_create_direct_conversation(conn, &peer_id).await?;
+    repository::set_conversation_retention(conn, conversation_id, retention_days, retention_messages).await
+}
+
+/*
+Applies the age and count limits to every conversation, then deletes the oldest messages
+across all conversations until the database is under max_db_bytes, then vacuums if enabled
+and worthwhile. Deleting for the quota stops early when a round doesn't make the database
+smaller or QUOTA_MAX_PER_RUN messages are gone, so a quota the file can't meet doesn't wipe
+the history.
+*/
+pub async fn run_maintenance(conn: &Connection, config: &RetentionConfig) -> Result<MaintenanceReport, BoxError> {
+    run_maintenance_capped(conn, config, QUOTA_MAX_PER_RUN).await
+}
+
+//run_maintenance, deleting at most quota_max_per_run messages for the quota
+pub async fn run_maintenance_capped(conn: &Connection, config: &RetentionConfig, quota_max_per_run: usize) -> Result<MaintenanceReport, BoxError> {
+    let mut report = MaintenanceReport::default();
+
+    for conversation in repository::list_conversations(conn).await? {
+        let (days, messages) = effective_limits(config, &conversation);
+        if let Some(days) = days {
+            let cutoff = unix_now() - days * SECS_PER_DAY;
+            report.too_old += repository::delete_messages_before(conn, conversation.conversation_id, cutoff).await?;
+        }
+        if let Some(messages) = messages {
+            report.over_limit += repository::delete_messages_beyond(conn, conversation.conversation_id, messages).await?;
+        }
+    }
+
+    if let Some(max_bytes) = config.max_db_bytes {
+        let mut size = repository::database_size(conn).await?;
+        while size > max_bytes && report.over_quota < quota_max_per_run {
+            let batch = QUOTA_BATCH.min((quota_max_per_run - report.over_quota) as i64);
+            let deleted = repository::delete_oldest_messages(conn, batch).await?;
+            //no messages left, the schema alone may be over a tiny quota
+            if deleted == 0 {
+                break;
+            }
+            report.over_quota += deleted;
+            //deleted index entries only give their pages back once the index is merged
+            repository::optimize_search_index(conn).await?;
+            let shrunk = repository::database_size(conn).await?;
+            //pages that stay in use whatever is deleted (schema, partly filled pages) must not cost the whole history
+            if shrunk >= size {
+                break;
+            }
+            size = shrunk;
+        }
+    }
+
+    if config.auto_vacuum {
+        let (free, total) = repository::free_pages(conn).await?;
+        if free > 0 && free * VACUUM_FREE_RATIO >= total {
+            repository::vacuum(conn).await?;
+            report.vacuumed = true;
+        }
+    }
+    Ok(report)
+}
+
+//Runs maintenance at the start of the session and then every MAINTENANCE_INTERVAL_SECS
+pub async fn maintenance_task(ctx: SessionContext, config: RetentionConfig) -> Result<(), BoxError> {
+    loop {
+        if let Err(e) = run_maintenance(&ctx.conn, &config).await {
+            ctx.notify(SessionEvent::Error(format!("Message retention maintenance failed: {}", e)));
+        }
+        tokio::time::sleep(Duration::from_secs(MAINTENANCE_INTERVAL_SECS)).await;
+    }
+}
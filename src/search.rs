@@ -0,0 +1,131 @@
+/**
+ * Full-text search over local message history.
+ * The index (messages_fts) is kept in sync by the repository's message functions;
+ * this module decides what goes into it, turns user input into an FTS5 query and cuts
+ * snippets from the decrypted matches.
+ *
+ * The index never holds words. Every word of a message, and every prefix of it from
+ * MIN_PREFIX characters, is stored as a keyed hash under the database key (see
+ * FieldCipher::index_token), and queries are hashed the same way. Without the key the
+ * index doesn't say what was written, but it still shows which messages share words
+ * and how often each hashed word occurs, which is what a search index is for.
+ */
+use std::collections::BTreeSet;
+use tokio_rusqlite::Connection;
+
+use crate::encryption::FieldCipher;
+use crate::repository;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+//characters of context kept on each side of the first match
+const SNIPPET_CONTEXT: usize = 30;
+//shortest prefix indexed, shorter search words only match whole words
+const MIN_PREFIX: usize = 3;
+//longest prefix indexed, longer search words match on their first MAX_PREFIX characters
+const MAX_PREFIX: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchResult {
+    pub message_id: i64,
+    pub conversation_id: i64,
+    pub sender_id: String,
+    pub created_at: String,
+    pub snippet: String,
+}
+
+//Words as the index sees them: lowercased runs of letters and digits
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+fn word_token(cipher: &FieldCipher, word: &str) -> String {
+    cipher.index_token(&format!("w:{}", word))
+}
+
+fn prefix_token(cipher: &FieldCipher, word: &str, len: usize) -> String {
+    cipher.index_token(&format!("p:{}", word.chars().take(len).collect::<String>()))
+}
+
+//What messages_fts holds for text: the hash of each distinct word and of its prefixes
+pub fn index_terms(cipher: &FieldCipher, text: &str) -> String {
+    let mut terms = BTreeSet::new();
+    for word in tokenize(text) {
+        let len = word.chars().count();
+        for prefix in MIN_PREFIX..len.min(MAX_PREFIX + 1) {
+            terms.insert(prefix_token(cipher, &word, prefix));
+        }
+        terms.insert(word_token(cipher, &word));
+    }
+    terms.into_iter().collect::<Vec<_>>().join(" ")
+}
+
+/*
+Every word must match. The last word also matches as a prefix once it is MIN_PREFIX
+characters long, so results show up while a word is still being typed.
+Returns None when there is nothing to search for.
+*/
+pub fn build_query(cipher: &FieldCipher, input: &str) -> Option<String> {
+    let words = tokenize(input);
+    let (last, rest) = words.split_last()?;
+    let mut terms: Vec<String> = rest.iter()
+        .map(|word| format!("\"{}\"", word_token(cipher, word)))
+        .collect();
+    let len = last.chars().count();
+    terms.push(if len >= MIN_PREFIX {
+        format!("(\"{}\" OR \"{}\")", word_token(cipher, last), prefix_token(cipher, last, len.min(MAX_PREFIX)))
+    } else {
+        format!("\"{}\"", word_token(cipher, last))
+    });
+    Some(terms.join(" AND "))
+}
+
+pub async fn search(conn: &Connection, cipher: &FieldCipher, input: &str, limit: i64) -> Result<Vec<SearchResult>, BoxError> {
+    let Some(query) = build_query(cipher, input) else {
+        return Ok(Vec::new());
+    };
+    let terms: Vec<&str> = input.split_whitespace().collect();
+
+    let results = repository::search_messages(conn, cipher, &query, limit).await?
+        .into_iter()
+        .map(|m| SearchResult {
+            snippet: snippet(&m.content, &terms),
+            message_id: m.message_id,
+            conversation_id: m.conversation_id,
+            sender_id: m.sender_id,
+            created_at: m.created_at,
+        })
+        .collect();
+    Ok(results)
+}
+
+//The text around the first occurrence of any term, with the match wrapped in [ ]
+pub fn snippet(content: &str, terms: &[&str]) -> String {
+    let content = content.replace('\n', " ");
+    let lower = content.to_lowercase();
+    let found = terms.iter()
+        .filter_map(|t| {
+            let t = t.trim_matches('"').to_lowercase();
+            lower.find(&t).map(|start| (start, t.len()))
+        })
+        .min_by_key(|(start, _)| *start);
+
+    //lowercasing can change byte lengths, only trust the match if it lines up with the original
+    let Some((start, len)) = found.filter(|(s, l)| content.is_char_boundary(*s) && content.is_char_boundary(s + l)) else {
+        return content.chars().take(SNIPPET_CONTEXT * 2).collect();
+    };
+
+    let before: String = content[..start].chars().rev().take(SNIPPET_CONTEXT).collect::<Vec<_>>().into_iter().rev().collect();
+    let after: String = content[start + len..].chars().take(SNIPPET_CONTEXT).collect();
+    format!(
+        "{}{}[{}]{}{}",
+        if before.len() < start { "..." } else { "" },
+        before,
+        &content[start..start + len],
+        after,
+        if start + len + after.len() < content.len() { "..." } else { "" },
+    )
+}
@@ -1,7 +1,377 @@
-use tokio_tungstenite::{tungstenite::protocol::Message, WebSocketStream};
-use futures_util::stream::{SplitSink, SplitStream};
-use tokio::net::TcpStream;
-use tokio::io::{AsyncWriteExt};
-use futures_util::{StreamExt};
+use tokio::io::{AsyncWriteExt, AsyncBufReadExt, BufReader};
 
 
+use crate::auth_cli;
+use crate::db;
+use crate::search;
+use crate::retention;
+use crate::archive;
+use crate::account_removal;
+use crate::prompt;
+use crate::messages;
+use crate::history;
+use crate::repository::{self, Cursor};
+use crate::session_manager::{self, SessionContext, SessionEvent};
+use tokio::sync::broadcast;
+use crate::transcript::{self, TranscriptFilter, TranscriptFormat};
+
+//max results printed by the search command
+const SEARCH_LIMIT: i64 = 20;
+
+/*
+Searches the user's local message history and prints one line per match:
+conversation, sender and time, followed by the snippet around the match.
+*/
+pub async fn search_command(username: &str, query: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (conn, cipher) = db::open(username).await?;
+
+    let results = search::search(&conn, &cipher, query, SEARCH_LIMIT).await?;
+    let mut stdout = tokio::io::stdout();
+    if results.is_empty() {
+        stdout.write_all(b"No messages found.\n").await?;
+    }
+    for result in results {
+        stdout.write_all(format!(
+            "[conversation {}] {} at {}\n    {}\n",
+            result.conversation_id, result.sender_id, result.created_at, result.snippet
+        ).as_bytes()).await?;
+    }
+    stdout.flush().await?;
+    Ok(())
+}
+
+/*
+Sets how long messages in the conversation with peer are kept. Each limit is a number
+(0 keeps everything) or "default" to follow the global policy in config.json.
+*/
+pub async fn retention_command(username: &str, peer: &str, days: &str, messages: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let parse = |value: &str| -> Result<Option<i64>, Box<dyn std::error::Error + Send + Sync>> {
+        match value {
+            "default" => Ok(None),
+            n => Ok(Some(n.parse()?)),
+        }
+    };
+    let (days, messages) = (parse(days)?, parse(messages)?);
+
+    let conn = db::connect(username).await?;
+    retention::set_conversation_policy(&conn, peer, days, messages).await?;
+    println!("Retention for {} set to {} days, {} messages", peer,
+        days.map_or("default".to_string(), |d| d.to_string()),
+        messages.map_or("default".to_string(), |m| m.to_string()));
+    Ok(())
+}
+
+//Writes the user's history to a passphrase-encrypted archive file
+pub async fn export_command(username: &str, file: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let passphrase = prompt::new_secret("Archive passphrase: ", "Repeat passphrase: ", 0).await?;
+
+    let (conn, cipher) = db::open(username).await?;
+    let contents = archive::export_archive(&conn, &cipher, username, std::path::Path::new(file), &passphrase).await?;
+    let messages: usize = contents.conversations.iter().map(|c| c.messages.len()).sum();
+    println!("Exported {} conversations and {} messages to {}", contents.conversations.len(), messages, file);
+    Ok(())
+}
+
+//Merges an archive written by export into the user's history
+pub async fn import_command(username: &str, file: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let passphrase = prompt::secret("Archive passphrase: ").await?;
+
+    let (conn, cipher) = db::open(username).await?;
+    let report = archive::import_archive(&conn, &cipher, std::path::Path::new(file), &passphrase).await?;
+    println!(
+        "Imported {} messages ({} already present, {} expired) and {} new contacts",
+        report.messages, report.duplicates, report.expired, report.contacts
+    );
+    Ok(())
+}
+
+/*
+Removes the account from this client after the user types the username to confirm.
+With --delete-server the account is deleted on the server as well, otherwise only this device is revoked.
+*/
+pub async fn remove_account_command(username: &str, options: &[String]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let delete_on_server = match options {
+        [] => false,
+        [flag] if flag == "--delete-server" => true,
+        _ => return Err(Box::from("Usage: remove-account <username> [--delete-server]")),
+    };
+
+    let scope = if delete_on_server { "on the server and on this device" } else { "on this device" };
+    println!("This permanently deletes all messages, keys and credentials of {} {}.", username, scope);
+    if prompt::line(&format!("Type {} to confirm: ", username)).await? != username {
+        println!("Nothing was removed");
+        return Ok(());
+    }
+
+    let report = account_removal::remove_account(username, delete_on_server).await?;
+    if report.deleted_on_server {
+        println!("Deleted the account on the server");
+    } else if !report.device_revoked {
+        println!(
+            "This device could not be revoked on the server ({}), it stays listed until its session expires",
+            report.revoke_error.as_deref().unwrap_or("unknown error"),
+        );
+    }
+    println!(
+        "Removed {} credentials{}{}",
+        report.credentials,
+        if report.data_removed { ", the local database" } else { "" },
+        if report.registry_removed { " and the account entry" } else { "" },
+    );
+    Ok(())
+}
+
+/*
+Writes a plaintext transcript of the user's history. options narrow it down:
+--conversation <id>, --since <YYYY-MM-DD> and --until <YYYY-MM-DD>.
+*/
+pub async fn transcript_command(username: &str, format: &str, file: &str, options: &[String]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let format: TranscriptFormat = format.parse()?;
+    let mut filter = TranscriptFilter::default();
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or(format!("Missing value for {}", option))?;
+        match option.as_str() {
+            "--conversation" => filter.conversation_id = Some(value.parse()?),
+            "--since" => filter.since = Some(value.clone()),
+            "--until" => filter.until = Some(value.clone()),
+            _ => return Err(Box::from(format!("Unknown option {}", option))),
+        }
+    }
+
+    let (conn, cipher) = db::open(username).await?;
+    let count = transcript::export_transcript(&conn, &cipher, &filter, format, std::path::Path::new(file)).await?;
+    println!("Wrote {} messages to {}. The file is not encrypted.", count, file);
+    Ok(())
+}
+
+/*
+INTERACTIVE CHAT SHELL
+Lines starting with '/' are commands, anything else is sent to the current recipient.
+Incoming messages are printed from the session's events as they arrive, see print_events.
+*/
+
+//messages shown by /history when no count is given
+const HISTORY_DEFAULT: i64 = 20;
+
+const SHELL_HELP: &str = "\
+Commands:
+  /to <user>        send the following lines to user
+  /list             conversations with unread counts
+  /history [count]  latest messages with the current recipient
+  /devices          devices of the current recipient, or yours without one
+  /sync <device>    copy history over from another of your devices
+  /approve <device> send your history to a device that asked, once both show the same code
+  /reject <device>  turn down a device's history request
+  /unlock           unlock the credential vault after it locked while idle
+  /help             this list
+  /quit             end the session
+Anything else is sent to the current recipient.";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShellCommand {
+    To(String),
+    List,
+    History(i64),
+    Devices,
+    Sync(String),
+    Approve(String),
+    Reject(String),
+    Unlock,
+    Help,
+    Quit,
+    Send(String),
+    Empty,
+    //a line starting with '/' that isn't a command, with the reason
+    Invalid(String),
+}
+
+pub fn parse_command(line: &str) -> ShellCommand {
+    let line = line.trim_end_matches(['\r', '\n']);
+    if line.trim().is_empty() {
+        return ShellCommand::Empty;
+    }
+    //"//text" sends "/text", for messages that start with a slash
+    if let Some(text) = line.strip_prefix("//") {
+        return ShellCommand::Send(format!("/{}", text));
+    }
+    let Some(command) = line.strip_prefix('/') else {
+        return ShellCommand::Send(line.to_string());
+    };
+
+    let mut words = command.split_whitespace();
+    let name = words.next().unwrap_or("");
+    let args: Vec<&str> = words.collect();
+    match (name, args.as_slice()) {
+        ("to", [user]) => ShellCommand::To(user.to_string()),
+        ("to", _) => ShellCommand::Invalid(String::from("Usage: /to <user>")),
+        ("list", []) => ShellCommand::List,
+        ("history", []) => ShellCommand::History(HISTORY_DEFAULT),
+        ("history", [count]) => match count.parse::<i64>() {
+            Ok(count) if count > 0 => ShellCommand::History(count),
+            _ => ShellCommand::Invalid(String::from("Usage: /history [count], count above 0")),
+        },
+        ("devices", []) => ShellCommand::Devices,
+        ("sync", [device]) => ShellCommand::Sync(device.to_string()),
+        ("sync", _) => ShellCommand::Invalid(String::from("Usage: /sync <device id>")),
+        ("approve", [device]) => ShellCommand::Approve(device.to_string()),
+        ("approve", _) => ShellCommand::Invalid(String::from("Usage: /approve <device id>")),
+        ("reject", [device]) => ShellCommand::Reject(device.to_string()),
+        ("reject", _) => ShellCommand::Invalid(String::from("Usage: /reject <device id>")),
+        ("unlock", []) => ShellCommand::Unlock,
+        ("help", _) => ShellCommand::Help,
+        ("quit", _) | ("exit", _) => ShellCommand::Quit,
+        _ => ShellCommand::Invalid(format!("Unknown command /{}, /help lists the commands", name)),
+    }
+}
+
+//Reads commands and messages from stdin until /quit or the end of input
+pub async fn chat_shell(ctx: SessionContext) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    println!("Logged in as {}. /to <user> picks who to write to, /help lists the commands.", ctx.username);
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut recipient: Option<String> = None;
+    let printer = tokio::spawn(print_events(ctx.events.subscribe()));
+
+    while let Some(line) = lines.next_line().await? {
+        let result = match parse_command(&line) {
+            ShellCommand::Empty => Ok(()),
+            ShellCommand::Quit => break,
+            ShellCommand::Help => {
+                println!("{}", SHELL_HELP);
+                Ok(())
+            }
+            ShellCommand::Invalid(reason) => {
+                eprintln!("{}", reason);
+                Ok(())
+            }
+            ShellCommand::To(user) => {
+                println!("Writing to {}", user);
+                recipient = Some(user);
+                Ok(())
+            }
+            ShellCommand::List => print_conversations(&ctx).await,
+            ShellCommand::History(count) => match &recipient {
+                Some(peer) => print_history(&ctx, peer, count).await,
+                None => Err(Box::from("Pick a recipient with /to <user> first")),
+            },
+            ShellCommand::Devices => {
+                let user = recipient.as_deref().unwrap_or(&ctx.username);
+                send_frame(&ctx, messages::get_devices(user).await?).await
+            }
+            ShellCommand::Sync(device) => {
+                let result = session_manager::request_history(&ctx, &device).await;
+                if result.is_ok() {
+                    println!("Asked device {} for its history", device);
+                }
+                result
+            }
+            ShellCommand::Approve(device) => session_manager::approve_transfer(&ctx, &device).await,
+            ShellCommand::Reject(device) => {
+                let result = session_manager::reject_transfer(&ctx, &device).await;
+                if result.is_ok() {
+                    println!("Turned down device {}'s history request", device);
+                }
+                result
+            }
+            ShellCommand::Unlock => {
+                let result = auth_cli::unlock_credentials().await;
+                if result.is_ok() {
+                    println!("Credentials are unlocked");
+                }
+                result
+            }
+            ShellCommand::Send(text) => match &recipient {
+                Some(peer) => send_frame(&ctx, messages::message(&ctx.username, peer, &text).await?).await,
+                None => Err(Box::from("Pick a recipient with /to <user> first")),
+            },
+        };
+        if let Err(e) = result {
+            eprintln!("{}", e);
+        }
+    }
+    printer.abort();
+    Ok(())
+}
+
+async fn print_events(mut events: broadcast::Receiver<SessionEvent>) {
+    loop {
+        match events.recv().await {
+            Ok(SessionEvent::Message { message, incoming: true }) => {
+                print_message(&message.sender_id, &message.created_at, &message.content);
+            }
+            Ok(SessionEvent::Message { incoming: false, .. }) => {}
+            Ok(SessionEvent::Devices { user_id, devices }) => print_devices(&user_id, &devices),
+            Ok(SessionEvent::Notice(notice)) => println!("{}", notice),
+            Ok(SessionEvent::TransferApproval { device, code }) => println!(
+                "Device {0} asks for this device's history. Verification code: {1}. If device {0} shows the same code, /approve {0}, otherwise /reject {0}.",
+                device, code
+            ),
+            Ok(SessionEvent::Error(e)) => eprintln!("{}", e),
+            Ok(SessionEvent::Disconnected { reason }) => {
+                match reason {
+                    Some(reason) => println!("Connection closed: {}", reason),
+                    None => println!("Connection closed"),
+                }
+                break;
+            }
+            Err(broadcast::error::RecvError::Lagged(missed)) => eprintln!("{} events were missed, /history shows everything stored", missed),
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+async fn send_frame(ctx: &SessionContext, frame: serde_json::Value) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    ctx.outgoing.send(frame.to_string()).await.map_err(|_| "The connection is closed")?;
+    Ok(())
+}
+
+async fn print_conversations(ctx: &SessionContext) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let summaries = repository::list_conversation_summaries(&ctx.conn, &ctx.cipher, &ctx.username).await?;
+    if summaries.is_empty() {
+        println!("No conversations yet");
+    }
+    for summary in summaries {
+        let unread = if summary.unread_count > 0 { format!(" ({} unread)", summary.unread_count) } else { String::new() };
+        let preview = summary.last_message.map(|m| history::preview(&m.content)).unwrap_or_default();
+        println!("{}{}: {}", summary.participants.join(", "), unread, preview);
+    }
+    Ok(())
+}
+
+//Prints the latest count messages with peer and marks them read
+async fn print_history(ctx: &SessionContext, peer: &str, count: i64) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let summaries = repository::list_conversation_summaries(&ctx.conn, &ctx.cipher, &ctx.username).await?;
+    let Some(conversation) = summaries.into_iter().find(|s| s.participants == [peer]) else {
+        println!("No messages with {} yet", peer);
+        return Ok(());
+    };
+
+    let page = repository::page_messages(&ctx.conn, &ctx.cipher, conversation.conversation_id, Cursor::Latest, count).await?;
+    for message in &page {
+        println!("{}", format_message(&message.sender_id, &message.created_at, &message.content));
+    }
+    if let Some(last) = page.last() {
+        repository::mark_read(&ctx.conn, conversation.conversation_id, last.message_id).await?;
+    }
+    Ok(())
+}
+
+//Shows a message that just arrived
+pub fn print_message(sender: &str, created_at: &str, content: &str) {
+    println!("{}", format_message(sender, created_at, content));
+}
+
+//Shows the devices the server reported for a user
+pub fn print_devices(user_id: &str, devices: &[String]) {
+    println!("Devices of {}: {}", user_id, if devices.is_empty() { String::from("none") } else { devices.join(", ") });
+}
+
+/*
+"[HH:MM] sender: content", with continuation lines indented under the first.
+created_at is SQLite's "YYYY-MM-DD HH:MM:SS" (UTC), anything else is shown as it is.
+*/
+pub fn format_message(sender: &str, created_at: &str, content: &str) -> String {
+    let time = created_at.get(11..16).filter(|_| created_at.len() >= 16).unwrap_or(created_at);
+    let indent = " ".repeat(time.len() + 3);
+    format!("[{}] {}: {}", time, sender, content.replace('\n', &format!("\n{}", indent)))
+}
@@ -3,80 +3,302 @@ use futures_util::stream::{SplitStream, SplitSink};
 use futures_util::{StreamExt, SinkExt};
 use tokio::io::{AsyncBufReadExt};
 use tokio::net::TcpStream;
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use crate::manage_keys::store_token;
+use tokio_rusqlite::Connection;
+
+use crate::auth_commands;
+use crate::db;
+use crate::encryption::FieldCipher;
+use crate::history;
+use crate::disappearing;
+use crate::retention;
+use crate::config;
+use crate::transfer;
+use crate::manage_keys;
+use crate::credential_store;
+use crate::messages;
+use crate::repository;
+use crate::session_cli;
+use crate::tui;
+
+//events a slow front end can fall behind by before it misses some
+const EVENT_CAPACITY: usize = 64;
+//how long frames still queued at the end of a session get to reach the relay
+const TX_DRAIN_SECS: u64 = 2;
+
+//What the session tells the front end about, so it can update without polling the database
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionEvent {
+    //a message was stored, incoming from a peer or sent by this device
+    Message { message: repository::Message, incoming: bool },
+    //the server's answer to a get_devices request
+    Devices { user_id: String, devices: Vec<String> },
+    //something the user should know about that isn't a message
+    Notice(String),
+    //another device of this account asks for the history, answered with approve_transfer or reject_transfer
+    TransferApproval { device: String, code: String },
+    //something went wrong that doesn't end the session
+    Error(String),
+    //the websocket closed, nothing more will arrive
+    Disconnected { reason: Option<String> },
+}
+
+//Which interface the session runs in the foreground
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Frontend {
+    Shell,
+    Tui,
+}
+
+//Per-session state shared by the session tasks
+#[derive(Clone)]
+pub struct SessionContext {
+    pub username: String,
+    pub conn: Connection,
+    pub cipher: FieldCipher,
+    //frames queued here are sent by tx_task
+    pub outgoing: mpsc::Sender<String>,
+    pub events: broadcast::Sender<SessionEvent>,
+    //history requests waiting for the user to compare verification codes, by requesting device
+    pub pending_transfers: Arc<Mutex<HashMap<String, serde_json::Value>>>,
+}
+
+impl SessionContext {
+    pub async fn open(username: &str, outgoing: mpsc::Sender<String>) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let (conn, cipher) = db::open(username).await?;
+        Ok(SessionContext {
+            username: username.to_string(),
+            conn,
+            cipher,
+            outgoing,
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            pending_transfers: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    //Sends an event to whichever front end is listening, there may be none
+    pub fn notify(&self, event: SessionEvent) {
+        let _ = self.events.send(event);
+    }
+}
 
 pub async fn session(
+    username: &str,
     tx: SplitSink<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>, Message>,
     rx: SplitStream<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>>,
+    frontend: Frontend,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let (msg_tx, msg_rx) = mpsc::channel::<String>(32);
+    let ctx = SessionContext::open(username, msg_tx.clone()).await?;
+    let config = config::load().await?;
 
     //Spawn a task for receiving messages
-    let rx_handle = tokio::spawn(rx_task(rx));
+    let mut rx_handle = tokio::spawn(rx_task(ctx.clone(), rx));
     // Spawn a task for sending messages
-    let tx_handle = tokio::spawn(tx_task(tx, msg_rx));
-    //Use main task to manage input
-
-    // Await both tasks and propagate any errors
-    let _rx_result = rx_handle.await?;
-    let _tx_result = tx_handle.await?;
+    let (tx_close, tx_close_rx) = oneshot::channel();
+    let mut tx_handle = tokio::spawn(tx_task(ctx.clone(), tx, msg_rx, tx_close_rx));
+    // Spawn a task that keeps the auth token fresh for the lifetime of the session
+    let refresh_handle = tokio::spawn(refresh_task(ctx.clone()));
+    // Spawn a task that deletes disappearing messages once they expire
+    let sweeper_handle = tokio::spawn(disappearing::sweeper_task(ctx.clone()));
+    // Spawn a task that applies the retention policy and storage quota
+    let maintenance_handle = tokio::spawn(retention::maintenance_task(ctx.clone(), config.retention));
+    //history requests are only served to devices on this list
+    msg_tx.send(messages::get_devices(username).await?.to_string()).await?;
+    // The main task runs the front end until the user quits
+    match frontend {
+        //the shell can't do anything useful offline, so it ends with the connection
+        Frontend::Shell => tokio::select! {
+            result = session_cli::chat_shell(ctx.clone()) => {
+                if let Err(e) = result {
+                    eprintln!("Input failed: {}", e);
+                }
+            }
+            _ = &mut rx_handle => {}
+        },
+        //the TUI stays up after a disconnect so history can still be read
+        Frontend::Tui => {
+            let result = tui::run(ctx.clone()).await;
+            if let Err(e) = result {
+                eprintln!("Terminal UI failed: {}", e);
+            }
+        }
+    }
+    rx_handle.abort();
+    refresh_handle.abort();
+    sweeper_handle.abort();
+    maintenance_handle.abort();
+    //frames queued just before quitting still go out, unless the relay stops taking them
+    let _ = tx_close.send(());
+    if tokio::time::timeout(Duration::from_secs(TX_DRAIN_SECS), &mut tx_handle).await.is_err() {
+        tx_handle.abort();
+    }
 
     Ok(())
 }
 
 async fn rx_task(
+    ctx: SessionContext,
     mut rx: SplitStream<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>>
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     
     while let Some(msg) = rx.next().await {
         match msg {
             Ok(Message::Text(text)) => {
-
-                println!("Received: {}", text);
-                process_message(&text.to_string()).await?;
-                
-            }
-            Ok(Message::Binary(_)) => {
-                println!("Received binary message");
+                //one bad frame shouldn't end the session
+                if let Err(e) = process_message(&ctx, text.as_ref()).await {
+                    ctx.notify(SessionEvent::Error(format!("Failed to process message: {}", e)));
+                }
             }
+            Ok(Message::Binary(_)) => {}
             Err(e) => {
-                eprintln!("Error receiving message: {}", e);
-                break;
+                ctx.notify(SessionEvent::Disconnected { reason: Some(e.to_string()) });
+                return Ok(());
             }
             _ => {}
         }
     }
+    ctx.notify(SessionEvent::Disconnected { reason: None });
     Ok(())
 }
 
 
+/*
+Sends queued frames until close fires, then sends whatever is still queued and stops.
+The session's own senders live as long as the session does, so the channel is closed from
+this end rather than by dropping them.
+*/
 async fn tx_task(
-    mut tx: SplitSink<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>, Message>, mut msg_rx: mpsc::Receiver<String>
+    ctx: SessionContext,
+    mut tx: SplitSink<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>, Message>, mut msg_rx: mpsc::Receiver<String>,
+    mut close: oneshot::Receiver<()>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut closing = false;
+    loop {
+        let msg = tokio::select! {
+            msg = msg_rx.recv() => msg,
+            _ = &mut close, if !closing => {
+                //recv hands out what is already queued, then returns None
+                msg_rx.close();
+                closing = true;
+                continue;
+            }
+        };
+        let Some(msg) = msg else {
+            break;
+        };
+        //attach the conversation's disappearing timer so the recipient enforces it too
+        let msg = match with_expiry(&ctx, &msg).await {
+            Ok(msg) => msg,
+            Err(e) => {
+                ctx.notify(SessionEvent::Error(format!("Not sending message, failed to apply its disappearing timer: {}", e)));
+                continue;
+            }
+        };
+        tx.send(Message::Text(msg.clone().into())).await?;
+        //only keep a copy once the relay has taken it
+        match store_sent(&ctx, &msg).await {
+            Ok(Some(message)) => ctx.notify(SessionEvent::Message { message, incoming: false }),
+            Ok(None) => {}
+            Err(e) => ctx.notify(SessionEvent::Error(format!("Failed to store sent message: {}", e))),
+        }
+    }
+
+    Ok(())
+}
 
-    while let Some(msg) = msg_rx.recv().await {
-        tx.send(Message::Text(msg.into())).await?;
+/*
+Sleeps until shortly before the token expires, then swaps it for a new one.
+Stops quietly when the expiry is unknown; if the refresh is rejected the next
+reconnect will fail with AuthFailed and the user is asked to log in again.
+A credential vault that locked while idle is waited for, see vault_unlocked.
+*/
+async fn refresh_task(ctx: SessionContext) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    loop {
+        vault_unlocked(&ctx).await?;
+        let Some(secs) = auth_commands::seconds_until_refresh(&ctx.username).await else {
+            break;
+        };
+        if secs > 0 {
+            tokio::time::sleep(Duration::from_secs(secs as u64)).await;
+            //the vault has most likely locked during the wait
+            continue;
+        }
+        if let Err(e) = auth_commands::refresh_token(&ctx.username).await {
+            ctx.notify(SessionEvent::Error(format!("Failed to refresh session token: {}", e)));
+            return Err(e);
+        }
+        //server didn't extend the expiry, so there is nothing left to schedule
+        if auth_commands::token_needs_refresh(&ctx.username).await {
+            break;
+        }
     }
+    Ok(())
+}
 
+//Asks the user to unlock the credential vault if it has locked, and waits until they do
+async fn vault_unlocked(ctx: &SessionContext) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let store = credential_store::current().await?;
+    if let Some(vault) = store.vault()
+        && vault.is_locked() {
+        ctx.notify(SessionEvent::Notice(String::from(
+            "The credential vault locked while idle. Unlock it (/unlock, or Ctrl+U in the terminal UI) so the session token can be refreshed before it expires"
+        )));
+        vault.unlocked().await;
+    }
     Ok(())
 }
 
-pub async fn process_message(msg: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+//Handles the auth message the server sends right after the websocket handshake, before a session exists
+pub async fn process_auth_message(msg: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let msg: serde_json::Value = serde_json::from_str(msg)?;
+
+    match msg.get("type").and_then(|v| v.as_str()) {
+        Some("auth") => auth_handler(msg).await,
+        _ => Err(Box::from("Expected auth message")),
+    }
+}
+
+pub async fn process_message(ctx: &SessionContext, msg: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let msg: serde_json::Value = serde_json::from_str(msg)?;
 
+    //the reply to a get_devices request has no type
+    if msg.get("type").is_none()
+        && let Some(devices) = msg.get("devices").and_then(|v| v.as_array()) {
+        let user_id = msg.get("user_id").and_then(json_id).unwrap_or_default();
+        let devices: Vec<String> = devices.iter().filter_map(json_id).collect();
+        if !user_id.is_empty() {
+            repository::record_device_list(&ctx.conn, &user_id, &devices).await?;
+        }
+        ctx.notify(SessionEvent::Devices { user_id, devices });
+        return Ok(());
+    }
+
     let msg_type = msg.get("type")
         .and_then(|v| v.as_str())
         .ok_or("Message type not found")?;
 
     match msg_type {
         "auth" => {
-            auth_handler(msg).await?;
+            //auth_handler prints, which is fine before a session but not once a front end owns the terminal
+            if msg.get("subtype").and_then(|v| v.as_str()) == Some("logout") {
+                ctx.notify(SessionEvent::Notice(String::from("The server logged this device out")));
+            } else {
+                auth_handler(msg).await?;
+            }
         }
         "message" => {
-            // Handle incoming message
-            println!("Received message: {:?}", msg);
+            message_handler(ctx, msg).await?;
+        }
+        "control" => {
+            control_handler(ctx, msg).await?;
+        }
+        "sync" => {
+            sync_handler(ctx, msg).await;
         }
         _ => {
             return Err(Box::from("Unknown message type"));
@@ -87,6 +309,139 @@ pub async fn process_message(msg: &str) -> Result<(), Box<dyn std::error::Error
     Ok(())
 }
 
+async fn message_handler(ctx: &SessionContext, msg: serde_json::Value) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let sender = msg.get("sender")
+        .and_then(json_id)
+        .ok_or("Sender not found")?;
+    let content = msg.get("content")
+        .and_then(|v| v.as_str())
+        .ok_or("Content not found")?;
+
+    let expires_in = msg.get("expires_in").and_then(|v| v.as_i64());
+
+    let (_, message_id) = history::store_incoming(&ctx.conn, &ctx.cipher, &sender, content, expires_in).await?;
+    if let Some(message) = repository::get_message(&ctx.conn, &ctx.cipher, message_id).await? {
+        ctx.notify(SessionEvent::Message { message, incoming: true });
+    }
+    Ok(())
+}
+
+async fn control_handler(ctx: &SessionContext, msg: serde_json::Value) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let subtype = msg.get("subtype")
+        .and_then(|v| v.as_str())
+        .ok_or("Control subtype not found")?;
+    let sender = msg.get("sender")
+        .and_then(json_id)
+        .ok_or("Sender not found")?;
+
+    match subtype {
+        "expiry" => {
+            let expiry_seconds = msg.get("expiry_seconds").and_then(|v| v.as_i64());
+            disappearing::apply_timer(&ctx.conn, &sender, expiry_seconds).await?;
+        }
+        _ => {
+            return Err(Box::from("Unknown control subtype"));
+        }
+    }
+    Ok(())
+}
+
+/*
+History transfer frames. Failures are reported but don't end the session,
+an interrupted transfer is picked up again by request_history.
+*/
+async fn sync_handler(ctx: &SessionContext, msg: serde_json::Value) {
+    let result = async {
+        let serve = config::load().await?.serve_history_sync;
+        let device_id = manage_keys::get_device_id(&ctx.username).await?;
+        let outcome = transfer::handle(&ctx.conn, &ctx.cipher, &ctx.username, &device_id, serve, &msg).await?;
+        send_outcome(ctx, outcome).await
+    }.await;
+    if let Err(e) = result {
+        ctx.notify(SessionEvent::Error(format!("History transfer failed: {}", e)));
+    }
+}
+
+//Sends a transfer step's frames and tells the front end what it needs to know
+async fn send_outcome(ctx: &SessionContext, outcome: transfer::SyncOutcome) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    for frame in outcome.frames {
+        ctx.outgoing.send(frame.to_string()).await?;
+    }
+    if let Some(notice) = outcome.notice {
+        ctx.notify(SessionEvent::Notice(notice));
+    }
+    if let Some(approval) = outcome.approval {
+        ctx.pending_transfers.lock().unwrap().insert(approval.device.clone(), approval.request);
+        ctx.notify(SessionEvent::TransferApproval { device: approval.device, code: approval.code });
+    }
+    Ok(())
+}
+
+//Sends the history to device, once the user has seen the same verification code on both devices
+pub async fn approve_transfer(ctx: &SessionContext, device: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let request = ctx.pending_transfers.lock().unwrap().remove(device)
+        .ok_or_else(|| format!("Device {} has no history request waiting", device))?;
+    let device_id = manage_keys::get_device_id(&ctx.username).await?;
+    let outcome = transfer::approve(&ctx.conn, &ctx.cipher, &ctx.username, &device_id, &request).await?;
+    send_outcome(ctx, outcome).await
+}
+
+//Turns down device's history request, it has to ask again
+pub async fn reject_transfer(ctx: &SessionContext, device: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let request = ctx.pending_transfers.lock().unwrap().remove(device)
+        .ok_or_else(|| format!("Device {} has no history request waiting", device))?;
+    transfer::reject(&ctx.conn, &request).await
+}
+
+//Asks old_device to send its history to this device, or resumes an interrupted transfer
+pub async fn request_history(ctx: &SessionContext, old_device: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let device_id = manage_keys::get_device_id(&ctx.username).await?;
+    let request = transfer::request_history(&ctx.conn, &ctx.cipher, &device_id, old_device).await?;
+    ctx.outgoing.send(request.to_string()).await?;
+    Ok(())
+}
+
+//Adds "expires_in" to an outgoing chat message when its conversation has a timer and the frame doesn't
+async fn with_expiry(ctx: &SessionContext, msg: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut frame: serde_json::Value = serde_json::from_str(msg)?;
+    if frame.get("type").and_then(|v| v.as_str()) != Some("message") || frame.get("expires_in").is_some() {
+        return Ok(msg.to_string());
+    }
+    let recipient = frame.get("recipient")
+        .and_then(json_id)
+        .ok_or("Recipient not found")?;
+
+    if let Some(expires_in) = disappearing::timer_for(&ctx.conn, &recipient).await? {
+        frame["expires_in"] = serde_json::json!(expires_in);
+    }
+    Ok(frame.to_string())
+}
+
+//Stores a frame written to the relay if it is a chat message and returns the stored copy, anything else is ignored
+async fn store_sent(ctx: &SessionContext, msg: &str) -> Result<Option<repository::Message>, Box<dyn std::error::Error + Send + Sync>> {
+    let msg: serde_json::Value = serde_json::from_str(msg)?;
+    if msg.get("type").and_then(|v| v.as_str()) != Some("message") {
+        return Ok(None);
+    }
+    let recipient = msg.get("recipient")
+        .and_then(json_id)
+        .ok_or("Recipient not found")?;
+    let content = msg.get("content")
+        .and_then(|v| v.as_str())
+        .ok_or("Content not found")?;
+
+    let expires_in = msg.get("expires_in").and_then(|v| v.as_i64());
+
+    let (_, message_id) = history::store_outgoing(&ctx.conn, &ctx.cipher, &ctx.username, &recipient, content, expires_in).await?;
+    repository::get_message(&ctx.conn, &ctx.cipher, message_id).await
+}
+
+//ids arrive as either JSON strings or numbers depending on the endpoint
+fn json_id(value: &serde_json::Value) -> Option<String> {
+    value.as_str().map(|s| s.to_string())
+        .or_else(|| value.as_i64().map(|n| n.to_string()))
+}
+
 async fn auth_handler(msg: serde_json::Value) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let subtype = msg.get("subtype")
         .and_then(|v| v.as_str())
@@ -111,15 +466,11 @@ async fn auth_handler(msg: serde_json::Value) -> Result<(), Box<dyn std::error::
 
 pub async fn auth_confirm(msg: serde_json::Value) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
-    let token = msg.get("token")
-        .and_then(|v| v.as_str())
-        .ok_or("Token not found")?;
-
     let user_id = msg.get("user_id")
         .and_then(|v| v.as_str())
         .ok_or("User ID not found")?;
 
-    store_token(token, user_id).await?;
+    auth_commands::store_session_token(user_id, &msg).await?;
 
     Ok(())
 }
@@ -1,10 +1,13 @@
 /**
  * Use only in development environment.
  * Will saturate server-side db with test data.
+ * Credentials go to the configured credential store, set "credential_store": "memory"
+ * in config.json to keep them out of the OS keyring.
  */
 use tokio_tungstenite::tungstenite::protocol::Message;
 use futures_util::{SinkExt, StreamExt};
 use tokio::fs;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
 use tokio_rusqlite;
 
 use crate::auth_commands;
@@ -12,19 +15,65 @@ use crate::manage_keys;
 use crate::messages;
 use crate::json_structures;
 use crate::db;
+use crate::accounts;
+use crate::paths;
+use crate::repository;
+use crate::encryption;
+use crate::history;
+use crate::search;
+use crate::disappearing;
+use crate::retention;
+use crate::config::RetentionConfig;
+use crate::archive;
+use crate::transcript::{self, TranscriptFilter, TranscriptFormat};
+use crate::transfer;
+use crate::credential_store::{self, CredentialStore, EncryptedFileStore, MemoryStore, StoreKind, VaultStore};
+use crate::config;
+use crate::account_removal;
+use crate::recovery;
+use crate::prompt;
+use crate::session_cli::{self, ShellCommand};
+use crate::tui::{self, Input};
+use crate::password_auth::{self, PasswordKeys};
+use crate::totp::{Totp, TotpAlgorithm};
+use crate::manage_keys::{KeyKind, PrivateKeyMaterial};
 
 
 pub async fn run_all_tests() {
 
     create_db().await.unwrap();
+    migrate_fixtures_test().await;
+    encrypt_existing_test().await;
 
     new_account_valid().await;
     add_necessary_accounts().await;
     store_uuid_test().await;
     delete_credential_test().await;
+    credential_store_test().await;
+    credential_vault_test().await;
+    private_key_test().await;
+    wipe_local_test().await;
+    recovery_codes_test().await;
+    totp_test().await;
+    password_auth_test().await;
+    prompt_validation_test().await;
+    chat_shell_test().await;
+    tui_test().await;
+    token_expiry_test().await;
+    account_registry_test().await;
     send_message().await;
     get_devices().await;
+    store_user().await;
     store_devices().await;
+    new_conversation().await;
+    persist_messages_test().await;
+    history_paging_test().await;
+    search_test().await;
+    disappearing_test().await;
+    retention_test().await;
+    archive_test().await;
+    transcript_test().await;
+    history_transfer_test().await;
 }
 /*
 AUTH COMMANDS TESTS
@@ -51,7 +100,7 @@ pub async fn create_db() -> Result<(), Box<dyn std::error::Error>> {
             let name: String = row.get(0)?;
             table_names.push(name);
         }
-        Ok(table_names)
+        Ok::<_, tokio_rusqlite::Error>(table_names)
     }).await.unwrap();
 
     assert!(conn.contains(&"users".to_string()), "Users table not found");
@@ -62,10 +111,88 @@ pub async fn create_db() -> Result<(), Box<dyn std::error::Error>> {
 
     db.close().await.unwrap();
 
-    fs::remove_file("testing.database").await?;
+    //opening an existing database again must not try to recreate the schema
+    let db = db::initialize_db("testing").await.unwrap();
+    assert_eq!(db::schema_version(&db).await.unwrap(), db::SCHEMA_VERSION);
+    db.close().await.unwrap();
+
+    fs::remove_dir_all(paths::account_dir("testing").await.unwrap()).await?;
     
     Ok(())
 }
+//Upgrades copies of the checked-in databases, which predate migrations (user_version 0)
+pub async fn migrate_fixtures_test() {
+    for fixture in ["example.database", "test.database"] {
+        let source = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join(fixture);
+        let copy = std::env::temp_dir().join(format!("migrate_{}", fixture));
+        fs::copy(&source, &copy).await.unwrap();
+
+        let conn = tokio_rusqlite::Connection::open(&copy).await.unwrap();
+        assert_eq!(db::schema_version(&conn).await.unwrap(), 0, "{} is not a pre-migration fixture", fixture);
+        //a device row in the original one-device-per-user layout has to survive the devices rework
+        conn.call(|call| {
+            call.execute("INSERT INTO users (user_id, email) VALUES ('fixture_user', 'fixture@example.com')", [])?;
+            call.execute("INSERT INTO devices (device_id, user_id, shared_key, msg_sequence_num) VALUES (7, 'fixture_user', 'key', 3)", [])?;
+            Ok::<(), tokio_rusqlite::Error>(())
+        }).await.unwrap();
+        assert_eq!(db::migrate(&conn).await.unwrap(), db::SCHEMA_VERSION);
+        let migrated = conn.call(|call| {
+            let row = call.query_row(
+                "SELECT user_id, msg_sequence_num, verified, revoked FROM devices WHERE device_id = 7", [],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?, row.get::<_, bool>(2)?, row.get::<_, bool>(3)?)),
+            )?;
+            Ok::<_, tokio_rusqlite::Error>(row)
+        }).await.unwrap();
+        assert_eq!(migrated, ("fixture_user".to_string(), 3, false, false), "device row not migrated in {}", fixture);
+        //the plaintext shared key went with its column
+        let columns = conn.call(|call| {
+            let mut stmt = call.prepare("SELECT name FROM pragma_table_info('devices')")?;
+            let columns = stmt.query_map([], |row| row.get::<_, String>(0))?.collect::<Result<Vec<_>, _>>()?;
+            Ok::<_, tokio_rusqlite::Error>(columns)
+        }).await.unwrap();
+        assert!(!columns.iter().any(|c| c == "shared_key"), "shared_key still in {}", fixture);
+        //a second run is a no-op
+        assert_eq!(db::migrate(&conn).await.unwrap(), db::SCHEMA_VERSION);
+        assert_eq!(db::schema_version(&conn).await.unwrap(), db::SCHEMA_VERSION);
+        conn.close().await.unwrap();
+
+        fs::remove_file(&copy).await.unwrap();
+    }
+    println!("Migrate Fixtures Test Passed");
+}
+//Plaintext rows written before encryption at rest must be encrypted in place and still decrypt
+pub async fn encrypt_existing_test() {
+    let source = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("example.database");
+    let copy = std::env::temp_dir().join("encrypt_example.database");
+    fs::copy(&source, &copy).await.unwrap();
+
+    let conn = tokio_rusqlite::Connection::open(&copy).await.unwrap();
+    conn.call(|call| {
+        call.execute("INSERT INTO users (user_id, email) VALUES ('fixture_user', 'fixture@example.com')", [])?;
+        call.execute("INSERT INTO conversations (conversation_id) VALUES (1)", [])?;
+        call.execute("INSERT INTO messages (conversation_id, sender_id, content) VALUES (1, 'fixture_user', 'legacy plaintext')", [])?;
+        Ok::<(), tokio_rusqlite::Error>(())
+    }).await.unwrap();
+    db::migrate(&conn).await.unwrap();
+
+    let cipher = encryption::FieldCipher::new(&[7u8; 32]);
+    assert_eq!(encryption::encrypt_existing(&conn, &cipher).await.unwrap(), 1);
+    //already encrypted rows are left alone
+    assert_eq!(encryption::encrypt_existing(&conn, &cipher).await.unwrap(), 0);
+
+    let raw = conn.call(|call| {
+        let content: String = call.query_row("SELECT content FROM messages", [], |row| row.get(0))?;
+        Ok::<String, tokio_rusqlite::Error>(content)
+    }).await.unwrap();
+    assert!(encryption::is_encrypted(&raw) && !raw.contains("legacy plaintext"), "Content still stored in plaintext");
+    assert_eq!(repository::list_messages(&conn, &cipher, 1).await.unwrap()[0].content, "legacy plaintext");
+    //a different key must not be able to read it
+    assert!(encryption::FieldCipher::new(&[8u8; 32]).decrypt(&raw).is_err());
+
+    conn.close().await.unwrap();
+    fs::remove_file(&copy).await.unwrap();
+    println!("Encrypt Existing Test Passed");
+}
 pub async fn new_account_valid() {
     if manage_keys::get_uuid("test").await.is_ok() {
         //Account can only exist in this environment if this test has previously run and passed
@@ -86,7 +213,7 @@ pub async fn add_necessary_accounts() {
 /*
 pub async fn auth_new_device() {
 
-    let result = auth_commands::login_new("test", "password123").await;
+    let result = auth_commands::login_new("test", "password123", None).await;
     assert!(result.is_ok(), "auth_new_device failed: {:?}", result.err());
 
     println!("auth_new_device test passed");
@@ -111,14 +238,48 @@ pub async fn delete_credential_test() {
 
     println!("Delete Credential Test Passed");
 }
+pub async fn token_expiry_test() {
+    let username = "test_expiry";
+    let resp = serde_json::json!({ "token": "expiring-token", "expires_in": 30 });
+    auth_commands::store_session_token(username, &resp).await.unwrap();
+
+    //30 seconds left is inside the refresh margin, so the token should be refreshed now
+    assert!(auth_commands::token_needs_refresh(username).await, "token_expiry_test: token not flagged for refresh");
+    assert_eq!(manage_keys::get_token(username).await.unwrap(), "expiring-token");
+
+    manage_keys::delete_credential(username, "e_to_e_msgr_token").await.unwrap();
+    manage_keys::delete_credential(username, "e_to_e_msgr_token_expiry").await.unwrap();
+    println!("Token Expiry Test Passed");
+}
+/*
+ACCOUNT REGISTRY TESTS
+*/
+pub async fn account_registry_test() {
+    accounts::add_account("registry_a", "localhost:3000", Some("1")).await.unwrap();
+    accounts::add_account("registry_b", "localhost:3000", None).await.unwrap();
+    //adding again must update in place rather than duplicate
+    accounts::add_account("registry_b", "localhost:3000", Some("2")).await.unwrap();
+
+    let listed = accounts::list_accounts().await.unwrap();
+    assert_eq!(listed.iter().filter(|a| a.username == "registry_b").count(), 1, "account_registry_test: duplicate entry");
+    assert_eq!(accounts::get_account("registry_b").await.unwrap().unwrap().device_id.as_deref(), Some("2"));
+
+    accounts::set_default("registry_b").await.unwrap();
+    assert_eq!(accounts::get_default().await.unwrap().unwrap().username, "registry_b");
+
+    assert!(accounts::remove_account("registry_a").await.unwrap());
+    assert!(accounts::remove_account("registry_b").await.unwrap());
+    assert!(accounts::get_account("registry_b").await.unwrap().is_none());
+    println!("Account Registry Test Passed");
+}
 /*
 MESSAGE TESTS
 */
 
 //should result in the message being received by the server and echoed back to this client
 pub async fn send_message() {
-    let (mut _tx1, mut rx1) = auth_commands::login_existing("example").await.unwrap();
-    let (mut tx, mut _rx) = auth_commands::login_existing("test").await.unwrap();
+    let (mut _tx1, mut rx1, _) = auth_commands::login_existing("example").await.unwrap();
+    let (mut tx, mut _rx, _) = auth_commands::login_existing("test").await.unwrap();
     
     let message = messages::message("test", &manage_keys::get_device_id("example").await.unwrap(), "Hello, world!").await.unwrap();
     
@@ -135,7 +296,7 @@ pub async fn send_message() {
 }
 
 pub async fn get_devices() {
-    let (mut tx, mut rx) = auth_commands::login_existing("test").await.unwrap();
+    let (mut tx, mut rx, _) = auth_commands::login_existing("test").await.unwrap();
 
     let message = messages::get_devices("test").await.unwrap();
     tx.send(Message::Text(message.to_string().into())).await.unwrap();
@@ -158,47 +319,825 @@ pub async fn store_user() {
     let conn = db::connect("test").await.unwrap();
     let user_id = "test_user";
     let email = "someone@example.com";
-    conn.call(move |call| {
-        call.execute(
-            "INSERT OR IGNORE INTO users (user_id, email) VALUES (?1, ?2)",
-            &[&user_id, &email],
-        ).map_err(tokio_rusqlite::Error::from)?;
-        Ok(())
-    }).await.unwrap();
+    repository::upsert_user(&conn, user_id, email).await.unwrap();
 
-    let exists = conn.call(move |call| {
-        let mut stmt = call.prepare("SELECT COUNT(*) FROM users WHERE user_id = ?1")?;
-        let mut rows = stmt.query([&user_id])?;
-        if let Some(row) = rows.next()? {
-            let count: i32 = row.get(0)?;
-            Ok(count > 0)
-        } else {
-            Ok(false)
-        }
-    }).await.unwrap();
+    let stored = repository::get_user(&conn, user_id).await.unwrap();
 
-    assert!(exists, "User was not stored correctly in the database");
+    assert!(stored.is_some_and(|u| u.email == email), "User was not stored correctly in the database");
 
     println!("Store user test passed");
 }
 pub async fn store_devices() {
     let conn = db::connect("test").await.unwrap();
     let user_id = "test_user";
-    let shared_key = "shared_key_example";
-    let device_id = 1;
-    let msg_sequence_num = 0;
+    let device = repository::Device {
+        device_id: 1,
+        user_id: user_id.to_string(),
+        identity_key: None,
+        msg_sequence_num: 0,
+        verified: false,
+        last_seen: None,
+        revoked: false,
+    };
+
+    repository::upsert_device(&conn, &device).await.unwrap();
+    //a second device for the same user
+    repository::upsert_device(&conn, &repository::Device { device_id: 2, ..device.clone() }).await.unwrap();
+
+    let devices = repository::list_devices(&conn, user_id).await.unwrap();
+    assert_eq!(devices.len(), 2, "Both devices for the user should be stored");
+    assert_eq!(devices[0], device);
+
+    println!("Store devices test passed");
+}
+pub async fn new_conversation() {
+    let conn = db::connect("test").await.unwrap();
+    let cipher = encryption::load_cipher("test").await.unwrap();
+    repository::upsert_user(&conn, "test_user", "someone@example.com").await.unwrap();
+
+    let conversation_id = repository::insert_conversation(&conn).await.unwrap();
+    repository::add_participant(&conn, conversation_id, "test_user").await.unwrap();
+    let message_id = repository::insert_message(&conn, &cipher, conversation_id, "test_user", "first", None).await.unwrap();
+
+    assert_eq!(repository::list_participants(&conn, conversation_id).await.unwrap(), vec!["test_user".to_string()]);
+    assert_eq!(repository::get_message(&conn, &cipher, message_id).await.unwrap().unwrap().content, "first");
+    assert_eq!(repository::list_messages(&conn, &cipher, conversation_id).await.unwrap().len(), 1);
+
+    assert!(repository::delete_conversation(&conn, conversation_id).await.unwrap());
+    assert!(repository::get_message(&conn, &cipher, message_id).await.unwrap().is_none(), "Messages should be removed with their conversation");
 
+    println!("New conversation test passed");
+}
+
+
+//Incoming and outgoing messages with the same contact (on any of their devices) share one conversation
+pub async fn persist_messages_test() {
+    let conn = db::connect("test").await.unwrap();
+    let cipher = encryption::load_cipher("test").await.unwrap();
+    repository::upsert_user(&conn, "history_peer", "peer@example.com").await.unwrap();
+    repository::upsert_device(&conn, &repository::Device {
+        device_id: 4242,
+        user_id: "history_peer".to_string(),
+        identity_key: None,
+        msg_sequence_num: 0,
+        verified: false,
+        last_seen: None,
+        revoked: false,
+    }).await.unwrap();
+
+    let (conversation_id, _) = history::store_incoming(&conn, &cipher, "4242", "hi from the peer's device", None).await.unwrap();
+    let (outgoing_conversation, _) = history::store_outgoing(&conn, &cipher, "test", "history_peer", "hi back", None).await.unwrap();
+    assert_eq!(conversation_id, outgoing_conversation, "Replies should land in the existing conversation");
+
+    //history survives closing the connection
+    conn.close().await.unwrap();
+    let conn = db::connect("test").await.unwrap();
+    let messages = repository::list_messages(&conn, &cipher, conversation_id).await.unwrap();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0].sender_id, "history_peer");
+    assert_eq!(messages[1].sender_id, "test");
+    assert_eq!(repository::list_participants(&conn, conversation_id).await.unwrap(), vec!["history_peer".to_string()]);
+
+    repository::delete_conversation(&conn, conversation_id).await.unwrap();
+    println!("Persist messages test passed");
+}
+pub async fn history_paging_test() {
+    let conn = db::connect("test").await.unwrap();
+    let cipher = encryption::load_cipher("test").await.unwrap();
+
+    let mut ids = Vec::new();
+    for i in 0..5 {
+        let (_, message_id) = history::store_incoming(&conn, &cipher, "paging_peer", &format!("message {}", i), None).await.unwrap();
+        ids.push(message_id);
+    }
+    let (conversation_id, _) = history::store_outgoing(&conn, &cipher, "test", "paging_peer", "my reply", None).await.unwrap();
+
+    let latest = repository::page_messages(&conn, &cipher, conversation_id, repository::Cursor::Latest, 2).await.unwrap();
+    assert_eq!(latest.iter().map(|m| m.content.as_str()).collect::<Vec<_>>(), vec!["message 4", "my reply"]);
+    let older = repository::page_messages(&conn, &cipher, conversation_id, repository::Cursor::Before(latest[0].message_id), 3).await.unwrap();
+    assert_eq!(older.iter().map(|m| m.message_id).collect::<Vec<_>>(), ids[1..4].to_vec());
+    let newer = repository::page_messages(&conn, &cipher, conversation_id, repository::Cursor::After(ids[3]), 10).await.unwrap();
+    assert_eq!(newer.len(), 2);
+
+    //our own reply doesn't count as unread, the five incoming messages do until marked read
+    let summary = repository::list_conversation_summaries(&conn, &cipher, "test").await.unwrap()
+        .into_iter().find(|c| c.conversation_id == conversation_id).unwrap();
+    assert_eq!(summary.unread_count, 5);
+    assert_eq!(summary.participants, vec!["paging_peer".to_string()]);
+    assert_eq!(summary.last_message.unwrap().content, "my reply");
+
+    repository::mark_read(&conn, conversation_id, ids[2]).await.unwrap();
+    let summary = repository::list_conversation_summaries(&conn, &cipher, "test").await.unwrap()
+        .into_iter().find(|c| c.conversation_id == conversation_id).unwrap();
+    assert_eq!(summary.unread_count, 2);
+
+    assert_eq!(history::preview("short"), "short");
+    assert_eq!(history::preview("first line\nsecond line"), "first line...");
+
+    repository::delete_conversation(&conn, conversation_id).await.unwrap();
+    println!("History paging test passed");
+}
+pub async fn search_test() {
+    let conn = db::connect("test").await.unwrap();
+    let cipher = encryption::load_cipher("test").await.unwrap();
+
+    let (conversation_id, lunch) = history::store_incoming(&conn, &cipher, "search_peer", "Shall we get lunch on Friday?", None).await.unwrap();
+    let (_, other) = history::store_incoming(&conn, &cipher, "search_peer", "the report is due monday", None).await.unwrap();
+
+    let results = search::search(&conn, &cipher, "lunch friday", 10).await.unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].message_id, lunch);
+    assert_eq!(results[0].conversation_id, conversation_id);
+    assert_eq!(results[0].sender_id, "search_peer");
+    assert_eq!(results[0].snippet, "Shall we get [lunch] on Friday?");
+    //last word matches as a prefix, and query syntax in the input is treated as text
+    assert_eq!(search::search(&conn, &cipher, "repo", 10).await.unwrap()[0].message_id, other);
+    assert!(search::search(&conn, &cipher, "\"unbalanced OR", 10).await.is_ok());
+    //whole words and prefixes shorter than a word, but not words that merely start the same
+    assert_eq!(search::search(&conn, &cipher, "FRIDAY", 10).await.unwrap().len(), 1);
+    assert!(search::search(&conn, &cipher, "fridays", 10).await.unwrap().is_empty());
+    assert!(search::search(&conn, &cipher, "re", 10).await.unwrap().is_empty());
+
+    //the index holds hashes, the words can't be read from the database file
+    let leaked = conn.call(|call| {
+        let leaked: i64 = call.query_row(
+            "SELECT COUNT(*) FROM messages_fts_data WHERE instr(block, CAST('lunch' AS BLOB)) > 0", [], |row| row.get(0),
+        )?;
+        Ok::<_, tokio_rusqlite::rusqlite::Error>(leaked)
+    }).await.unwrap();
+    assert_eq!(leaked, 0);
+    assert_eq!(search::index_terms(&cipher, "Lunch lunch"), search::index_terms(&cipher, "lunch"));
+    assert_ne!(search::index_terms(&cipher, "lunch"), search::index_terms(&encryption::FieldCipher::new(&[3; 32]), "lunch"));
+
+    //edits and deletes keep the index in sync
+    repository::update_message_content(&conn, &cipher, other, "the invoice is due monday").await.unwrap();
+    assert!(search::search(&conn, &cipher, "report", 10).await.unwrap().is_empty());
+    assert_eq!(search::search(&conn, &cipher, "invoice", 10).await.unwrap().len(), 1);
+    repository::delete_message(&conn, lunch).await.unwrap();
+    assert!(search::search(&conn, &cipher, "lunch", 10).await.unwrap().is_empty());
+
+    repository::delete_conversation(&conn, conversation_id).await.unwrap();
+    assert!(search::search(&conn, &cipher, "invoice", 10).await.unwrap().is_empty());
+    println!("Search test passed");
+}
+pub async fn disappearing_test() {
+    let conn = db::connect("test").await.unwrap();
+    let cipher = encryption::load_cipher("test").await.unwrap();
+
+    //a timer set locally produces the control message for the peer and applies to new messages
+    let control = disappearing::set_timer(&conn, "test", "expiry_peer", Some(3600)).await.unwrap();
+    assert_eq!(control["type"], "control");
+    assert_eq!(control["subtype"], "expiry");
+    assert_eq!(control["expiry_seconds"], 3600);
+    assert_eq!(disappearing::timer_for(&conn, "expiry_peer").await.unwrap(), Some(3600));
+
+    let (conversation_id, kept) = history::store_outgoing(&conn, &cipher, "test", "expiry_peer", "see you soon", None).await.unwrap();
+    let expires_at = repository::get_message(&conn, &cipher, kept).await.unwrap().unwrap().expires_at.unwrap();
+    assert!(expires_at > crate::clock::unix_now() + 3500);
+
+    //a shorter timer on the message wins, and expired messages leave history and the search index
+    let (_, ignored) = history::store_incoming(&conn, &cipher, "expiry_peer", "no timer here", Some(-1)).await.unwrap();
+    assert!(repository::get_message(&conn, &cipher, ignored).await.unwrap().unwrap().expires_at.is_some());
+    let (_, gone) = history::store_incoming(&conn, &cipher, "expiry_peer", "vanishing note", Some(1)).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+    assert!(disappearing::sweep(&conn).await.unwrap() >= 1);
+    assert!(repository::get_message(&conn, &cipher, gone).await.unwrap().is_none());
+    assert!(repository::get_message(&conn, &cipher, kept).await.unwrap().is_some());
+    assert!(search::search(&conn, &cipher, "vanishing", 10).await.unwrap().is_empty());
+
+    //the peer turning the timer off is adopted
+    assert_eq!(disappearing::apply_timer(&conn, "expiry_peer", None).await.unwrap(), None);
+    assert_eq!(disappearing::timer_for(&conn, "expiry_peer").await.unwrap(), None);
+
+    repository::delete_conversation(&conn, conversation_id).await.unwrap();
+    println!("Disappearing messages test passed");
+}
+pub async fn retention_test() {
+    //a database of its own, so the counts below only ever see this test's messages
+    let _ = fs::remove_file(paths::user_db_path("retention_test").await.unwrap()).await;
+    let conn = db::connect("retention_test").await.unwrap();
+    let cipher = encryption::load_cipher("retention_test").await.unwrap();
+
+    let mut ids = Vec::new();
+    for i in 0..5 {
+        let (conversation_id, message_id) = history::store_incoming(&conn, &cipher, "retention_peer", &format!("retained {}", i), None).await.unwrap();
+        ids.push((conversation_id, message_id));
+    }
+    let conversation_id = ids[0].0;
+    //backdate the first message past the age limit
+    let old = ids[0].1;
     conn.call(move |call| {
-        call.execute(
-            "INSERT OR IGNORE INTO devices (device_id, user_id, shared_key, msg_sequence_num) VALUES (?1, ?2, ?3, ?4)",
-            &[&device_id, &user_id, &shared_key, &msg_sequence_num],
-        ).map_err(tokio_rusqlite::Error::from)?;
-        Ok(())
+        call.execute("UPDATE messages SET created_at = datetime('now', '-10 days') WHERE message_id = ?1", [old])?;
+        Ok::<(), tokio_rusqlite::Error>(())
     }).await.unwrap();
 
+    let config = RetentionConfig { max_age_days: Some(7), max_messages: Some(3), ..Default::default() };
+    let report = retention::run_maintenance(&conn, &config).await.unwrap();
+    assert_eq!(report, retention::MaintenanceReport { too_old: 1, over_limit: 1, ..Default::default() });
+    let kept: Vec<i64> = repository::list_messages(&conn, &cipher, conversation_id).await.unwrap()
+        .iter().map(|m| m.message_id).collect();
+    assert_eq!(kept, vec![ids[2].1, ids[3].1, ids[4].1]);
+    assert!(search::search(&conn, &cipher, "retained 1", 10).await.unwrap().is_empty());
+
+    //a per-conversation override of 0 keeps everything despite the global limit
+    retention::set_conversation_policy(&conn, "retention_peer", None, Some(0)).await.unwrap();
+    history::store_incoming(&conn, &cipher, "retention_peer", "retained 5", None).await.unwrap();
+    assert_eq!(retention::run_maintenance(&conn, &config).await.unwrap(), retention::MaintenanceReport::default());
+    assert_eq!(repository::list_messages(&conn, &cipher, conversation_id).await.unwrap().len(), 4);
+    assert!(retention::set_conversation_policy(&conn, "retention_peer", Some(-1), None).await.is_err());
+
+    //a quota below the size of the schema deletes every message but stops there, then vacuums
+    //once enough pages are free
+    let config = RetentionConfig { max_db_bytes: Some(1), auto_vacuum: true, ..Default::default() };
+    let report = retention::run_maintenance(&conn, &config).await.unwrap();
+    assert_eq!(report.over_quota, 4);
+    assert!(repository::list_messages(&conn, &cipher, conversation_id).await.unwrap().is_empty());
+    let (free, total) = repository::free_pages(&conn).await.unwrap();
+    assert!(if report.vacuumed { free == 0 } else { free * 4 < total });
+
+    //one run never deletes more than its cap, however far over the quota the database is,
+    //and the next run carries on
+    for i in 0..300 {
+        let content = format!("quota {} {}", i, "x".repeat(1000));
+        history::store_incoming(&conn, &cipher, "retention_peer", &content, None).await.unwrap();
+    }
+    let report = retention::run_maintenance_capped(&conn, &config, 250).await.unwrap();
+    assert_eq!(report.over_quota, 250);
+    assert_eq!(repository::list_messages(&conn, &cipher, conversation_id).await.unwrap().len(), 50);
+    let report = retention::run_maintenance_capped(&conn, &config, 250).await.unwrap();
+    assert_eq!(report.over_quota, 50);
+    assert!(repository::list_messages(&conn, &cipher, conversation_id).await.unwrap().is_empty());
+
+    repository::delete_conversation(&conn, conversation_id).await.unwrap();
+    println!("Retention test passed");
 }
-pub async fn new_conversation() {
+pub async fn archive_test() {
+    let conn = db::connect("test").await.unwrap();
+    let cipher = encryption::load_cipher("test").await.unwrap();
 
+    repository::upsert_user(&conn, "archive_peer", "peer@example.com").await.unwrap();
+    let (conversation_id, _) = history::store_incoming(&conn, &cipher, "archive_peer", "archived hello", None).await.unwrap();
+    let (_, reply) = history::store_outgoing(&conn, &cipher, "test", "archive_peer", "archived reply", None).await.unwrap();
+    repository::ensure_user(&conn, "archive_other").await.unwrap();
+    let group = repository::find_or_create_conversation_with(&conn, &["archive_peer".to_string(), "archive_other".to_string()]).await.unwrap();
+    repository::insert_message(&conn, &cipher, group, "archive_other", "group message", None).await.unwrap();
+
+    let path = paths::data_dir().await.unwrap().join("test_archive.bin");
+    //an export over a file others can read leaves it owner-only, whatever temporary file a crash left
+    let tmp = path.with_file_name("test_archive.bin.tmp");
+    fs::write(&path, b"old").await.unwrap();
+    fs::write(&tmp, b"stale").await.unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).await.unwrap();
+        fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o644)).await.unwrap();
+    }
+    archive::export_archive(&conn, &cipher, "test", &path, "correct horse").await.unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(&path).await.unwrap().permissions().mode() & 0o777, 0o600);
+    }
+    //the new file was renamed into place
+    assert!(fs::metadata(&tmp).await.is_err());
+    let data = fs::read(&path).await.unwrap();
+    assert!(!String::from_utf8_lossy(&data).contains("archived hello"));
+    assert!(archive::unseal(&data, "wrong horse").is_err());
+    let mut tampered = data.clone();
+    tampered[9] ^= 1;
+    assert!(archive::unseal(&tampered, "correct horse").is_err());
+
+    //importing into the database it came from adds nothing, a deleted message comes back
+    repository::delete_message(&conn, reply).await.unwrap();
+    let report = archive::import_archive(&conn, &cipher, &path, "correct horse").await.unwrap();
+    assert_eq!(report.messages, 1);
+    assert!(report.duplicates >= 2);
+    let restored = repository::list_messages(&conn, &cipher, conversation_id).await.unwrap();
+    assert_eq!(restored.len(), 2);
+    assert_eq!(restored[1].content, "archived reply");
+    assert_eq!(repository::find_or_create_conversation_with(&conn, &["archive_other".to_string(), "archive_peer".to_string()]).await.unwrap(), group);
+
+    //a fresh database gets the contacts, conversations and messages
+    let other_conn = db::connect("archive_import").await.unwrap();
+    let other_cipher = encryption::load_cipher("archive_import").await.unwrap();
+    let report = archive::import_archive(&other_conn, &other_cipher, &path, "correct horse").await.unwrap();
+    assert!(report.messages >= 3);
+    assert_eq!(repository::get_user(&other_conn, "archive_peer").await.unwrap().unwrap().email, "peer@example.com");
+    let results = search::search(&other_conn, &other_cipher, "group message", 10).await.unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(archive::import_archive(&other_conn, &other_cipher, &path, "correct horse").await.unwrap().messages, 0);
+
+    fs::remove_file(&path).await.unwrap();
+    repository::delete_conversation(&conn, conversation_id).await.unwrap();
+    repository::delete_conversation(&conn, group).await.unwrap();
+    println!("Archive test passed");
+}
+pub async fn transcript_test() {
+    let conn = db::connect("test").await.unwrap();
+    let cipher = encryption::load_cipher("test").await.unwrap();
+
+    repository::upsert_user(&conn, "transcript_peer", "peer@example.com").await.unwrap();
+    let (conversation_id, first) = history::store_incoming(&conn, &cipher, "transcript_peer", "hello, \"there\"", None).await.unwrap();
+    history::store_outgoing(&conn, &cipher, "test", "transcript_peer", "two\nlines", None).await.unwrap();
+    conn.call(move |call| {
+        call.execute("UPDATE messages SET created_at = '2020-01-01 12:00:00' WHERE message_id = ?1", [first])?;
+        Ok::<(), tokio_rusqlite::Error>(())
+    }).await.unwrap();
+
+    let filter = TranscriptFilter { conversation_id: Some(conversation_id), ..Default::default() };
+    let entries = transcript::collect(&conn, &cipher, &filter).await.unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].sender, "transcript_peer");
+    assert_eq!(entries[0].sender_email, "peer@example.com");
+
+    let jsonl = String::from_utf8(transcript::render(&entries, TranscriptFormat::JsonLines).unwrap()).unwrap();
+    assert_eq!(jsonl.lines().count(), 2);
+    let parsed: serde_json::Value = serde_json::from_str(jsonl.lines().next().unwrap()).unwrap();
+    assert_eq!(parsed["content"], "hello, \"there\"");
+
+    let csv = transcript::render(&entries, TranscriptFormat::Csv).unwrap();
+    let mut reader = csv::Reader::from_reader(csv.as_slice());
+    assert_eq!(reader.headers().unwrap().iter().collect::<Vec<_>>(),
+        vec!["conversation_id", "message_id", "sender", "sender_email", "created_at", "content"]);
+    let rows: Vec<csv::StringRecord> = reader.records().map(|r| r.unwrap()).collect();
+    assert_eq!(&rows[0][5], "hello, \"there\"");
+    assert_eq!(&rows[1][5], "two\nlines");
+
+    let markdown = String::from_utf8(transcript::render(&entries, TranscriptFormat::Markdown).unwrap()).unwrap();
+    assert!(markdown.starts_with(&format!("## Conversation {}\n\n", conversation_id)));
+    assert!(markdown.contains("**transcript_peer** (peer@example.com): hello"));
+    assert!(markdown.contains("two\n  lines"));
+
+    //date ranges are inclusive days
+    let filter = TranscriptFilter { conversation_id: Some(conversation_id), since: None, until: Some("2020-01-01".to_string()) };
+    assert_eq!(transcript::collect(&conn, &cipher, &filter).await.unwrap().len(), 1);
+    let filter = TranscriptFilter { conversation_id: Some(conversation_id), since: Some("2020-01-02".to_string()), until: None };
+    assert_eq!(transcript::collect(&conn, &cipher, &filter).await.unwrap().len(), 1);
+    let filter = TranscriptFilter { since: Some("01/02/2020".to_string()), ..Default::default() };
+    assert!(transcript::collect(&conn, &cipher, &filter).await.is_err());
+    assert!("xml".parse::<TranscriptFormat>().is_err());
+
+    repository::delete_conversation(&conn, conversation_id).await.unwrap();
+    println!("Transcript test passed");
+}
+pub async fn history_transfer_test() {
+    let old_conn = db::connect("test").await.unwrap();
+    let old_cipher = encryption::load_cipher("test").await.unwrap();
+    let new_conn = db::connect("transfer_new").await.unwrap();
+    let new_cipher = encryption::load_cipher("transfer_new").await.unwrap();
+
+    //enough history for several chunks
+    let mut conversation_id = 0;
+    for i in 0..100 {
+        let content = format!("transfer message {} {}", i, "x".repeat(1000));
+        conversation_id = history::store_incoming(&old_conn, &old_cipher, "transfer_peer", &content, None).await.unwrap().0;
+    }
+
+    let request = transfer::request_history(&new_conn, &new_cipher, "2002", "1001").await.unwrap();
+    assert_eq!(request["type"], "sync");
+    //the request only commits to the new device's key
+    assert!(request.get("public_key").is_none());
+    assert!(request["key_commitment"].is_string());
+    //the old device only answers when serving is enabled
+    let outcome = transfer::handle(&old_conn, &old_cipher, "test", "1001", false, &request).await.unwrap();
+    assert!(outcome.frames.is_empty() && outcome.approval.is_none());
+    //and only to devices the server lists for the account, a refresh is asked for otherwise
+    let outcome = transfer::handle(&old_conn, &old_cipher, "test", "1001", true, &request).await.unwrap();
+    assert!(outcome.approval.is_none());
+    assert_eq!(outcome.frames.len(), 1);
+    assert_eq!(outcome.frames[0]["action"], "get_devices");
+    repository::record_device_list(&old_conn, "test", &["1001".to_string(), "2002".to_string()]).await.unwrap();
+
+    //nothing of the history goes out before the user approves
+    let outcome = transfer::handle(&old_conn, &old_cipher, "test", "1001", true, &request).await.unwrap();
+    assert_eq!(outcome.frames.len(), 1);
+    assert_eq!(outcome.frames[0]["action"], "key");
+    assert!(outcome.approval.is_none());
+    //the new device reveals its key once it has the old device's
+    let outcome = transfer::handle(&new_conn, &new_cipher, "transfer_new", "2002", false, &outcome.frames[0]).await.unwrap();
+    let reveal = outcome.frames[0].clone();
+    assert_eq!(reveal["action"], "reveal");
+    assert_eq!(reveal["resume_from"], 0);
+    //a key swapped on the way doesn't match the request's commitment
+    let relay_secret = x448::Secret::from_bytes(&[7u8; 56]).unwrap();
+    let mut swapped = reveal.clone();
+    swapped["public_key"] = serde_json::json!(STANDARD.encode(x448::PublicKey::from(&relay_secret).as_bytes()));
+    assert!(transfer::handle(&old_conn, &old_cipher, "test", "1001", true, &swapped).await.is_err());
+    let approval = transfer::handle(&old_conn, &old_cipher, "test", "1001", true, &reveal).await.unwrap().approval.unwrap();
+    assert_eq!(approval.device, "2002");
+    //both devices show the same code
+    assert!(outcome.notice.unwrap().contains(&approval.code));
+
+    let frames = transfer::approve(&old_conn, &old_cipher, "test", "1001", &approval.request).await.unwrap().frames;
+    assert_eq!(frames[0]["action"], "offer");
+    let total = frames[0]["total_chunks"].as_i64().unwrap();
+    assert!(total >= 3);
+    assert_eq!(frames.len() as i64, total + 1);
+    assert!(!frames[1]["data"].as_str().unwrap().contains("transfer message"));
+
+    //the connection drops after the first chunk
+    for frame in &frames[..2] {
+        assert!(transfer::handle(&new_conn, &new_cipher, "transfer_new", "2002", false, frame).await.unwrap().frames.is_empty());
+    }
+    //out of order chunks are refused
+    assert!(transfer::handle(&new_conn, &new_cipher, "transfer_new", "2002", false, &frames[3]).await.is_err());
+    //and so are chunks from a device the transfer wasn't requested from
+    let mut forged = frames[2].clone();
+    forged["sender"] = serde_json::json!("3003");
+    assert!(transfer::handle(&new_conn, &new_cipher, "transfer_new", "2002", false, &forged).await.is_err());
+
+    //resuming only sends what is missing, again once approved
+    let request = transfer::request_history(&new_conn, &new_cipher, "2002", "1001").await.unwrap();
+    let key = transfer::handle(&old_conn, &old_cipher, "test", "1001", true, &request).await.unwrap().frames[0].clone();
+    let reveal = transfer::handle(&new_conn, &new_cipher, "transfer_new", "2002", false, &key).await.unwrap().frames[0].clone();
+    assert_eq!(reveal["resume_from"], 1);
+    let resumed = transfer::handle(&old_conn, &old_cipher, "test", "1001", true, &reveal).await.unwrap().approval.unwrap();
+    assert_eq!(resumed.code, approval.code);
+    let frames = transfer::approve(&old_conn, &old_cipher, "test", "1001", &resumed.request).await.unwrap().frames;
+    assert_eq!(frames[0]["restart"], false);
+    assert_eq!(frames.len() as i64, total);
+    assert_eq!(frames[1]["index"], 1);
+
+    //a modified chunk fails authentication and isn't stored
+    let mut tampered = frames[1].clone();
+    let data = tampered["data"].as_str().unwrap().to_string();
+    tampered["data"] = serde_json::json!(format!("{}{}", if data.starts_with('A') { "B" } else { "A" }, &data[1..]));
+    assert!(transfer::handle(&new_conn, &new_cipher, "transfer_new", "2002", false, &tampered).await.is_err());
+
+    let mut reply = Vec::new();
+    for frame in &frames {
+        reply = transfer::handle(&new_conn, &new_cipher, "transfer_new", "2002", false, frame).await.unwrap().frames;
+    }
+    assert_eq!(reply.len(), 1);
+    assert_eq!(reply[0]["action"], "complete");
+    assert_eq!(search::search(&new_conn, &new_cipher, "transfer message 99", 10).await.unwrap().len(), 1);
+    let transfer_id = reply[0]["transfer_id"].as_str().unwrap().to_string();
+    assert!(repository::get_transfer(&new_conn, &transfer_id).await.unwrap().is_none());
+
+    transfer::handle(&old_conn, &old_cipher, "test", "1001", true, &reply[0]).await.unwrap();
+    assert!(repository::get_transfer(&old_conn, &transfer_id).await.unwrap().is_none());
+
+    //a rejected request leaves nothing behind to approve later
+    let request = transfer::request_history(&new_conn, &new_cipher, "2002", "1001").await.unwrap();
+    let key = transfer::handle(&old_conn, &old_cipher, "test", "1001", true, &request).await.unwrap().frames[0].clone();
+    let reveal = transfer::handle(&new_conn, &new_cipher, "transfer_new", "2002", false, &key).await.unwrap().frames[0].clone();
+    let approval = transfer::handle(&old_conn, &old_cipher, "test", "1001", true, &reveal).await.unwrap().approval.unwrap();
+    transfer::reject(&old_conn, &approval.request).await.unwrap();
+    assert!(transfer::approve(&old_conn, &old_cipher, "test", "1001", &approval.request).await.is_err());
+    //a device the server stops listing can't ask any more
+    repository::record_device_list(&old_conn, "test", &["1001".to_string()]).await.unwrap();
+    assert_eq!(transfer::handle(&old_conn, &old_cipher, "test", "1001", true, &request).await.unwrap().frames[0]["action"], "get_devices");
+
+    repository::delete_conversation(&old_conn, conversation_id).await.unwrap();
+    println!("History transfer test passed");
+}
+pub async fn credential_store_test() {
+    let memory = MemoryStore::default();
+    memory.set("e_to_e_msgr_token", "store_user", "secret token").unwrap();
+    assert_eq!(memory.get("e_to_e_msgr_token", "store_user").unwrap().as_str(), "secret token");
+    assert!(credential_store::is_not_found(&memory.get("e_to_e_msgr_token", "other_user").unwrap_err()));
+    memory.delete("e_to_e_msgr_token", "store_user").unwrap();
+    assert!(memory.get("e_to_e_msgr_token", "store_user").is_err());
+    assert!(memory.delete("e_to_e_msgr_token", "store_user").is_err());
+
+    //the file store persists across instances, and never holds the secrets in the clear
+    let path = paths::data_dir().await.unwrap().join("test_credentials.enc");
+    let key = [7u8; 32];
+    let file = EncryptedFileStore::new(path.clone(), &key);
+    file.set("e_to_e_msgr_uuid", "store_user", "device-uuid").unwrap();
+    file.set("e_to_e_msgr_device_id", "store_user", "42").unwrap();
+    let reopened = EncryptedFileStore::new(path.clone(), &key);
+    assert_eq!(reopened.get("e_to_e_msgr_uuid", "store_user").unwrap().as_str(), "device-uuid");
+    assert_eq!(reopened.get("e_to_e_msgr_device_id", "store_user").unwrap().as_str(), "42");
+    assert!(!fs::read_to_string(&path).await.unwrap().contains("device-uuid"));
+    assert!(EncryptedFileStore::new(path.clone(), &[8u8; 32]).get("e_to_e_msgr_uuid", "store_user").is_err());
+    reopened.delete("e_to_e_msgr_uuid", "store_user").unwrap();
+    assert!(file.get("e_to_e_msgr_uuid", "store_user").is_err());
+    fs::remove_file(&path).await.unwrap();
+
+    let config: config::Config = serde_json::from_str(r#"{"credential_store": "memory"}"#).unwrap();
+    assert_eq!(config.credential_store, StoreKind::Memory);
+    assert_eq!(config::Config::default().credential_store, StoreKind::Keyring);
+    let store = credential_store::open(StoreKind::Memory, None, 60).await.unwrap();
+    store.set("e_to_e_msgr_token", "store_user", "t").unwrap();
+    assert_eq!(store.get("e_to_e_msgr_token", "store_user").unwrap().as_str(), "t");
+    println!("Credential store test passed");
+}
+pub async fn credential_vault_test() {
+    let path = paths::data_dir().await.unwrap().join("test_credentials.vault");
+    let _ = fs::remove_file(&path).await;
+
+    //a new vault starts locked and is created by the first unlock
+    let vault = VaultStore::new(path.clone(), std::time::Duration::from_secs(60));
+    assert!(vault.is_locked());
+    assert!(!vault.exists());
+    assert!(vault.get("e_to_e_msgr_token", "vault_user").is_err());
+    vault.unlock("vault passphrase").unwrap();
+    assert!(vault.exists());
+    vault.set("e_to_e_msgr_token", "vault_user", "vault token").unwrap();
+    assert_eq!(vault.get("e_to_e_msgr_token", "vault_user").unwrap().as_str(), "vault token");
+    assert!(!String::from_utf8_lossy(&fs::read(&path).await.unwrap()).contains("vault token"));
+
+    assert!(credential_store::is_not_found(&vault.get("e_to_e_msgr_token", "other_user").unwrap_err()));
+
+    vault.lock();
+    assert!(vault.is_locked());
+    //a locked vault is a failure, not a missing credential, so nothing gets regenerated over it
+    assert!(!credential_store::is_not_found(&vault.get("e_to_e_msgr_token", "vault_user").unwrap_err()));
+    assert!(vault.unlock("wrong passphrase").is_err());
+    assert!(vault.is_locked());
+
+    //another instance opens the same file with the passphrase
+    let reopened = std::sync::Arc::new(VaultStore::new(path.clone(), std::time::Duration::from_secs(1)));
+    reopened.unlock("vault passphrase").unwrap();
+    assert_eq!(reopened.get("e_to_e_msgr_token", "vault_user").unwrap().as_str(), "vault token");
+    reopened.delete("e_to_e_msgr_token", "vault_user").unwrap();
+    assert!(reopened.get("e_to_e_msgr_token", "vault_user").is_err());
+
+    //a held vault stays unlocked past its timeout, the timeout starts over once released
+    let hold = credential_store::hold(reopened.clone()).unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    assert!(!reopened.lock_if_idle());
+    assert!(reopened.set("e_to_e_msgr_token", "vault_user", "held token").is_ok());
+    drop(hold);
+    assert!(!reopened.lock_if_idle());
+    assert!(credential_store::hold(std::sync::Arc::new(MemoryStore::default())).is_none());
+
+    //idle vaults lock themselves
+    assert!(!reopened.lock_if_idle());
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    assert!(reopened.lock_if_idle());
+    assert!(reopened.is_locked());
+    assert!(reopened.vault().is_some());
+    assert!(MemoryStore::default().vault().is_none());
+
+    //an opened vault is locked on a timer, without waiting for the next credential operation
+    let opened = credential_store::open(StoreKind::Vault, Some(path.clone()), 1).await.unwrap();
+    let vault = opened.vault().unwrap();
+    vault.unlock("vault passphrase").unwrap();
+    assert!(!vault.is_locked());
+    tokio::time::sleep(std::time::Duration::from_millis(2500)).await;
+    assert!(vault.is_locked());
+    assert!(!vault.lock_if_idle());
+    //and unlocking wakes whoever waits for it
+    let waiter = tokio::spawn({
+        let opened = opened.clone();
+        async move { opened.vault().unwrap().unlocked().await }
+    });
+    vault.unlock("vault passphrase").unwrap();
+    tokio::time::timeout(std::time::Duration::from_secs(1), waiter).await.unwrap().unwrap();
+
+    fs::remove_file(&path).await.unwrap();
+    println!("Credential vault test passed");
+}
+pub async fn private_key_test() {
+    let username = "test_keys";
+    let identity = PrivateKeyMaterial::generate(1, 56);
+    manage_keys::store_private_key(username, &KeyKind::Identity, &identity).await.unwrap();
+    let loaded = manage_keys::get_private_key(username, &KeyKind::Identity).await.unwrap();
+    assert_eq!(loaded.expose(), identity.expose());
+    assert_eq!(loaded.key_id, 1);
+    //key bytes never show up in debug output
+    let debug = format!("{:?}", loaded);
+    assert!(debug.contains("[redacted]"));
+    assert!(!debug.contains(&format!("{:?}", loaded.expose())));
+
+    //rotation keeps the replaced key as the previous one
+    assert!(manage_keys::get_previous_private_key(username, &KeyKind::SignedPrekey).await.unwrap().is_none());
+    let first = manage_keys::rotate_private_key(username, &KeyKind::SignedPrekey, vec![1; 32]).await.unwrap();
+    assert_eq!(first.key_id, 1);
+    let second = manage_keys::rotate_private_key(username, &KeyKind::SignedPrekey, vec![2; 32]).await.unwrap();
+    assert_eq!(second.key_id, 2);
+    assert_eq!(manage_keys::get_private_key(username, &KeyKind::SignedPrekey).await.unwrap().expose(), &[2; 32]);
+    let previous = manage_keys::get_previous_private_key(username, &KeyKind::SignedPrekey).await.unwrap().unwrap();
+    assert_eq!((previous.key_id, previous.expose()), (1, &[1u8; 32][..]));
+
+    //ratchet roots are kept per peer
+    let alice = KeyKind::RatchetRoot { peer: "alice_device".to_string() };
+    let bob = KeyKind::RatchetRoot { peer: "bob_device".to_string() };
+    manage_keys::store_private_key(username, &alice, &PrivateKeyMaterial::new(1, vec![3; 32])).await.unwrap();
+    assert!(manage_keys::get_private_key(username, &bob).await.is_err());
+
+    for kind in [KeyKind::Identity, KeyKind::SignedPrekey, alice.clone()] {
+        manage_keys::delete_private_key(username, &kind).await.unwrap();
+        assert!(manage_keys::get_private_key(username, &kind).await.is_err());
+    }
+    assert!(manage_keys::get_previous_private_key(username, &KeyKind::SignedPrekey).await.unwrap().is_none());
+    println!("Private key test passed");
+}
+
+//Recovery codes are unique, survive loose typing and only their digests are sent
+pub async fn recovery_codes_test() {
+    let codes = recovery::generate_codes();
+    assert_eq!(codes.len(), recovery::RECOVERY_CODE_COUNT);
+    let unique: std::collections::HashSet<_> = codes.iter().collect();
+    assert_eq!(unique.len(), codes.len(), "recovery_codes_test: duplicate code");
+    for code in &codes {
+        assert_eq!(code.len(), 14);
+        assert_eq!(code.matches('-').count(), 2);
+    }
+
+    let code = &codes[0];
+    let loose = format!(" {} ", code.replace('-', " ").to_lowercase());
+    assert_eq!(recovery::normalize(&loose).unwrap(), code.replace('-', ""));
+    assert_eq!(recovery::digest(&loose).unwrap(), recovery::digest(code).unwrap());
+    let digests = recovery::digests(&codes).unwrap();
+    assert_ne!(digests[0], digests[1]);
+    assert!(!digests[0].contains(&code.replace('-', "")));
+
+    //wrong length and characters outside the alphabet are rejected
+    assert!(recovery::normalize("ABCD-EFGH").is_err());
+    assert!(recovery::normalize("ABCD-EFGH-JKM0").is_err());
+    println!("Recovery codes test passed");
+}
+
+//TOTP codes match the RFC 6238 appendix B test vectors
+pub async fn totp_test() {
+    let sha1 = Totp::new(b"12345678901234567890".to_vec(), TotpAlgorithm::Sha1, 8, 30);
+    let sha256 = Totp::new(b"12345678901234567890123456789012".to_vec(), TotpAlgorithm::Sha256, 8, 30);
+    let sha512 = Totp::new(
+        b"1234567890123456789012345678901234567890123456789012345678901234".to_vec(), TotpAlgorithm::Sha512, 8, 30,
+    );
+    let vectors: [(i64, &str, &str, &str); 6] = [
+        (59, "94287082", "46119246", "90693936"),
+        (1111111109, "07081804", "68084774", "25091201"),
+        (1111111111, "14050471", "67062674", "99943326"),
+        (1234567890, "89005924", "91819424", "93441116"),
+        (2000000000, "69279037", "90698825", "38618901"),
+        (20000000000, "65353130", "77737706", "47863826"),
+    ];
+    for (time, expected_sha1, expected_sha256, expected_sha512) in vectors {
+        assert_eq!(sha1.code_at(time), expected_sha1, "totp_test: SHA1 at {}", time);
+        assert_eq!(sha256.code_at(time), expected_sha256, "totp_test: SHA256 at {}", time);
+        assert_eq!(sha512.code_at(time), expected_sha512, "totp_test: SHA512 at {}", time);
+    }
+
+    //one step of drift either way is accepted, two are not
+    assert!(sha1.verify("07081804", 1111111109));
+    assert!(sha1.verify("07081804", 1111111109 + 30));
+    assert!(sha1.verify(" 07081804 ", 1111111109 - 30));
+    assert!(!sha1.verify("07081804", 1111111109 + 60));
+    assert!(!sha1.verify("0708180", 1111111109));
+
+    //generated secrets use the defaults authenticator apps expect
+    let generated = Totp::generate();
+    assert_eq!(generated.code_at(1111111109).len(), 6);
+    let uri = generated.provisioning_uri("alice@example.com");
+    assert!(uri.starts_with("otpauth://totp/e_to_e_msgr:alice%40example.com?secret="), "totp_test: {}", uri);
+    assert!(uri.contains(&format!("secret={}&", generated.secret_base32())));
+    assert!(uri.ends_with("&algorithm=SHA1&digits=6&period=30"));
+    assert_eq!(Totp::new(b"12345678901234567890".to_vec(), TotpAlgorithm::Sha1, 6, 30).secret_base32(), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    println!("TOTP test passed");
+}
+
+//Password keys are deterministic per account and the local key can restore a lost database key
+pub async fn password_auth_test() {
+    let username = "test_password_keys";
+    let keys = PasswordKeys::derive(username, "correct horse").unwrap();
+    assert_eq!(keys.verifier(), PasswordKeys::derive(username, "correct horse").unwrap().verifier());
+    assert_ne!(keys.verifier(), PasswordKeys::derive("other_user", "correct horse").unwrap().verifier());
+    assert_ne!(keys.verifier(), PasswordKeys::derive(username, "correct horse!").unwrap().verifier());
+    assert!(!keys.verifier().contains("correct horse"));
+
+    //a login wraps the database key, a later login restores it to an empty credential store
+    assert!(password_auth::unlock_local_state(username, &keys).await.unwrap().is_none());
+    let db_key = manage_keys::get_db_key(username).await.unwrap();
+    manage_keys::delete_credential(username, "e_to_e_msgr_db_key").await.unwrap();
+    assert!(password_auth::unlock_local_state(username, &keys).await.unwrap().is_none());
+    assert_eq!(manage_keys::get_db_key(username).await.unwrap(), db_key);
+
+    //the wrong password can't restore it, and leaves the wrapped copy for the right one
+    manage_keys::delete_credential(username, "e_to_e_msgr_db_key").await.unwrap();
+    let wrong = PasswordKeys::derive(username, "wrong horse").unwrap();
+    let warning = password_auth::unlock_local_state(username, &wrong).await.unwrap().unwrap();
+    assert!(warning.contains("different password"));
+    assert!(manage_keys::get_db_key(username).await.is_err());
+    password_auth::unlock_local_state(username, &keys).await.unwrap();
+    assert_eq!(manage_keys::get_db_key(username).await.unwrap(), db_key);
+
+    //a password change moves the wrapped copy to the new password
+    let changed = PasswordKeys::derive(username, "battery staple").unwrap();
+    password_auth::unlock_local_state(username, &changed).await.unwrap();
+    manage_keys::delete_credential(username, "e_to_e_msgr_db_key").await.unwrap();
+    password_auth::unlock_local_state(username, &changed).await.unwrap();
+    assert_eq!(manage_keys::get_db_key(username).await.unwrap(), db_key);
+
+    account_removal::wipe_local(username).await.unwrap();
+    println!("Password auth test passed");
+}
+
+//Account fields are checked before they are sent
+pub async fn prompt_validation_test() {
+    for username in ["alice", "bob_2", "carol.smith", "d-e", "7up"] {
+        assert!(prompt::validate_username(username).is_ok(), "prompt_validation_test: rejected {}", username);
+    }
+    for username in ["", "_alice", ".hidden", "al ice", "a/b", "..", "alice,bob", "émile", &"x".repeat(33)] {
+        assert!(prompt::validate_username(username).is_err(), "prompt_validation_test: accepted {:?}", username);
+    }
+
+    for email in ["a@example.com", "first.last+tag@mail.example.org"] {
+        assert!(prompt::validate_email(email).is_ok(), "prompt_validation_test: rejected {}", email);
+    }
+    for email in ["", "example.com", "@example.com", "a@example", "a@@example.com", "a@example..com", "a b@example.com", "a@.com"] {
+        assert!(prompt::validate_email(email).is_err(), "prompt_validation_test: accepted {:?}", email);
+    }
+    println!("Prompt validation test passed");
 }
 
+//Chat shell input is split into commands and messages, and messages print one per line
+pub async fn chat_shell_test() {
+    assert_eq!(session_cli::parse_command("hello there"), ShellCommand::Send(String::from("hello there")));
+    assert_eq!(session_cli::parse_command("  \n"), ShellCommand::Empty);
+    assert_eq!(session_cli::parse_command("/to bob"), ShellCommand::To(String::from("bob")));
+    assert!(matches!(session_cli::parse_command("/to"), ShellCommand::Invalid(_)));
+    assert_eq!(session_cli::parse_command("/list"), ShellCommand::List);
+    assert_eq!(session_cli::parse_command("/history"), ShellCommand::History(20));
+    assert_eq!(session_cli::parse_command("/history 5"), ShellCommand::History(5));
+    assert!(matches!(session_cli::parse_command("/history -1"), ShellCommand::Invalid(_)));
+    assert_eq!(session_cli::parse_command("/devices"), ShellCommand::Devices);
+    assert_eq!(session_cli::parse_command("/sync 42"), ShellCommand::Sync(String::from("42")));
+    assert_eq!(session_cli::parse_command("/approve 42"), ShellCommand::Approve(String::from("42")));
+    assert_eq!(session_cli::parse_command("/reject 42"), ShellCommand::Reject(String::from("42")));
+    assert_eq!(session_cli::parse_command("/unlock"), ShellCommand::Unlock);
+    assert!(matches!(session_cli::parse_command("/approve"), ShellCommand::Invalid(_)));
+    assert_eq!(session_cli::parse_command("/quit"), ShellCommand::Quit);
+    assert!(matches!(session_cli::parse_command("/frobnicate"), ShellCommand::Invalid(_)));
+    //a doubled slash sends a message that starts with one
+    assert_eq!(session_cli::parse_command("//shrug"), ShellCommand::Send(String::from("/shrug")));
 
+    assert_eq!(session_cli::format_message("bob", "2024-05-01 13:45:09", "hi"), "[13:45] bob: hi");
+    assert_eq!(
+        session_cli::format_message("bob", "2024-05-01 13:45:09", "line one\nline two"),
+        "[13:45] bob: line one\n        line two"
+    );
+    assert_eq!(session_cli::format_message("bob", "now", "hi"), "[now] bob: hi");
+    println!("Chat shell test passed");
+}
+
+pub async fn tui_test() {
+    let mut input = Input::default();
+    for c in "héllo".chars() {
+        input.insert(c);
+    }
+    input.backspace();
+    input.left();
+    input.insert('\n');
+    assert_eq!(input.text(), "hél\nl");
+    assert_eq!(input.cursor_position(), (0, 1));
+    input.home();
+    input.backspace();
+    //backspace at the start of a line joins it to the previous one
+    assert_eq!(input.text(), "héll");
+    input.left();
+    input.left();
+    input.delete();
+    assert_eq!(input.text(), "hll");
+    assert_eq!(input.cursor_position(), (1, 0));
+    input.end();
+    input.insert('\n');
+    input.insert('x');
+    assert_eq!(input.line_count(), 2);
+    assert_eq!(input.take(), "hll\nx");
+    assert_eq!(input, Input::default());
+
+    assert_eq!(tui::wrap("abcdefg", 3), vec!["abc", "def", "g"]);
+    assert_eq!(tui::wrap("ab\n\ncd", 3), vec!["ab", "", "cd"]);
+    assert_eq!(tui::wrap("ééé", 2), vec!["éé", "é"]);
+    println!("TUI test passed");
+}
+
+//Wiping an account leaves no credentials, keys, database or registry entry behind
+pub async fn wipe_local_test() {
+    let username = "test_wipe";
+    let conn = db::connect(username).await.unwrap();
+    repository::upsert_user(&conn, "wipe_peer", "peer@example.com").await.unwrap();
+    drop(conn);
+    accounts::add_account(username, "localhost:3000", Some("9")).await.unwrap();
+    manage_keys::store_uuid(username, "wipe-uuid").await.unwrap();
+    manage_keys::store_token("wipe-token", username).await.unwrap();
+    manage_keys::store_device_id(username, "9").await.unwrap();
+    manage_keys::store_private_key(username, &KeyKind::Identity, &PrivateKeyMaterial::generate(1, 56)).await.unwrap();
+    let peer = KeyKind::RatchetRoot { peer: "wipe_peer".to_string() };
+    manage_keys::rotate_private_key(username, &peer, vec![4; 32]).await.unwrap();
+    manage_keys::rotate_private_key(username, &peer, vec![5; 32]).await.unwrap();
+    let db_path = paths::user_db_path(username).await.unwrap();
+
+    let report = account_removal::wipe_local(username).await.unwrap();
+    //uuid, token, device id, db key, identity key, and the current and previous ratchet root
+    assert_eq!(report.credentials, 7);
+    assert!(report.data_removed && report.registry_removed);
+    assert!(!report.deleted_on_server && !report.device_revoked);
+
+    assert!(manage_keys::get_token(username).await.is_err());
+    assert!(manage_keys::get_db_key(username).await.is_err());
+    assert!(manage_keys::get_private_key(username, &peer).await.is_err());
+    assert!(manage_keys::get_previous_private_key(username, &peer).await.unwrap().is_none());
+    assert!(fs::metadata(&db_path).await.is_err());
+    assert!(accounts::get_account(username).await.unwrap().is_none());
+
+    //running it again finds nothing left to remove
+    let again = account_removal::wipe_local(username).await.unwrap();
+    assert_eq!(again, account_removal::RemovalReport::default());
+    println!("Wipe local test passed");
+}
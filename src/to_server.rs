@@ -1,8 +1,11 @@
 use reqwest::Client;
 
+//host:port of the relay server, shared by the HTTP and websocket endpoints
+pub const SERVER: &str = "localhost:3000";
+
 //only for POST atp
 pub async fn to_server(uri: &str, payload: serde_json::Value) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
-    let url = format!("http://localhost:3000/{}", uri);
+    let url = format!("http://{}/{}", SERVER, uri);
     let client = Client::new();
     let resp = client.post(url)
         .json(&payload)
@@ -0,0 +1,121 @@
+/**
+ * Time-based one-time passwords (RFC 6238) for two-factor login.
+ * The server checks codes at login; the client needs the algorithm to create the secret
+ * at enrollment and check the user's authenticator app agrees with it before the server
+ * starts demanding codes, so a mistyped setup can't lock the user out.
+ */
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+use zeroize::Zeroizing;
+
+//name shown for the account in authenticator apps
+pub const ISSUER: &str = "e_to_e_msgr";
+//RFC 4226 recommends at least 128 bits, 160 matches the SHA-1 block the apps default to
+const SECRET_LEN: usize = 20;
+//codes from one step either side are accepted to allow for clock drift
+const SKEW_STEPS: i64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TotpAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl TotpAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TotpAlgorithm::Sha1 => "SHA1",
+            TotpAlgorithm::Sha256 => "SHA256",
+            TotpAlgorithm::Sha512 => "SHA512",
+        }
+    }
+}
+
+pub struct Totp {
+    secret: Zeroizing<Vec<u8>>,
+    pub algorithm: TotpAlgorithm,
+    pub digits: u32,
+    //seconds per code
+    pub step: u64,
+}
+
+impl Totp {
+    pub fn new(secret: Vec<u8>, algorithm: TotpAlgorithm, digits: u32, step: u64) -> Self {
+        Totp { secret: Zeroizing::new(secret), algorithm, digits, step }
+    }
+
+    //A random secret with the parameters every authenticator app supports: SHA-1, 6 digits, 30 seconds
+    pub fn generate() -> Self {
+        let mut secret = vec![0u8; SECRET_LEN];
+        OsRng.fill_bytes(&mut secret);
+        Totp::new(secret, TotpAlgorithm::Sha1, 6, 30)
+    }
+
+    //The secret as typed into an authenticator app that can't scan the provisioning URI
+    pub fn secret_base32(&self) -> String {
+        BASE32_NOPAD.encode(&self.secret)
+    }
+
+    //otpauth:// URI for a QR code or a link, see the Key Uri Format of Google Authenticator
+    pub fn provisioning_uri(&self, account: &str) -> String {
+        format!(
+            "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm={}&digits={}&period={}",
+            percent_encode(ISSUER), percent_encode(account), self.secret_base32(),
+            percent_encode(ISSUER), self.algorithm.name(), self.digits, self.step
+        )
+    }
+
+    //The code for the step containing unix_time
+    pub fn code_at(&self, unix_time: i64) -> String {
+        let counter = unix_time.max(0) as u64 / self.step;
+        self.code_for_counter(counter)
+    }
+
+    //Whether code is valid at unix_time, allowing one step of clock drift either way
+    pub fn verify(&self, code: &str, unix_time: i64) -> bool {
+        let code = code.trim();
+        (-SKEW_STEPS..=SKEW_STEPS).any(|skew| {
+            let at = unix_time + skew * self.step as i64;
+            at >= 0 && constant_time_eq(self.code_at(at).as_bytes(), code.as_bytes())
+        })
+    }
+
+    //HOTP (RFC 4226): HMAC of the counter, dynamically truncated to digits decimal digits
+    fn code_for_counter(&self, counter: u64) -> String {
+        let message = counter.to_be_bytes();
+        let hash = match self.algorithm {
+            TotpAlgorithm::Sha1 => mac::<Hmac<Sha1>>(&self.secret, &message),
+            TotpAlgorithm::Sha256 => mac::<Hmac<Sha256>>(&self.secret, &message),
+            TotpAlgorithm::Sha512 => mac::<Hmac<Sha512>>(&self.secret, &message),
+        };
+        let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+        let truncated = u32::from_be_bytes(hash[offset..offset + 4].try_into().expect("slice is 4 bytes")) & 0x7fff_ffff;
+        let code = truncated as u64 % 10u64.pow(self.digits);
+        format!("{:0width$}", code, width = self.digits as usize)
+    }
+}
+
+fn mac<M: Mac + hmac::digest::KeyInit>(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = <M as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+//compares without returning early, so response times don't reveal how much of a code matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn percent_encode(value: &str) -> String {
+    value.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
@@ -0,0 +1,145 @@
+/**
+ * Human-readable transcripts of local history, for compliance and record keeping.
+ * Unlike archive, the output is plaintext: anyone who can read the file can read the messages.
+ * Senders are resolved through the users table so transcripts show who wrote what
+ * rather than raw device ids.
+ */
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use tokio_rusqlite::Connection;
+
+use crate::encryption::FieldCipher;
+use crate::history;
+use crate::paths;
+use crate::repository;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TranscriptFormat {
+    //one JSON object per line
+    JsonLines,
+    Csv,
+    Markdown,
+}
+
+impl FromStr for TranscriptFormat {
+    type Err = BoxError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "jsonl" | "json" => Ok(TranscriptFormat::JsonLines),
+            "csv" => Ok(TranscriptFormat::Csv),
+            "md" | "markdown" => Ok(TranscriptFormat::Markdown),
+            other => Err(Box::from(format!("Unknown transcript format '{}', expected jsonl, csv or md", other))),
+        }
+    }
+}
+
+//Which messages go into a transcript, unset fields don't filter
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TranscriptFilter {
+    pub conversation_id: Option<i64>,
+    //first day included, YYYY-MM-DD
+    pub since: Option<String>,
+    //last day included, YYYY-MM-DD
+    pub until: Option<String>,
+}
+
+//One message as written to a transcript
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TranscriptEntry {
+    pub conversation_id: i64,
+    pub message_id: i64,
+    pub sender: String,
+    pub sender_email: String,
+    pub created_at: String,
+    pub content: String,
+}
+
+//Reads the matching messages with their senders resolved
+pub async fn collect(conn: &Connection, cipher: &FieldCipher, filter: &TranscriptFilter) -> Result<Vec<TranscriptEntry>, BoxError> {
+    for date in [&filter.since, &filter.until].into_iter().flatten() {
+        if !is_date(date) {
+            return Err(Box::from(format!("Invalid date '{}', expected YYYY-MM-DD", date)));
+        }
+    }
+
+    let emails: HashMap<String, String> = repository::list_users(conn).await?
+        .into_iter()
+        .map(|u| (u.user_id, u.email))
+        .collect();
+    let messages = repository::list_messages_in_range(
+        conn, cipher, filter.conversation_id, filter.since.clone(), filter.until.clone(),
+    ).await?;
+
+    let mut entries = Vec::with_capacity(messages.len());
+    for message in messages {
+        //rows stored before device ids were mapped to users may still hold a device id
+        let sender = history::resolve_user(conn, &message.sender_id).await?;
+        entries.push(TranscriptEntry {
+            conversation_id: message.conversation_id,
+            message_id: message.message_id,
+            sender_email: emails.get(&sender).cloned().unwrap_or_default(),
+            sender,
+            created_at: message.created_at,
+            content: message.content,
+        });
+    }
+    Ok(entries)
+}
+
+pub fn render(entries: &[TranscriptEntry], format: TranscriptFormat) -> Result<Vec<u8>, BoxError> {
+    let mut out = Vec::new();
+    match format {
+        TranscriptFormat::JsonLines => {
+            for entry in entries {
+                serde_json::to_writer(&mut out, entry)?;
+                out.push(b'\n');
+            }
+        }
+        TranscriptFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(&mut out);
+            for entry in entries {
+                writer.serialize(entry)?;
+            }
+            writer.flush()?;
+        }
+        TranscriptFormat::Markdown => {
+            let mut conversation = None;
+            for entry in entries {
+                if conversation != Some(entry.conversation_id) {
+                    if conversation.is_some() {
+                        out.push(b'\n');
+                    }
+                    out.extend_from_slice(format!("## Conversation {}\n\n", entry.conversation_id).as_bytes());
+                    conversation = Some(entry.conversation_id);
+                }
+                let sender = if entry.sender_email.is_empty() {
+                    format!("**{}**", entry.sender)
+                } else {
+                    format!("**{}** ({})", entry.sender, entry.sender_email)
+                };
+                //continuation lines are indented so multi-line messages stay in their list item
+                let content = entry.content.replace('\n', "\n  ");
+                out.extend_from_slice(format!("- {} {}: {}\n", entry.created_at, sender, content).as_bytes());
+            }
+        }
+    }
+    Ok(out)
+}
+
+//Writes a transcript to path, readable only by the owner. Returns how many messages it holds
+pub async fn export_transcript(conn: &Connection, cipher: &FieldCipher, filter: &TranscriptFilter, format: TranscriptFormat, path: &Path) -> Result<usize, BoxError> {
+    let entries = collect(conn, cipher, filter).await?;
+    paths::write_private(path, &render(&entries, format)?)?;
+    Ok(entries.len())
+}
+
+fn is_date(value: &str) -> bool {
+    let bytes = value.as_bytes();
+    bytes.len() == 10
+        && bytes.iter().enumerate().all(|(i, b)| if i == 4 || i == 7 { *b == b'-' } else { b.is_ascii_digit() })
+}
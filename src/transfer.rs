@@ -0,0 +1,466 @@
+/**
+ * History transfer from one of the user's devices to another, e.g. a device just linked
+ * with auth_commands::login_new whose database is still empty.
+ *
+ * Opt-in on both ends: the new device asks with request_history, and the old device only
+ * answers when serve_history_sync is set in its config.json and the request comes from one
+ * of the account's own devices (as last listed by the server). Both sides generate an
+ * ephemeral x448 key for the transfer, so the relay only ever sees ciphertext. The request
+ * carries only a hash of the new device's public key; the old device answers with its own
+ * key, and only then does the new device reveal its key, which the old device checks against
+ * the hash. Both devices then show a verification code derived from the transfer key. As each
+ * key is fixed before its owner sees the other one, a relay swapping keys can't search for a
+ * pair whose codes match, it gets a single one in a million guess. Nothing of the history is
+ * sent until the user has compared the codes and approved the request on the old device
+ * (see approve): if the relay swapped the keys, the codes differ and the user rejects it.
+ *
+ * The sender snapshots the history (see archive::collect), splits the JSON into chunks and
+ * seals each one with XChaCha20-Poly1305, binding it to its index and the chunk count so
+ * chunks can't be reordered, dropped or replayed into another transfer. The offer carries a
+ * SHA-256 digest of the whole snapshot, which the receiver checks before merging anything.
+ *
+ * Transfer state lives in history_transfers / history_transfer_chunks, so after a disconnect
+ * request_history resumes from the first missing chunk. If the sender's history changed in
+ * the meantime the digest no longer matches and the transfer restarts from the beginning.
+ */
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rand::RngCore;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio_rusqlite::Connection;
+
+use crate::archive::{self, ArchiveContents};
+use crate::clock::unix_now;
+use crate::encryption::FieldCipher;
+use crate::messages;
+use crate::repository::{self, Transfer};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+//plaintext bytes per chunk, small enough that a chunk frame stays well under relay limits
+pub const CHUNK_SIZE: usize = 32 * 1024;
+const NONCE_LEN: usize = 24;
+const KEY_CONTEXT: &[u8] = b"e_to_e_msgr history transfer v1";
+const SECRET_AAD: &[u8] = b"e_to_e_msgr:history_transfers.secret_key";
+const COMMITMENT_CONTEXT: &[u8] = b"e_to_e_msgr history transfer key commitment v1";
+
+const ROLE_SEND: &str = "send";
+const ROLE_RECEIVE: &str = "receive";
+
+/*
+Starts, or resumes, a transfer of old_device's history to this device.
+Returns the request frame to send to old_device, which commits to this device's key
+without revealing it.
+*/
+pub async fn request_history(conn: &Connection, cipher: &FieldCipher, device_id: &str, old_device: &str) -> Result<Value, BoxError> {
+    let transfer = match repository::find_transfer(conn, ROLE_RECEIVE, old_device).await? {
+        Some(transfer) => transfer,
+        None => {
+            let transfer = Transfer {
+                transfer_id: uuid::Uuid::new_v4().to_string(),
+                role: ROLE_RECEIVE.to_string(),
+                peer_device: old_device.to_string(),
+                secret_key: cipher.encrypt_for(SECRET_AAD, &STANDARD.encode(generate_secret()))?,
+                peer_commitment: None,
+                peer_public_key: None,
+                snapshot_at: None,
+                digest: None,
+                total_chunks: None,
+            };
+            repository::save_transfer(conn, &transfer).await?;
+            transfer
+        }
+    };
+
+    let public_key = public_key(&load_secret(cipher, &transfer)?);
+    messages::sync_request(device_id, old_device, &transfer.transfer_id, &key_commitment(&transfer.transfer_id, &public_key)).await
+}
+
+//What handling a sync frame produced
+#[derive(Debug, Default)]
+pub struct SyncOutcome {
+    //frames to send back
+    pub frames: Vec<Value>,
+    //what to tell the user
+    pub notice: Option<String>,
+    //a request waiting for the user to compare verification codes, answered by approve or reject
+    pub approval: Option<PendingApproval>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingApproval {
+    pub device: String,
+    pub code: String,
+    pub request: Value,
+}
+
+impl SyncOutcome {
+    fn notice(notice: String) -> Self {
+        SyncOutcome { notice: Some(notice), ..SyncOutcome::default() }
+    }
+}
+
+/*
+Handles a "sync" frame from another device. serve is whether this device answers requests.
+A request from one of the account's devices is answered with this device's key only, the
+user is asked to compare codes once the requester reveals its key, and the history follows
+once they approve it.
+*/
+pub async fn handle(conn: &Connection, cipher: &FieldCipher, username: &str, device_id: &str, serve: bool, msg: &Value) -> Result<SyncOutcome, BoxError> {
+    let action = msg.get("action").and_then(|v| v.as_str()).ok_or("Sync action not found")?;
+    let sender = sync_sender(msg)?;
+    let transfer_id = msg.get("transfer_id").and_then(|v| v.as_str()).ok_or("Transfer id not found")?;
+
+    match action {
+        "request" => {
+            if !serve {
+                return Ok(SyncOutcome::notice(format!(
+                    "Device {} asked for this device's history. Set serve_history_sync in config.json to allow it.", sender
+                )));
+            }
+            if !is_own_device(conn, username, &sender).await? {
+                //a device linked since the list was fetched shows up once it is refreshed
+                return Ok(SyncOutcome {
+                    frames: vec![messages::get_devices(username).await?],
+                    notice: Some(format!(
+                        "Ignored a history request from device {}, it isn't one of {}'s devices. The device list is being refreshed, a new device can ask again.",
+                        sender, username
+                    )),
+                    approval: None,
+                });
+            }
+            prepare_request(conn, cipher, device_id, &sender, transfer_id, msg).await
+        }
+        "key" => receive_key(conn, cipher, device_id, &sender, transfer_id, msg).await,
+        "reveal" => receive_reveal(conn, cipher, &sender, transfer_id, msg).await,
+        "offer" => {
+            receive_offer(conn, &sender, transfer_id, msg).await?;
+            Ok(SyncOutcome::default())
+        }
+        "chunk" => receive_chunk(conn, cipher, device_id, &sender, transfer_id, msg).await,
+        "complete" => {
+            if let Some(transfer) = repository::get_transfer(conn, transfer_id).await?
+                && transfer.role == ROLE_SEND && transfer.peer_device == sender {
+                repository::delete_transfer(conn, transfer_id).await?;
+                return Ok(SyncOutcome::notice(format!("History transfer to device {} finished", transfer.peer_device)));
+            }
+            Ok(SyncOutcome::default())
+        }
+        _ => Err(Box::from("Unknown sync action")),
+    }
+}
+
+fn sync_sender(msg: &Value) -> Result<String, BoxError> {
+    msg.get("sender")
+        .and_then(|v| v.as_str().map(|s| s.to_string()).or_else(|| v.as_i64().map(|n| n.to_string())))
+        .ok_or(Box::from("Sender not found"))
+}
+
+//Whether device is one of username's devices that the server listed and hasn't revoked
+async fn is_own_device(conn: &Connection, username: &str, device: &str) -> Result<bool, BoxError> {
+    Ok(repository::list_devices(conn, username).await?
+        .iter()
+        .any(|d| !d.revoked && d.device_id.to_string() == device))
+}
+
+//Sender side: keeps the request's key commitment and answers with this device's key
+async fn prepare_request(conn: &Connection, cipher: &FieldCipher, device_id: &str, sender: &str, transfer_id: &str, msg: &Value) -> Result<SyncOutcome, BoxError> {
+    let commitment = msg.get("key_commitment").and_then(|v| v.as_str()).ok_or("Key commitment not found")?;
+
+    //a resumed request keeps its key and snapshot time, anything else starts over
+    let transfer = match repository::get_transfer(conn, transfer_id).await? {
+        Some(t) if t.role == ROLE_SEND && t.peer_device == sender && t.peer_commitment.as_deref() == Some(commitment) => t,
+        Some(t) if t.role != ROLE_SEND || t.peer_device != sender => return Err(Box::from("Transfer id is already in use")),
+        _ => {
+            let transfer = Transfer {
+                transfer_id: transfer_id.to_string(),
+                role: ROLE_SEND.to_string(),
+                peer_device: sender.to_string(),
+                secret_key: cipher.encrypt_for(SECRET_AAD, &STANDARD.encode(generate_secret()))?,
+                peer_commitment: Some(commitment.to_string()),
+                peer_public_key: None,
+                snapshot_at: Some(unix_now()),
+                digest: None,
+                total_chunks: None,
+            };
+            repository::save_transfer(conn, &transfer).await?;
+            transfer
+        }
+    };
+
+    let own_public_key = public_key(&load_secret(cipher, &transfer)?);
+    Ok(SyncOutcome {
+        frames: vec![messages::sync_key(device_id, sender, transfer_id, &own_public_key).await?],
+        ..SyncOutcome::default()
+    })
+}
+
+/*
+Sender side: checks the revealed key against the request's commitment and asks the user to
+compare codes. A key that doesn't match was swapped on the way and the transfer is refused.
+*/
+async fn receive_reveal(conn: &Connection, cipher: &FieldCipher, sender: &str, transfer_id: &str, msg: &Value) -> Result<SyncOutcome, BoxError> {
+    let transfer = repository::get_transfer(conn, transfer_id).await?
+        .filter(|t| t.role == ROLE_SEND && t.peer_device == sender)
+        .ok_or_else(|| format!("Device {} revealed a key for a transfer it did not request", sender))?;
+    let peer_public_key = msg.get("public_key").and_then(|v| v.as_str()).ok_or("Public key not found")?;
+    if transfer.peer_commitment.as_deref() != Some(key_commitment(transfer_id, peer_public_key).as_str()) {
+        return Err(Box::from(format!(
+            "Device {} revealed a different key than its request committed to, the transfer was refused", sender
+        )));
+    }
+    let secret = load_secret(cipher, &transfer)?;
+    let key = transfer_key(&secret, peer_public_key, transfer_id, peer_public_key, &public_key(&secret))?;
+
+    repository::save_transfer(conn, &Transfer {
+        peer_public_key: Some(peer_public_key.to_string()),
+        ..transfer
+    }).await?;
+    Ok(SyncOutcome {
+        approval: Some(PendingApproval {
+            device: sender.to_string(),
+            code: verification_code(&key),
+            request: msg.clone(),
+        }),
+        ..SyncOutcome::default()
+    })
+}
+
+/*
+Sender side, once the user has seen the same verification code on both devices: snapshots
+the history and returns the offer followed by every chunk the receiver is missing.
+request is the reveal frame from PendingApproval.
+*/
+pub async fn approve(conn: &Connection, cipher: &FieldCipher, username: &str, device_id: &str, request: &Value) -> Result<SyncOutcome, BoxError> {
+    let sender = sync_sender(request)?;
+    let transfer_id = request.get("transfer_id").and_then(|v| v.as_str()).ok_or("Transfer id not found")?;
+    let peer_public_key = request.get("public_key").and_then(|v| v.as_str()).ok_or("Public key not found")?;
+    let resume_from = request.get("resume_from").and_then(|v| v.as_i64()).unwrap_or(0).max(0);
+
+    let transfer = repository::get_transfer(conn, transfer_id).await?
+        .filter(|t| t.role == ROLE_SEND && t.peer_device == sender && t.peer_public_key.as_deref() == Some(peer_public_key))
+        .ok_or("The history request is no longer pending, ask again from the other device")?;
+    let secret = load_secret(cipher, &transfer)?;
+    let own_public_key = public_key(&secret);
+    let key = transfer_key(&secret, peer_public_key, transfer_id, peer_public_key, &own_public_key)?;
+
+    let mut contents = archive::collect(conn, cipher, username).await?;
+    contents.exported_at = transfer.snapshot_at.unwrap_or_else(unix_now);
+    let snapshot = serde_json::to_vec(&contents)?;
+    let digest = STANDARD.encode(Sha256::digest(&snapshot));
+    let chunks: Vec<&[u8]> = snapshot.chunks(CHUNK_SIZE).collect();
+    let total = chunks.len() as i64;
+
+    let restart = request.get("digest").and_then(|v| v.as_str()) != Some(digest.as_str());
+    let from = if restart { 0 } else { resume_from.min(total) };
+
+    repository::save_transfer(conn, &Transfer {
+        digest: Some(digest.clone()),
+        total_chunks: Some(total),
+        ..transfer
+    }).await?;
+
+    let mut frames = vec![messages::sync_offer(device_id, &sender, transfer_id, &own_public_key, total, &digest, restart).await?];
+    for (index, chunk) in chunks.iter().enumerate().skip(from as usize) {
+        let data = seal_chunk(&key, transfer_id, index as i64, total, chunk)?;
+        frames.push(messages::sync_chunk(device_id, &sender, transfer_id, index as i64, &data).await?);
+    }
+    Ok(SyncOutcome {
+        frames,
+        notice: Some(format!("Sending history to device {} ({} chunks)", sender, total - from)),
+        approval: None,
+    })
+}
+
+//Sender side: the user didn't approve the request, its key and snapshot time are dropped
+pub async fn reject(conn: &Connection, request: &Value) -> Result<(), BoxError> {
+    let sender = sync_sender(request)?;
+    let transfer_id = request.get("transfer_id").and_then(|v| v.as_str()).ok_or("Transfer id not found")?;
+    if let Some(transfer) = repository::get_transfer(conn, transfer_id).await?
+        && transfer.role == ROLE_SEND && transfer.peer_device == sender {
+        repository::delete_transfer(conn, transfer_id).await?;
+    }
+    Ok(())
+}
+
+//The transfer this device requested from sender, anything else is refused
+async fn requested_transfer(conn: &Connection, sender: &str, transfer_id: &str) -> Result<Transfer, BoxError> {
+    repository::get_transfer(conn, transfer_id).await?
+        .filter(|t| t.role == ROLE_RECEIVE && t.peer_device == sender)
+        .ok_or(Box::from(format!("Device {} sent data for a transfer this device did not request from it", sender)))
+}
+
+/*
+Receiver side: records the sender's key, reveals this device's key along with how much was
+already received, and shows the verification code to compare with the sender's. A different
+key than before (the sender started over) drops what was received.
+*/
+async fn receive_key(conn: &Connection, cipher: &FieldCipher, device_id: &str, sender: &str, transfer_id: &str, msg: &Value) -> Result<SyncOutcome, BoxError> {
+    let mut transfer = requested_transfer(conn, sender, transfer_id).await?;
+    let peer_public_key = msg.get("public_key").and_then(|v| v.as_str()).ok_or("Public key not found")?;
+    let secret = load_secret(cipher, &transfer)?;
+    let own_public_key = public_key(&secret);
+    let key = transfer_key(&secret, peer_public_key, transfer_id, &own_public_key, peer_public_key)?;
+
+    if transfer.peer_public_key.as_deref() != Some(peer_public_key) {
+        repository::clear_transfer_chunks(conn, transfer_id).await?;
+        transfer = Transfer {
+            peer_public_key: Some(peer_public_key.to_string()),
+            digest: None,
+            total_chunks: None,
+            ..transfer
+        };
+        repository::save_transfer(conn, &transfer).await?;
+    }
+    let received = repository::list_transfer_chunks(conn, transfer_id).await?.len() as i64;
+    Ok(SyncOutcome {
+        frames: vec![messages::sync_reveal(device_id, sender, transfer_id, &own_public_key, received, transfer.digest.as_deref()).await?],
+        notice: Some(format!(
+            "Device {} is ready to send its history. Verification code: {}. Approve it on that device only if it shows the same code.",
+            sender, verification_code(&key)
+        )),
+        approval: None,
+    })
+}
+
+//Receiver side: records what to expect from the key whose code was shown, dropping chunks of an older snapshot
+async fn receive_offer(conn: &Connection, sender: &str, transfer_id: &str, msg: &Value) -> Result<(), BoxError> {
+    let transfer = requested_transfer(conn, sender, transfer_id).await?;
+    let peer_public_key = msg.get("public_key").and_then(|v| v.as_str()).ok_or("Public key not found")?;
+    if transfer.peer_public_key.as_deref() != Some(peer_public_key) {
+        return Err(Box::from("The offer's key isn't the one whose verification code was shown, the transfer was refused"));
+    }
+    let total = msg.get("total_chunks").and_then(|v| v.as_i64()).filter(|n| *n > 0).ok_or("Chunk count not found")?;
+    let digest = msg.get("digest").and_then(|v| v.as_str()).ok_or("Digest not found")?;
+    let restart = msg.get("restart").and_then(|v| v.as_bool()).unwrap_or(true);
+
+    if restart || transfer.digest.as_deref() != Some(digest) {
+        repository::clear_transfer_chunks(conn, transfer_id).await?;
+    }
+    repository::save_transfer(conn, &Transfer {
+        digest: Some(digest.to_string()),
+        total_chunks: Some(total),
+        ..transfer
+    }).await
+}
+
+//Receiver side: stores the chunk once it authenticates, and merges the history after the last one
+async fn receive_chunk(conn: &Connection, cipher: &FieldCipher, device_id: &str, sender: &str, transfer_id: &str, msg: &Value) -> Result<SyncOutcome, BoxError> {
+    let transfer = requested_transfer(conn, sender, transfer_id).await?;
+    let (Some(peer_public_key), Some(digest), Some(total)) = (&transfer.peer_public_key, &transfer.digest, transfer.total_chunks) else {
+        return Err(Box::from("Chunk arrived before the transfer offer"));
+    };
+    let index = msg.get("index").and_then(|v| v.as_i64()).ok_or("Chunk index not found")?;
+    let data = msg.get("data").and_then(|v| v.as_str()).ok_or("Chunk data not found")?;
+
+    let received = repository::list_transfer_chunks(conn, transfer_id).await?.len() as i64;
+    if index > received || index >= total {
+        //a gap means frames were lost, request_history resumes from the first missing chunk
+        return Err(Box::from(format!("Unexpected chunk {} of transfer {}, expected {}", index, transfer_id, received)));
+    }
+    let secret = load_secret(cipher, &transfer)?;
+    let key = transfer_key(&secret, peer_public_key, transfer_id, &public_key(&secret), peer_public_key)?;
+    open_chunk(&key, transfer_id, index, total, data)?;
+    repository::insert_transfer_chunk(conn, transfer_id, index, data).await?;
+    if received + 1 < total || index < received {
+        return Ok(SyncOutcome::default());
+    }
+
+    let mut snapshot = Vec::new();
+    for (index, data) in repository::list_transfer_chunks(conn, transfer_id).await? {
+        snapshot.extend_from_slice(&open_chunk(&key, transfer_id, index, total, &data)?);
+    }
+    if STANDARD.encode(Sha256::digest(&snapshot)) != *digest {
+        repository::clear_transfer_chunks(conn, transfer_id).await?;
+        return Err(Box::from("Transferred history failed its integrity check, request it again"));
+    }
+    let contents: ArchiveContents = serde_json::from_slice(&snapshot)?;
+    let report = archive::merge(conn, cipher, &contents).await?;
+    repository::delete_transfer(conn, transfer_id).await?;
+    Ok(SyncOutcome {
+        frames: vec![messages::sync_complete(device_id, sender, transfer_id).await?],
+        notice: Some(format!(
+            "History transfer finished: {} messages added, {} already present",
+            report.messages, report.duplicates
+        )),
+        approval: None,
+    })
+}
+
+/*
+Six digits both devices show, equal only if both derived the same transfer key. Six are
+enough because of the key commitment: a relay in the middle has to pick its keys before
+seeing the ones it would need to match the codes.
+*/
+pub fn verification_code(key: &[u8; 32]) -> String {
+    let hash = Sha256::new().chain_update(b"verification").chain_update(key).finalize();
+    let number = u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) % 1_000_000;
+    format!("{:06}", number)
+}
+
+//What the request carries in place of the receiver's public key, see the module doc
+fn key_commitment(transfer_id: &str, public_key: &str) -> String {
+    let hash = Sha256::new()
+        .chain_update(COMMITMENT_CONTEXT)
+        .chain_update(transfer_id.as_bytes())
+        .chain_update(public_key.as_bytes())
+        .finalize();
+    STANDARD.encode(hash)
+}
+
+fn generate_secret() -> [u8; 56] {
+    let mut secret = [0u8; 56];
+    OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+fn load_secret(cipher: &FieldCipher, transfer: &Transfer) -> Result<x448::Secret, BoxError> {
+    let encoded = cipher.decrypt_for(SECRET_AAD, &transfer.secret_key)?;
+    x448::Secret::from_bytes(&STANDARD.decode(encoded)?).ok_or(Box::from("Invalid transfer secret"))
+}
+
+fn public_key(secret: &x448::Secret) -> String {
+    STANDARD.encode(x448::PublicKey::from(secret).as_bytes())
+}
+
+//Both public keys and the transfer id go into the key, so it is unique to this pair of ephemeral keys
+fn transfer_key(secret: &x448::Secret, peer_public_key: &str, transfer_id: &str, receiver_public_key: &str, sender_public_key: &str) -> Result<[u8; 32], BoxError> {
+    let peer = x448::PublicKey::from_bytes(&STANDARD.decode(peer_public_key)?).ok_or("Invalid public key")?;
+    let shared = secret.as_diffie_hellman(&peer).ok_or("Key exchange failed")?;
+    let key = Sha256::new()
+        .chain_update(KEY_CONTEXT)
+        .chain_update(shared.as_bytes())
+        .chain_update(transfer_id.as_bytes())
+        .chain_update(receiver_public_key.as_bytes())
+        .chain_update(sender_public_key.as_bytes())
+        .finalize();
+    Ok(key.into())
+}
+
+fn chunk_aad(transfer_id: &str, index: i64, total: i64) -> Vec<u8> {
+    format!("{}:{}:{}", transfer_id, index, total).into_bytes()
+}
+
+fn seal_chunk(key: &[u8; 32], transfer_id: &str, index: i64, total: i64, chunk: &[u8]) -> Result<String, BoxError> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = XChaCha20Poly1305::new(key.into())
+        .encrypt(&nonce, Payload { msg: chunk, aad: &chunk_aad(transfer_id, index, total) })
+        .map_err(|_| "Failed to encrypt chunk")?;
+    let mut blob = nonce.to_vec();
+    blob.extend_from_slice(&ciphertext);
+    Ok(STANDARD.encode(blob))
+}
+
+fn open_chunk(key: &[u8; 32], transfer_id: &str, index: i64, total: i64, data: &str) -> Result<Vec<u8>, BoxError> {
+    let blob = STANDARD.decode(data)?;
+    if blob.len() < NONCE_LEN {
+        return Err(Box::from("Chunk is truncated"));
+    }
+    let (nonce, ciphertext) = blob.split_at(NONCE_LEN);
+    let plaintext = XChaCha20Poly1305::new(key.into())
+        .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: &chunk_aad(transfer_id, index, total) })
+        .map_err(|_| "Chunk failed authentication")?;
+    Ok(plaintext)
+}
@@ -0,0 +1,558 @@
+/**
+ * Full-screen terminal client: conversations on the left with unread badges, the selected
+ * conversation's history on the right above a multiline input box, and a status bar with
+ * the connection state. Everything shown is read from the database; session events only
+ * say when to read it again, so the screen always matches what was stored.
+ *
+ * Keys:
+ *   Enter send, Alt+Enter / Shift+Enter new line, Ctrl+T new chat, Esc cancel
+ *   Alt+Up / Alt+Down, Tab / Shift+Tab switch chat, Alt+1..9 jump to a chat
+ *   Up / Down, PageUp / PageDown scroll history, Ctrl+C / Ctrl+Q quit
+ *   Ctrl+Y / Ctrl+N answer a history request, Ctrl+U unlock the credential vault
+ */
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use zeroize::Zeroizing;
+
+use crate::credential_store;
+use crate::history;
+use crate::messages;
+use crate::prompt;
+use crate::repository::{self, ConversationSummary, Cursor, Message};
+use crate::session_cli;
+use crate::session_manager::{self, SessionContext, SessionEvent};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+//messages loaded at a time for the open conversation, the next older page once scrolled to the top
+const HISTORY_PAGE: i64 = 200;
+const LIST_WIDTH: u16 = 28;
+//input lines shown before the box scrolls
+const INPUT_MAX_LINES: usize = 5;
+//how often the input thread checks whether the UI has gone away
+const INPUT_POLL: Duration = Duration::from_millis(200);
+
+//Text being typed, with the cursor as a byte offset into it
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Input {
+    text: String,
+    cursor: usize,
+}
+
+impl Input {
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn insert(&mut self, c: char) {
+        self.text.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+    }
+
+    pub fn backspace(&mut self) {
+        if let Some(c) = self.text[..self.cursor].chars().next_back() {
+            self.cursor -= c.len_utf8();
+            self.text.remove(self.cursor);
+        }
+    }
+
+    pub fn delete(&mut self) {
+        if self.cursor < self.text.len() {
+            self.text.remove(self.cursor);
+        }
+    }
+
+    pub fn left(&mut self) {
+        if let Some(c) = self.text[..self.cursor].chars().next_back() {
+            self.cursor -= c.len_utf8();
+        }
+    }
+
+    pub fn right(&mut self) {
+        if let Some(c) = self.text[self.cursor..].chars().next() {
+            self.cursor += c.len_utf8();
+        }
+    }
+
+    //to the start of the cursor's line
+    pub fn home(&mut self) {
+        self.cursor = self.text[..self.cursor].rfind('\n').map_or(0, |i| i + 1);
+    }
+
+    //to the end of the cursor's line
+    pub fn end(&mut self) {
+        self.cursor += self.text[self.cursor..].find('\n').unwrap_or(self.text.len() - self.cursor);
+    }
+
+    //Empties the input, returning what was in it
+    pub fn take(&mut self) -> String {
+        self.cursor = 0;
+        std::mem::take(&mut self.text)
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.text.split('\n').count()
+    }
+
+    //(column, line) of the cursor, in characters
+    pub fn cursor_position(&self) -> (usize, usize) {
+        let before = &self.text[..self.cursor];
+        let line = before.matches('\n').count();
+        let column = before.rsplit('\n').next().unwrap_or("").chars().count();
+        (column, line)
+    }
+}
+
+//Splits text into lines of at most width characters, breaking at line ends first
+pub fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for line in text.split('\n') {
+        let chars: Vec<char> = line.chars().collect();
+        if chars.is_empty() || width == 0 {
+            lines.push(line.to_string());
+            continue;
+        }
+        lines.extend(chars.chunks(width).map(|chunk| chunk.iter().collect::<String>()));
+    }
+    lines
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Compose,
+    //the input holds the name of someone to start a conversation with
+    NewChat,
+    //the input holds the credential vault's passphrase, shown masked
+    Unlock,
+}
+
+struct App {
+    username: String,
+    conversations: Vec<ConversationSummary>,
+    selected: usize,
+    messages: Vec<Message>,
+    //whether the conversation may have messages older than the first one loaded
+    older: bool,
+    //history lines scrolled up from the newest
+    scroll: usize,
+    //width and height of the history pane at the last draw, to keep scroll in range
+    history_size: (usize, usize),
+    input: Input,
+    mode: Mode,
+    connected: bool,
+    //last error or notice, shown in the status bar until the next key
+    notice: Option<String>,
+    //device and verification code of a history request waiting for Ctrl+Y or Ctrl+N
+    approval: Option<(String, String)>,
+    quit: bool,
+}
+
+impl App {
+    fn new(username: &str) -> Self {
+        App {
+            username: username.to_string(),
+            conversations: Vec::new(),
+            selected: 0,
+            messages: Vec::new(),
+            older: false,
+            scroll: 0,
+            history_size: (0, 0),
+            input: Input::default(),
+            mode: Mode::Compose,
+            connected: true,
+            notice: None,
+            approval: None,
+            quit: false,
+        }
+    }
+
+    fn current(&self) -> Option<&ConversationSummary> {
+        self.conversations.get(self.selected)
+    }
+
+    //Rereads the conversation list, keeping the selection on the same conversation
+    async fn reload(&mut self, ctx: &SessionContext) -> Result<(), BoxError> {
+        let selected_id = self.current().map(|c| c.conversation_id);
+        self.conversations = repository::list_conversation_summaries(&ctx.conn, &ctx.cipher, &ctx.username).await?;
+        self.selected = selected_id
+            .and_then(|id| self.conversations.iter().position(|c| c.conversation_id == id))
+            .unwrap_or(0);
+        self.load_messages(ctx).await
+    }
+
+    /*
+    Loads the open conversation's newest messages, as many as are loaded already so pages
+    scrolled back to stay, and marks it read
+    */
+    async fn load_messages(&mut self, ctx: &SessionContext) -> Result<(), BoxError> {
+        let Some(conversation) = self.current() else {
+            self.messages.clear();
+            self.older = false;
+            return Ok(());
+        };
+        let conversation_id = conversation.conversation_id;
+        let had_unread = conversation.unread_count > 0;
+        let limit = (self.messages.len() as i64).max(HISTORY_PAGE);
+        self.messages = repository::page_messages(&ctx.conn, &ctx.cipher, conversation_id, Cursor::Latest, limit).await?;
+        self.older = self.messages.len() as i64 == limit;
+        if let Some(last) = self.messages.last() {
+            repository::mark_read(&ctx.conn, conversation_id, last.message_id).await?;
+        }
+        if had_unread && let Some(conversation) = self.conversations.get_mut(self.selected) {
+            conversation.unread_count = 0;
+        }
+        Ok(())
+    }
+
+    //Adds the page of messages before the oldest one loaded
+    async fn load_older(&mut self, ctx: &SessionContext) -> Result<(), BoxError> {
+        let (Some(conversation), Some(first)) = (self.current(), self.messages.first()) else {
+            self.older = false;
+            return Ok(());
+        };
+        let page = repository::page_messages(&ctx.conn, &ctx.cipher, conversation.conversation_id, Cursor::Before(first.message_id), HISTORY_PAGE).await?;
+        self.older = page.len() as i64 == HISTORY_PAGE;
+        self.messages.splice(0..0, page);
+        Ok(())
+    }
+
+    //The history as wrapped lines of at most width characters, oldest first
+    fn history_lines(&self, width: usize) -> Vec<Line<'static>> {
+        let mut lines = Vec::new();
+        for message in &self.messages {
+            let style = if message.sender_id == self.username {
+                Style::default().fg(Color::Cyan)
+            } else {
+                Style::default()
+            };
+            let formatted = session_cli::format_message(&message.sender_id, &message.created_at, &message.content);
+            lines.extend(wrap(&formatted, width).into_iter().map(|line| Line::styled(line, style)));
+        }
+        if self.messages.is_empty() && self.current().is_some() {
+            lines.push(Line::styled("No messages yet", Style::default().fg(Color::DarkGray)));
+        }
+        lines
+    }
+
+    //Furthest the history can scroll up, with the oldest line at the top of the pane
+    fn max_scroll(&self) -> usize {
+        let (width, height) = self.history_size;
+        self.history_lines(width).len().saturating_sub(height)
+    }
+
+    //Scrolls the history up (positive) or down by lines, loading older pages once the top is reached
+    async fn scroll_by(&mut self, ctx: &SessionContext, lines: isize) -> Result<(), BoxError> {
+        let wanted = self.scroll.saturating_add_signed(lines);
+        while wanted > self.max_scroll() && self.older {
+            self.load_older(ctx).await?;
+        }
+        self.scroll = wanted.min(self.max_scroll());
+        Ok(())
+    }
+
+    async fn select(&mut self, ctx: &SessionContext, index: usize) -> Result<(), BoxError> {
+        if index < self.conversations.len() && index != self.selected {
+            self.selected = index;
+            self.scroll = 0;
+            self.messages.clear();
+            self.load_messages(ctx).await?;
+        }
+        Ok(())
+    }
+
+    async fn select_offset(&mut self, ctx: &SessionContext, offset: isize) -> Result<(), BoxError> {
+        let count = self.conversations.len() as isize;
+        if count > 0 {
+            let index = (self.selected as isize + offset).rem_euclid(count) as usize;
+            self.select(ctx, index).await?;
+        }
+        Ok(())
+    }
+
+    async fn submit(&mut self, ctx: &SessionContext) -> Result<(), BoxError> {
+        match self.mode {
+            Mode::Compose => {
+                if self.input.text().trim().is_empty() {
+                    return Ok(());
+                }
+                let Some(peer) = self.current().and_then(|c| match c.participants.as_slice() {
+                    [peer] => Some(peer.clone()),
+                    _ => None,
+                }) else {
+                    return Err(Box::from("Pick a one-to-one conversation first, Ctrl+T starts one"));
+                };
+                let frame = messages::message(&ctx.username, &peer, self.input.text()).await?;
+                ctx.outgoing.send(frame.to_string()).await.map_err(|_| "The connection is closed")?;
+                self.input.take();
+                self.scroll = 0;
+            }
+            Mode::Unlock => {
+                let passphrase = Zeroizing::new(self.input.take());
+                let store = credential_store::current().await?;
+                if let Some(vault) = store.vault() {
+                    vault.unlock(&passphrase)?;
+                }
+                self.mode = Mode::Compose;
+                self.notice = Some(String::from("Credential vault unlocked"));
+            }
+            Mode::NewChat => {
+                let peer = self.input.text().trim().to_string();
+                prompt::validate_username(&peer)?;
+                repository::ensure_user(&ctx.conn, &peer).await?;
+                let conversation_id = repository::find_or_create_direct_conversation(&ctx.conn, &peer).await?;
+                self.input.take();
+                self.mode = Mode::Compose;
+                self.reload(ctx).await?;
+                if let Some(index) = self.conversations.iter().position(|c| c.conversation_id == conversation_id) {
+                    self.select(ctx, index).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+//Runs the terminal UI until the user quits, restoring the terminal whatever happens
+pub async fn run(ctx: SessionContext) -> Result<(), BoxError> {
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, &ctx).await;
+    ratatui::restore();
+    result
+}
+
+async fn event_loop(terminal: &mut DefaultTerminal, ctx: &SessionContext) -> Result<(), BoxError> {
+    let (input_tx, mut input_rx) = mpsc::channel(32);
+    std::thread::spawn(move || read_terminal_events(input_tx));
+    let mut events = ctx.events.subscribe();
+
+    let mut app = App::new(&ctx.username);
+    if let Err(e) = app.reload(ctx).await {
+        app.notice = Some(e.to_string());
+    }
+
+    while !app.quit {
+        let drawn = terminal.draw(|frame| render(frame, &app))?;
+        let (_, history, _, _) = layout(drawn.area, &app);
+        app.history_size = (history.width.saturating_sub(2) as usize, history.height.saturating_sub(2) as usize);
+        let result = tokio::select! {
+            Some(event) = input_rx.recv() => handle_terminal_event(&mut app, ctx, event).await,
+            event = events.recv() => handle_session_event(&mut app, ctx, event).await,
+        };
+        if let Err(e) = result {
+            app.notice = Some(e.to_string());
+        }
+    }
+    Ok(())
+}
+
+//Crossterm only offers blocking reads, so they happen on their own thread
+fn read_terminal_events(tx: mpsc::Sender<Event>) {
+    loop {
+        match event::poll(INPUT_POLL) {
+            Ok(true) => match event::read() {
+                Ok(event) => {
+                    if tx.blocking_send(event).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            },
+            Ok(false) if tx.is_closed() => break,
+            Ok(false) => {}
+            Err(_) => break,
+        }
+    }
+}
+
+async fn handle_session_event(app: &mut App, ctx: &SessionContext, event: Result<SessionEvent, broadcast::error::RecvError>) -> Result<(), BoxError> {
+    match event {
+        Ok(SessionEvent::Message { .. }) | Err(broadcast::error::RecvError::Lagged(_)) => app.reload(ctx).await?,
+        Ok(SessionEvent::Devices { user_id, devices }) => {
+            app.notice = Some(format!("Devices of {}: {}", user_id, devices.join(", ")));
+        }
+        Ok(SessionEvent::Notice(notice)) | Ok(SessionEvent::Error(notice)) => app.notice = Some(notice),
+        Ok(SessionEvent::TransferApproval { device, code }) => app.approval = Some((device, code)),
+        Ok(SessionEvent::Disconnected { reason }) => {
+            app.connected = false;
+            app.notice = reason;
+        }
+        Err(broadcast::error::RecvError::Closed) => app.connected = false,
+    }
+    Ok(())
+}
+
+async fn handle_terminal_event(app: &mut App, ctx: &SessionContext, event: Event) -> Result<(), BoxError> {
+    let Event::Key(key) = event else {
+        //resizes only need the redraw that follows every event
+        return Ok(());
+    };
+    if key.kind != KeyEventKind::Press {
+        return Ok(());
+    }
+    app.notice = None;
+    handle_key(app, ctx, key).await
+}
+
+async fn handle_key(app: &mut App, ctx: &SessionContext, key: KeyEvent) -> Result<(), BoxError> {
+    let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+    let alt = key.modifiers.contains(KeyModifiers::ALT);
+    let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+
+    match key.code {
+        KeyCode::Char('c') | KeyCode::Char('q') if ctrl => app.quit = true,
+        KeyCode::Char('t') if ctrl => {
+            app.mode = Mode::NewChat;
+            app.input.take();
+        }
+        KeyCode::Char('u') if ctrl => {
+            if credential_store::current().await?.vault().is_none() {
+                return Err(Box::from("Credentials aren't kept in a vault, there is nothing to unlock"));
+            }
+            app.mode = Mode::Unlock;
+            app.input.take();
+        }
+        KeyCode::Char('y') if ctrl => {
+            if let Some((device, _)) = app.approval.take() {
+                session_manager::approve_transfer(ctx, &device).await?;
+            }
+        }
+        KeyCode::Char('n') if ctrl => {
+            if let Some((device, _)) = app.approval.take() {
+                session_manager::reject_transfer(ctx, &device).await?;
+                app.notice = Some(format!("Turned down device {}'s history request", device));
+            }
+        }
+        KeyCode::Esc => {
+            app.mode = Mode::Compose;
+            app.input.take();
+        }
+        KeyCode::Up if alt || ctrl => app.select_offset(ctx, -1).await?,
+        KeyCode::Down if alt || ctrl => app.select_offset(ctx, 1).await?,
+        KeyCode::BackTab => app.select_offset(ctx, -1).await?,
+        KeyCode::Tab => app.select_offset(ctx, 1).await?,
+        KeyCode::Char(digit @ '1'..='9') if alt => {
+            app.select(ctx, digit as usize - '1' as usize).await?;
+        }
+        KeyCode::Up => app.scroll_by(ctx, 1).await?,
+        KeyCode::Down => app.scroll_by(ctx, -1).await?,
+        KeyCode::PageUp => app.scroll_by(ctx, 10).await?,
+        KeyCode::PageDown => app.scroll_by(ctx, -10).await?,
+        KeyCode::Enter if (alt || shift) && app.mode == Mode::Compose => app.input.insert('\n'),
+        KeyCode::Enter => app.submit(ctx).await?,
+        KeyCode::Backspace => app.input.backspace(),
+        KeyCode::Delete => app.input.delete(),
+        KeyCode::Left => app.input.left(),
+        KeyCode::Right => app.input.right(),
+        KeyCode::Home => app.input.home(),
+        KeyCode::End => app.input.end(),
+        KeyCode::Char(c) if !ctrl && !alt => app.input.insert(c),
+        _ => {}
+    }
+    Ok(())
+}
+
+//Conversation list, history, input and status bar areas of the screen
+fn layout(area: Rect, app: &App) -> (Rect, Rect, Rect, Rect) {
+    let [main, status] = Layout::vertical([Constraint::Min(3), Constraint::Length(1)]).areas(area);
+    let [list, chat] = Layout::horizontal([Constraint::Length(LIST_WIDTH), Constraint::Min(10)]).areas(main);
+    let input_height = app.input.line_count().min(INPUT_MAX_LINES) as u16 + 2;
+    let [history, input] = Layout::vertical([Constraint::Min(1), Constraint::Length(input_height)]).areas(chat);
+    (list, history, input, status)
+}
+
+fn render(frame: &mut Frame, app: &App) {
+    let (list, history, input, status) = layout(frame.area(), app);
+    render_conversations(frame, app, list);
+    render_history(frame, app, history);
+    render_input(frame, app, input);
+    render_status(frame, app, status);
+}
+
+fn render_conversations(frame: &mut Frame, app: &App, area: Rect) {
+    let items: Vec<ListItem> = app.conversations.iter().enumerate()
+        .map(|(i, conversation)| {
+            let mut spans = Vec::new();
+            if i < 9 {
+                spans.push(Span::styled(format!("{} ", i + 1), Style::default().fg(Color::DarkGray)));
+            }
+            spans.push(Span::raw(conversation.participants.join(", ")));
+            if conversation.unread_count > 0 {
+                spans.push(Span::styled(
+                    format!(" ({})", conversation.unread_count),
+                    Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD),
+                ));
+            }
+            ListItem::new(Line::from(spans))
+        })
+        .collect();
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title("Conversations"))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    let mut state = ListState::default().with_selected((!app.conversations.is_empty()).then_some(app.selected));
+    frame.render_stateful_widget(list, area, &mut state);
+}
+
+fn render_history(frame: &mut Frame, app: &App, area: Rect) {
+    let title = app.current().map_or_else(|| String::from("No conversation"), |c| c.participants.join(", "));
+    let block = Block::default().borders(Borders::ALL).title(title);
+    let width = area.width.saturating_sub(2) as usize;
+    let height = area.height.saturating_sub(2) as usize;
+    let mut lines = app.history_lines(width);
+
+    //scrolled from the bottom, so new messages show up without scrolling
+    let scroll = app.scroll.min(lines.len().saturating_sub(height));
+    let end = lines.len() - scroll;
+    let start = end.saturating_sub(height);
+    let visible: Vec<Line> = lines.drain(start..end).collect();
+    frame.render_widget(Paragraph::new(visible).block(block), area);
+}
+
+fn render_input(frame: &mut Frame, app: &App, area: Rect) {
+    let title = match app.mode {
+        Mode::Compose => match app.current() {
+            Some(conversation) => format!("Message to {}", conversation.participants.join(", ")),
+            None => String::from("Ctrl+T to start a chat"),
+        },
+        Mode::NewChat => String::from("Start a chat with (Enter to open, Esc to cancel)"),
+        Mode::Unlock => String::from("Credential vault passphrase (Enter to unlock, Esc to cancel)"),
+    };
+    let (column, line) = app.input.cursor_position();
+    //keep the cursor's line in view once the input is taller than the box
+    let first_line = line.saturating_sub(INPUT_MAX_LINES - 1);
+    let text: Vec<Line> = match app.mode {
+        Mode::Unlock => vec![Line::raw("*".repeat(app.input.text().chars().count()))],
+        _ => app.input.text().split('\n').skip(first_line).map(Line::raw).collect(),
+    };
+    frame.render_widget(
+        Paragraph::new(text).block(Block::default().borders(Borders::ALL).title(title)),
+        area,
+    );
+
+    let x = area.x + 1 + (column as u16).min(area.width.saturating_sub(3));
+    let y = area.y + 1 + (line - first_line) as u16;
+    frame.set_cursor_position((x, y));
+}
+
+fn render_status(frame: &mut Frame, app: &App, area: Rect) {
+    let (state, color) = if app.connected { ("● online", Color::Green) } else { ("○ offline", Color::Red) };
+    let hint = app.notice.clone()
+        .or_else(|| app.approval.as_ref().map(|(device, code)| format!(
+            "Device {} asks for this device's history, code {} · Ctrl+Y send it if that device shows the same code · Ctrl+N refuse",
+            device, code
+        )))
+        .unwrap_or_else(|| {
+            String::from("Enter send · Alt+Enter newline · Alt+↑/↓ switch · Ctrl+T new chat · Ctrl+Q quit")
+        });
+    let line = Line::from(vec![
+        Span::styled(state, Style::default().fg(color).add_modifier(Modifier::BOLD)),
+        Span::raw(format!(" {} │ ", app.username)),
+        Span::raw(history::preview(&hint)),
+    ]);
+    frame.render_widget(Paragraph::new(line), area);
+}